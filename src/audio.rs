@@ -1,8 +1,12 @@
 use std::cmp::Ordering;
 use std::fmt::{Debug, Display, Formatter, Write};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
 use std::ops::Add;
 use std::process::exit;
-use std::sync::atomic::{Ordering as SyncOrdering, AtomicBool, AtomicU16};
 use std::sync::mpsc::{channel, Receiver, RecvError, Sender, SendError, TryRecvError};
 use std::thread::JoinHandle;
 use chrono::Timelike;
@@ -14,10 +18,26 @@ use std::cmp::Eq;
 
 use anyhow;
 
-use super::util;
-
-static AUDIO_FLAG: AtomicBool = AtomicBool::new(false);
-static AUDIO_VOLUME: AtomicU16 = AtomicU16::new(0);
+mod scheduler;
+pub mod clock;
+#[cfg(target_os = "linux")]
+pub mod jack;
+pub mod meter;
+pub mod render;
+pub mod routing;
+pub mod sample;
+pub mod tempo;
+pub mod voice;
+
+use scheduler::{ClickScheduler, EngineState};
+use clock::{BeatOutput, BeatQueue, BeatSink, ClockOutput, ClockQueue, ClockSink, SyncSource, TimedSync};
+use meter::{BeatClass, Subdivision, TimeSignature};
+use voice::{ClickVoice, NoiseState, Voice};
+use sample::{ClickSample, SampleBank};
+use tempo::{Bpm, Position, TempoMap};
+use routing::{ChannelRouting, Routing};
+use crate::config::{AudioConfig, JackConfig};
+use rtrb::{Consumer, Producer, RingBuffer};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum HostSelector {
@@ -94,16 +114,118 @@ impl HostSelector {
     }
 
     pub fn supported_output_devices(&self) -> Vec<String> {
-        let host = cpal::host_from_id((*self).into()).unwrap();
-        let dev = host.output_devices().unwrap();
-        dev.map(|d| d.name().unwrap()).collect()
+        let devices = cpal::host_from_id((*self).into())
+            .map_err(anyhow::Error::from)
+            .and_then(|host| Ok(host.output_devices()?));
+        match devices {
+            Ok(devices) => devices.filter_map(|d| d.name().ok()).collect(),
+            Err(e) => {
+                warn!("Could not list output devices of {} ({:?})", self, e);
+                Vec::new()
+            }
+        }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-enum InternalAudioMessage {
+impl Default for HostSelector {
+    fn default() -> Self {
+        cpal::default_host().id().into()
+    }
+}
+
+impl FromStr for HostSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        cpal::ALL_HOSTS
+            .iter()
+            .map(|h| HostSelector::from(*h))
+            .find(|h| h.to_string().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| anyhow::anyhow!(
+                "Unknown audio host '{}' (available: {})",
+                s,
+                HostSelector::supported().iter().map(|h| h.to_string()).collect::<Vec<_>>().join(", ")
+            ))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OutputSettings {
+    pub host: HostSelector,
+    // The default device of the host if None
+    pub device: Option<String>,
+    // The default of the device if None
+    pub sample_rate: Option<u32>,
+    pub buffer_size: Option<u32>,
+    // By device name, devices without routing play the click on every channel
+    pub routing: BTreeMap<String, ChannelRouting>,
+    // Client, ports and transport when the host is Jack
+    pub jack: JackConfig,
+}
+
+#[derive(Debug)]
+pub(crate) enum InternalAudioMessage {
     Shutdown,
-    External(AudioMessage)
+    External(AudioMessage),
+    SetSample(BeatClass, Option<Arc<ClickSample>>),
+    SetClockQueue(Option<ClockQueue>),
+    SetBeatQueue(Option<BeatQueue>),
+    SetSyncQueue(Option<Consumer<TimedSync>>),
+    // With the bar that comes next
+    SetTempoMap(Option<Arc<TempoMap>>, u32),
+}
+
+// What the engine replaced. It goes back to the handle, so it is not freed in the audio thread.
+// The values are never read, only dropped.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub(crate) enum Retired {
+    ClockQueue(ClockQueue),
+    BeatQueue(BeatQueue),
+    SyncQueue(Consumer<TimedSync>),
+    TempoMap(Arc<TempoMap>),
+}
+
+// Replacements are rare, the handle takes them back whenever it sends a message
+const RETIRED_QUEUE_SIZE: usize = 16;
+
+// The engine end of the control channel and of the event queues
+#[derive(Debug)]
+pub(crate) struct Inbox {
+    messages: Receiver<InternalAudioMessage>,
+    retired: Producer<Retired>,
+    // Events of a clock master
+    sync: Option<Consumer<TimedSync>>,
+    pub(crate) clock: ClockOutput,
+    pub(crate) beats: BeatOutput,
+}
+
+impl Inbox {
+    fn new() -> (Sender<InternalAudioMessage>, Inbox, Consumer<Retired>) {
+        let (sender, messages) = channel();
+        let (retired, retired_rx) = RingBuffer::new(RETIRED_QUEUE_SIZE);
+        let inbox = Inbox {
+            messages,
+            retired,
+            sync: None,
+            clock: ClockOutput::new(None),
+            beats: BeatOutput::new(None),
+        };
+        (sender, inbox, retired_rx)
+    }
+
+    // `playback` is when the first frame of the callback will be heard
+    fn flush(&mut self, playback: Instant, sample_rate: f32) {
+        self.clock.flush(playback, sample_rate);
+        self.beats.flush(playback, sample_rate);
+    }
+
+    fn retire(&mut self, retired: Retired) {
+        // The handle did not take anything back for a long time, so it is freed here after all
+        if let Err(rtrb::PushError::Full(retired)) = self.retired.push(retired) {
+            drop(retired);
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -111,62 +233,331 @@ pub enum AudioMessage {
     Play,
     Pause,
     Toggle,
-    SetBpm(u16),
+    SetBpm(Bpm),
     SetVolume(u16),
+    SetTimeSignature(TimeSignature),
+    SetSubdivision(Subdivision),
+    SetSubdivisionVolume(u16),
+    SetVoice(BeatClass, ClickVoice),
+    SetCueVoice(ClickVoice),
+}
+
+enum Stream {
+    Cpal(cpal::Stream),
+    #[cfg(target_os = "linux")]
+    Jack(jack::JackOutput),
+}
+
+struct Output {
+    stream: Stream,
+    device_name: String,
+    sample_rate: u32,
+    sender: Sender<InternalAudioMessage>,
+    retired: Consumer<Retired>,
 }
 
 pub struct AudioHandle {
-    stream: cpal::Stream,
-    thread: Option<std::thread::JoinHandle<()>>,
-    sender: Sender<InternalAudioMessage>
+    output: Option<Output>,
+    settings: OutputSettings,
+    // Mirrors what was sent to the engine, so a rebuilt stream continues where the old one stopped.
+    // What the engine changed on its own is taken over when the stream closes.
+    state: EngineState,
+    // Samples are kept at their original rate and resampled for every stream
+    samples: SampleBank,
+    // Resampled samples sent to the stream. The stream only drops its reference, the last
+    // one is released here once it is unused, so the memory is not freed in the audio thread.
+    streamed: Vec<Arc<ClickSample>>,
+    clock_sink: Option<ClockSink>,
+    beat_sink: Option<BeatSink>,
+    sync_source: Option<SyncSource>,
+    tempo_map: Option<Arc<TempoMap>>,
+    position: Arc<Position>,
+    // Why the routing of the open output could not be followed
+    routing_problem: Option<String>,
 }
 
 impl Debug for AudioHandle {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self.thread {
-            None => f.write_str("AudioHandle(running)"),
-            Some(_) => f.write_str("AudioHandle(not running)"),
+        match &self.output {
+            None => f.write_str("AudioHandle(no output)"),
+            Some(output) => write!(f, "AudioHandle({} / {})", self.settings.host, output.device_name),
         }
     }
 }
 
 impl AudioHandle {
-    fn new() -> AudioHandle {
-        let stream = stream_setup_for(sample_next).unwrap();
-        stream.play().unwrap();
+    fn new(settings: OutputSettings) -> AudioHandle {
+        let mut handle = AudioHandle {
+            output: None,
+            settings,
+            state: EngineState::default(),
+            samples: SampleBank::default(),
+            streamed: Vec::new(),
+            clock_sink: None,
+            beat_sink: None,
+            sync_source: None,
+            tempo_map: None,
+            position: Arc::new(Position::default()),
+            routing_problem: None,
+        };
+        if let Err(e) = handle.open() {
+            error!("Could not open output device ({:?})", e);
+        }
+        handle
+    }
 
-        let (tx, rx) = channel();
+    fn open(&mut self) -> Result<(), anyhow::Error> {
+        let (tx, mut inbox, retired_rx) = Inbox::new();
+        inbox.sync = self.sync_source.as_ref().and_then(SyncSource::queue);
+        inbox.clock.set_queue(self.clock_sink.as_ref().and_then(ClockSink::queue));
+        inbox.beats.set_queue(self.beat_sink.as_ref().and_then(BeatSink::queue));
+        self.routing_problem = None;
+        let host = self.settings.host;
+        let (stream, device_name, sample_rate) = match host {
+            #[cfg(target_os = "linux")]
+            HostSelector::Jack => self.open_jack(inbox)?,
+            _ => self.open_cpal(inbox)?,
+        };
+
+        for class in BeatClass::ALL {
+            if let Some(sample) = self.samples.get(class) {
+                let sample = Arc::new(sample.resampled(sample_rate));
+                self.streamed.push(sample.clone());
+                tx.send(InternalAudioMessage::SetSample(class, Some(sample))).map_err(|_| stopped())?;
+            }
+        }
+        // The song goes on with the bar after the one the previous stream played
+        let next_bar = self.position.bar() + 1;
+        tx.send(InternalAudioMessage::SetTempoMap(self.tempo_map.clone(), next_bar)).map_err(|_| stopped())?;
 
-        let thread = std::thread::spawn(move || {
-            audio_thread(rx);
-        });
+        if let Stream::Cpal(stream) = &stream {
+            stream.play()?;
+        }
 
-        AudioHandle {
+        self.output = Some(Output {
             stream,
-            thread: Some(thread),
-            sender: tx
+            device_name,
+            sample_rate,
+            sender: tx,
+            retired: retired_rx,
+        });
+        Ok(())
+    }
+
+    fn open_cpal(&mut self, inbox: Inbox) -> Result<(Stream, String, u32), anyhow::Error> {
+        let (_host, device, config) = host_device_setup(&self.settings)?;
+        let device_name = device.name()?;
+        let routing = self.routing_for(&device_name, config.channels() as usize);
+
+        let (stream, config) = stream_setup_for(
+            &device,
+            config,
+            self.settings.buffer_size,
+            routing,
+            sample_next,
+            inbox,
+            self.state,
+            self.position.clone(),
+        )?;
+        Ok((Stream::Cpal(stream), device_name, config.sample_rate.0))
+    }
+
+    // The click goes to ports of our own client, the device setting does not apply
+    #[cfg(target_os = "linux")]
+    fn open_jack(&mut self, inbox: Inbox) -> Result<(Stream, String, u32), anyhow::Error> {
+        let client_name = self.settings.jack.client_name.clone();
+        let routing = self.routing_for(&client_name, self.settings.jack.outputs as usize);
+        let output = jack::JackOutput::open(
+            &self.settings.jack,
+            routing,
+            inbox,
+            self.state,
+            self.position.clone(),
+        )?;
+        let name = output.name().to_string();
+        let sample_rate = output.sample_rate();
+        Ok((Stream::Jack(output), name, sample_rate))
+    }
+
+    // A routing the output cannot follow is played on channel 1 rather than not at all
+    fn routing_for(&mut self, name: &str, nchannels: usize) -> Routing {
+        let routing = self.settings.routing.get(name).cloned().unwrap_or_default();
+        match Routing::new(&routing, nchannels) {
+            Ok(routing) => routing,
+            Err(e) => {
+                let problem = format!("Routing of '{}' falls back to channel 1 ({:#})", name, e);
+                warn!("{}", problem);
+                self.routing_problem = Some(problem);
+                Routing::fallback(&routing, nchannels)
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        self.state = self.state();
+        if let Some(output) = self.output.take() {
+            if let Err(e) = output.sender.send(InternalAudioMessage::Shutdown) {
+                warn!("Could not send shutdown message to audio handler.");
+            }
+            // A JACK client is closed when it is dropped
+            if let Stream::Cpal(stream) = &output.stream {
+                if let Err(e) = stream.pause() {
+                    warn!("Could not pause the output stream ({:?})", e);
+                }
+            }
         }
     }
 
     fn shutdown(&mut self) {
         debug!("Shutting down audio...");
-        if self.thread.is_none() {
-            warn!("AudioHandle has no thread to join!");
-            return;
+        self.close();
+    }
+
+    // Tears down the current stream and opens a new one with the given settings.
+    // Play state, tempo, volume, voices and samples carry over.
+    // If the new device cannot be opened, the previous one is reopened.
+    pub fn rebuild(&mut self, settings: OutputSettings) -> Result<(), anyhow::Error> {
+        // Close first, some hosts (ASIO) can only have a single stream open
+        self.close();
+        let previous = std::mem::replace(&mut self.settings, settings);
+
+        if let Err(e) = self.open() {
+            error!("Could not open output device ({:?}), reopening the previous one", e);
+            self.settings = previous;
+            if let Err(e) = self.open() {
+                error!("Could not reopen the previous output device ({:?})", e);
+            }
+            return Err(e);
         }
-        if let Err(e) = (&self).sender.send(InternalAudioMessage::Shutdown) {
-            warn!("Could not send shutdown message to audio handler.");
+
+        debug!("Rebuilt audio stream: {:?}", self);
+        Ok(())
+    }
+
+    pub fn host(&self) -> HostSelector {
+        self.settings.host
+    }
+
+    pub fn settings(&self) -> &OutputSettings {
+        &self.settings
+    }
+
+    // Name of the device that is actually open, which may be the default device
+    pub fn device_name(&self) -> Option<&str> {
+        self.output.as_ref().map(|o| o.device_name.as_str())
+    }
+
+    pub fn routing_problem(&self) -> Option<&str> {
+        self.routing_problem.as_deref()
+    }
+
+    // Tempo maps and clock masters change tempo, meter and play state in the audio thread,
+    // those are read back from the engine
+    pub fn state(&self) -> EngineState {
+        let mut state = self.state;
+        if let (Some(_), Some((bpm, meter, playing))) = (&self.output, self.position.engine()) {
+            state.bpm = bpm;
+            state.time_signature = meter;
+            state.playing = playing;
+        }
+        state
+    }
+
+    fn send_internal(&mut self, msg: InternalAudioMessage) {
+        self.streamed.retain(|sample| Arc::strong_count(sample) > 1);
+        if let InternalAudioMessage::SetSample(_, Some(sample)) = &msg {
+            self.streamed.push(sample.clone());
+        }
+        if let Some(output) = &mut self.output {
+            while let Ok(retired) = output.retired.pop() {
+                drop(retired);
+            }
+            if let Err(e) = output.sender.send(msg) {
+                warn!("Could not send message to audio handler. It probably shut down for some reason (msg: {:?})", e.0);
+            }
+        }
+    }
+
+    pub fn send(&mut self, msg: AudioMessage) {
+        self.state.apply(msg);
+        self.send_internal(InternalAudioMessage::External(msg));
+    }
+
+    // Subdivision volume, voices and samples of the config. Relative sample paths start in dir.
+    // Every beat class is set up even if a sample fails to load, the first error is returned.
+    pub fn apply_sound(&mut self, config: &AudioConfig, dir: &Path) -> Result<(), anyhow::Error> {
+        self.send(AudioMessage::SetSubdivisionVolume(config.subdivision_volume));
+        let voices = config.voices.voices();
+        let mut result = Ok(());
+        for class in BeatClass::ALL {
+            self.send(AudioMessage::SetVoice(class, *voices.get(class)));
+            let loaded = match &config.voices.get(class).sample {
+                Some(path) => self.load_sample(class, dir.join(path)),
+                None => {
+                    self.clear_sample(class);
+                    Ok(())
+                }
+            };
+            result = result.and(loaded);
         }
-        let thread = self.thread.take().unwrap();
-        if thread.join().is_err() {
-            error!("Audio handler thread has panicked on join. Could not join!");
+        self.send(AudioMessage::SetCueVoice(voices.cue));
+        result
+    }
+
+    // Plays the given file instead of the synthesized voice for this beat class
+    pub fn load_sample<P: AsRef<Path>>(&mut self, class: BeatClass, path: P) -> Result<(), anyhow::Error> {
+        let sample = Arc::new(ClickSample::load(path)?);
+        if let Some(sample_rate) = self.output.as_ref().map(|o| o.sample_rate) {
+            let resampled = Arc::new(sample.resampled(sample_rate));
+            self.send_internal(InternalAudioMessage::SetSample(class, Some(resampled)));
         }
+        self.samples.set(class, Some(sample));
+        Ok(())
+    }
+
+    pub fn clear_sample(&mut self, class: BeatClass) {
+        self.samples.set(class, None);
+        self.send_internal(InternalAudioMessage::SetSample(class, None));
+    }
+
+    // Receives the MIDI clock events of the engine, timed to when their frame is heard
+    pub fn set_clock_sink(&mut self, sink: Option<ClockSink>) {
+        let queue = sink.as_ref().and_then(ClockSink::queue);
+        self.clock_sink = sink;
+        self.send_internal(InternalAudioMessage::SetClockQueue(queue));
+    }
+
+    // Receives every click of the engine, timed to when it is heard
+    pub fn set_beat_sink(&mut self, sink: Option<BeatSink>) {
+        let queue = sink.as_ref().and_then(BeatSink::queue);
+        self.beat_sink = sink;
+        self.send_internal(InternalAudioMessage::SetBeatQueue(queue));
+    }
+
+    // Playback follows the events of this clock master, in the audio thread
+    pub fn set_sync_source(&mut self, source: Option<SyncSource>) {
+        let queue = source.as_ref().and_then(SyncSource::queue);
+        self.sync_source = source;
+        self.send_internal(InternalAudioMessage::SetSyncQueue(queue));
     }
 
-    pub fn send(&self, msg: AudioMessage) {
-        if let Err(e) = self.sender.send(InternalAudioMessage::External(msg)) {
-            warn!("Could not send message to audio handler. It probably shut down for some reason (msg: {:?})", msg);
+    // Starts a new song. With a map, the engine takes tempo and meter from it bar by bar.
+    pub fn set_tempo_map(&mut self, map: Option<Arc<TempoMap>>) {
+        if let Some(map) = &map {
+            let (bpm, meter) = map.start();
+            self.send(AudioMessage::SetBpm(bpm));
+            self.send(AudioMessage::SetTimeSignature(meter));
         }
+        self.tempo_map = map.clone();
+        self.send_internal(InternalAudioMessage::SetTempoMap(map, 1));
+    }
+
+    pub fn tempo_map(&self) -> Option<&Arc<TempoMap>> {
+        self.tempo_map.as_ref()
+    }
+
+    pub fn position(&self) -> &Arc<Position> {
+        &self.position
     }
 }
 
@@ -176,118 +567,59 @@ impl Drop for AudioHandle {
     }
 }
 
-pub fn setup() -> AudioHandle {
-    AudioHandle::new()
+fn stopped() -> anyhow::Error {
+    anyhow::anyhow!("The audio engine stopped right after it was opened")
 }
 
-fn audio_thread(rx: Receiver<InternalAudioMessage>) {
-    let mut running = true;
-    let mut paused = false;
-
-    let mut timing = util::bpm_to_ns(55);
-    let busy_timing = 50000000;
-    let mut sleep_timing = timing - busy_timing;
-
-    if let Err(e) = thread_priority::set_current_thread_priority(thread_priority::ThreadPriority::Max) {
-        error!("Could not set priority! ({:?})", e);
-    }
-    debug!("Starting audio loop");
-    while running {
-        if !paused {
-            AUDIO_FLAG.store(true, SyncOrdering::SeqCst);
-        }
+pub fn setup(settings: OutputSettings) -> AudioHandle {
+    AudioHandle::new(settings)
+}
 
-        let start = std::time::Instant::now();
-        let sleep_start = start.add(std::time::Duration::from_nanos(busy_timing as u64));
-        // TODO warn if the delta gets to big from the requests value
-        let (messages, continue_running) = get_message(paused, &rx);
-
-        running = continue_running;
-
-        // TODO somehow consume all messages
-        if continue_running {
-            let mut volume_to_change_to = None;
-            let mut new_paused_state = paused;
-            for msg in messages {
-                match msg {
-                    InternalAudioMessage::External(msg) => {
-                        match msg {
-                            AudioMessage::Play => new_paused_state = false,
-                            AudioMessage::Pause => new_paused_state = true,
-                            AudioMessage::Toggle => new_paused_state = !new_paused_state,
-                            AudioMessage::SetBpm(bpm) => {
-                                debug!("Setting BPM to {}", bpm);
-                                timing = util::bpm_to_ns(bpm as u128);
-                                sleep_timing = timing - busy_timing;
-                            }
-                            AudioMessage::SetVolume(vol) => {
-                                volume_to_change_to = Some(vol);
-                            }
-                        }
-                    }
-                    InternalAudioMessage::Shutdown => running = false
+// `playback` is when the next frame will be heard
+pub(crate) fn drain_messages(inbox: &mut Inbox, scheduler: &mut ClickScheduler, playback: Instant) {
+    while let Ok(msg) = inbox.messages.try_recv() {
+        match msg {
+            InternalAudioMessage::External(msg) => scheduler.apply(msg),
+            InternalAudioMessage::Shutdown => scheduler.apply(AudioMessage::Pause),
+            InternalAudioMessage::SetSample(class, sample) => scheduler.set_sample(class, sample),
+            InternalAudioMessage::SetClockQueue(queue) => {
+                if let Some(old) = inbox.clock.set_queue(queue) {
+                    inbox.retire(Retired::ClockQueue(old));
                 }
             }
-            if let Some(volume) = volume_to_change_to {
-                AUDIO_VOLUME.store(volume, SyncOrdering::SeqCst);
-            }
-
-            if paused != new_paused_state {
-                paused = new_paused_state;
-                if paused {
-                    AUDIO_FLAG.store(false, SyncOrdering::SeqCst);
+            InternalAudioMessage::SetBeatQueue(queue) => {
+                if let Some(old) = inbox.beats.set_queue(queue) {
+                    inbox.retire(Retired::BeatQueue(old));
                 }
             }
-
-            if !paused {
-                util::busy_sleep_from(start, busy_timing);
-                AUDIO_FLAG.store(false, SyncOrdering::SeqCst);
-                // TODO set all values in the smaller phase (usually silent time)
-                util::busy_sleep_from(sleep_start, sleep_timing);
+            InternalAudioMessage::SetSyncQueue(queue) => {
+                if let Some(old) = std::mem::replace(&mut inbox.sync, queue) {
+                    inbox.retire(Retired::SyncQueue(old));
+                }
             }
-        }
-    }
-}
-
-fn get_message(paused: bool, rx: &Receiver<InternalAudioMessage>) -> (Vec<InternalAudioMessage>, bool) {
-    let mut ret = Vec::new();
-    let mut should_continue = true;
-
-    if !paused {
-        'breakable: loop {
-            match rx.try_recv() {
-                Ok(msg) => ret.push(msg),
-                Err(e) => {
-                    if let TryRecvError::Disconnected = e {
-                        error!("Audio controller thread channel hung up!");
-                        should_continue = false;
-                    }
-                    break 'breakable;
+            InternalAudioMessage::SetTempoMap(map, next_bar) => {
+                if let Some(old) = scheduler.resume_tempo_map(map, next_bar) {
+                    inbox.retire(Retired::TempoMap(old));
                 }
             }
         }
-    } else {
-        match rx.recv() {
-            Ok(msg) => ret.push(msg),
-            Err(_) => {
-                error!("Audio controller thread channel hung up!");
-                should_continue = false;
-            }
+    }
+    if let Some(sync) = &mut inbox.sync {
+        while let Ok(TimedSync { at, event }) = sync.pop() {
+            let late = playback.saturating_duration_since(at).as_secs_f64() * scheduler.sample_rate();
+            scheduler.on_sync(event, late);
         }
-    };
-
-    (ret, should_continue)
+    }
 }
 
-fn sample_next(o: &mut SampleRequestOptions, active: bool, vol: u16) -> f32 {
+pub(crate) fn sample_next(o: &mut SampleRequestOptions, voice: Option<Voice>, vol: u16) -> f32 {
     o.tick();
-    if active {
-        o.tone(659.25) * ((vol as f32) / 1000.)
+    if let Some(voice) = voice {
+        o.voice(voice) * ((vol as f32) / 1000.)
     } else {
         o.reset_clock();
         0.
     }
-    // combination of several tones
 }
 
 #[derive(Debug)]
@@ -295,86 +627,106 @@ pub struct SampleRequestOptions {
     pub sample_rate: f32,
     pub sample_clock: f32,
     pub nchannels: usize,
+    pub noise: NoiseState,
 }
 
 impl SampleRequestOptions {
-    fn tone(&self, freq: f32) -> f32 {
-        // TODO different waves apart from square wave
-        match (self.sample_clock * freq * 2.0 * std::f32::consts::PI / self.sample_rate).sin().partial_cmp(&0.0).unwrap() {
-            Ordering::Less => -1.,
-            Ordering::Equal => 0.,
-            Ordering::Greater => 1.
+    fn voice(&mut self, voice: Voice) -> f32 {
+        match voice {
+            Voice::Synth(voice) => voice.sample(self.sample_clock / self.sample_rate, self.sample_rate, &mut self.noise),
+            // The clock has already ticked once on the onset frame
+            Voice::Sample(sample) => sample.frame((self.sample_clock as usize).saturating_sub(1)),
         }
     }
 
     fn tick(&mut self) {
-        self.sample_clock = (self.sample_clock + 1.0) % self.sample_rate;
+        self.sample_clock += 1.0;
     }
 
     fn reset_clock(&mut self) {
         self.sample_clock = 0.;
+        self.noise.reset();
     }
 }
 
-pub fn stream_setup_for<F>(on_sample: F) -> Result<cpal::Stream, anyhow::Error>
+fn stream_setup_for<F>(
+    device: &cpal::Device,
+    config: cpal::SupportedStreamConfig,
+    buffer_size: Option<u32>,
+    routing: Routing,
+    on_sample: F,
+    inbox: Inbox,
+    state: EngineState,
+    position: Arc<Position>,
+) -> Result<(cpal::Stream, cpal::StreamConfig), anyhow::Error>
     where
-        F: FnMut(&mut SampleRequestOptions, bool, u16) -> f32 + std::marker::Send + 'static + Copy,
+        F: FnMut(&mut SampleRequestOptions, Option<Voice>, u16) -> f32 + std::marker::Send + 'static + Copy,
 {
-    let (_host, device, config) = host_device_setup()?;
+    let sample_format = config.sample_format();
+    let mut config: cpal::StreamConfig = config.into();
+    if let Some(frames) = buffer_size {
+        config.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
 
-    match config.sample_format() {
+    let stream = match sample_format {
         cpal::SampleFormat::F32 => {
             debug!("F32");
-            stream_make::<f32, _>(&device, &config.into(), on_sample)
+            stream_make::<f32, _>(device, &config, &routing, on_sample, inbox, state, position)
         },
         cpal::SampleFormat::I16 => {
             debug!("I16");
-            stream_make::<i16, _>(&device, &config.into(), on_sample)
+            stream_make::<i16, _>(device, &config, &routing, on_sample, inbox, state, position)
         },
         cpal::SampleFormat::U16 => {
             debug!("U16");
-            stream_make::<u16, _>(&device, &config.into(), on_sample)
+            stream_make::<u16, _>(device, &config, &routing, on_sample, inbox, state, position)
         },
-    }
+    }?;
+
+    Ok((stream, config))
 }
 
 pub fn host_device_setup(
+    settings: &OutputSettings,
 ) -> Result<(cpal::Host, cpal::Device, cpal::SupportedStreamConfig), anyhow::Error> {
-    let host = {
-        // #[cfg(target_os = "windows")]
-        // {
-        //     cpal::host_from_id(cpal::HostId::Asio).unwrap()
-        // }
-        // #[cfg(not(target_os = "windows"))]
-        // {
-        // }
-        cpal::default_host()
-    };
+    let host = cpal::host_from_id(settings.host.into())?;
 
     debug!("{:?}", host.id());
 
-    let device = host.output_devices()
-        .unwrap()
-        .filter(|d| d.name().unwrap().contains("Focusrite"))
-        .next().unwrap_or(host.default_output_device()
-                                               .ok_or_else(|| anyhow::Error::msg("Default output device is not available"))?);
-    debug!("{:?}", device.type_id());
-    println!("Output device : {}", device.name()?);
-
-    let config = device.default_output_config()?;
-    println!("Default output config : {:?}", config);
+    let device = match &settings.device {
+        Some(name) => host.output_devices()?
+            .find(|d| d.name().map_or(false, |n| &n == name))
+            .ok_or_else(|| anyhow::anyhow!("Output device '{}' is not available on {}", name, settings.host))?,
+        None => host.default_output_device()
+            .ok_or_else(|| anyhow::Error::msg("Default output device is not available"))?,
+    };
+    let name = device.name()?;
+    debug!("Output device : {}", name);
+
+    let config = match settings.sample_rate {
+        Some(rate) => device.supported_output_configs()?
+            .find(|c| c.min_sample_rate().0 <= rate && rate <= c.max_sample_rate().0)
+            .map(|c| c.with_sample_rate(cpal::SampleRate(rate)))
+            .ok_or_else(|| anyhow::anyhow!("Output device '{}' does not support a sample rate of {} Hz", name, rate))?,
+        None => device.default_output_config()?,
+    };
+    debug!("Output config : {:?}", config);
 
     Ok((host, device, config))
 }
 
-pub fn stream_make<T, F>(
+fn stream_make<T, F>(
     device: &cpal::Device,
     config: &cpal::StreamConfig,
+    routing: &Routing,
     on_sample: F,
+    inbox: Inbox,
+    state: EngineState,
+    position: Arc<Position>,
 ) -> Result<cpal::Stream, anyhow::Error>
     where
         T: cpal::Sample,
-        F: FnMut(&mut SampleRequestOptions, bool, u16) -> f32 + std::marker::Send + 'static + Copy,
+        F: FnMut(&mut SampleRequestOptions, Option<Voice>, u16) -> f32 + std::marker::Send + 'static + Copy,
 {
     let sample_rate = config.sample_rate.0 as f32;
     let sample_clock = 0f32;
@@ -383,16 +735,27 @@ pub fn stream_make<T, F>(
         sample_rate,
         sample_clock,
         nchannels,
+        noise: NoiseState::default(),
     };
 
-    debug!("Request: {:?}", request);
+    let mut scheduler = ClickScheduler::new(state, sample_rate);
+    scheduler.set_position(Some(position));
+    let mut inbox = inbox;
+    let routing = routing.clone();
+
+    debug!("Request: {:?}, {:?}", request, routing);
 
     let err_fn = |err| error!("Error building output sound stream: {}", err);
 
     let stream = device.build_output_stream(
         config,
-        move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
-            on_window(output, &mut request, on_sample)
+        move |output: &mut [T], info: &cpal::OutputCallbackInfo| {
+            let timestamp = info.timestamp();
+            let latency = timestamp.playback.duration_since(&timestamp.callback).unwrap_or_default();
+            let playback = Instant::now() + latency;
+            drain_messages(&mut inbox, &mut scheduler, playback);
+            on_window(output, &mut request, &mut scheduler, &routing, inbox.clock.events(), inbox.beats.events(), on_sample);
+            inbox.flush(playback, sample_rate);
         },
         err_fn,
     )?;
@@ -402,17 +765,37 @@ pub fn stream_make<T, F>(
     Ok(stream)
 }
 
-fn on_window<T, F>(output: &mut [T], request: &mut SampleRequestOptions, mut on_sample: F)
+pub(crate) fn on_window<T, F>(
+    output: &mut [T],
+    request: &mut SampleRequestOptions,
+    scheduler: &mut ClickScheduler,
+    routing: &Routing,
+    mut clock: Option<&mut clock::CallbackEvents<clock::ClockEvent>>,
+    mut beats: Option<&mut clock::CallbackEvents<clock::BeatEvent>>,
+    mut on_sample: F,
+)
     where
         T: cpal::Sample,
-        F: FnMut(&mut SampleRequestOptions, bool, u16) -> f32 + std::marker::Send + 'static,
+        F: FnMut(&mut SampleRequestOptions, Option<Voice>, u16) -> f32 + std::marker::Send + 'static,
 {
-    let active = AUDIO_FLAG.load(SyncOrdering::SeqCst);
-    let volume = AUDIO_VOLUME.load(SyncOrdering::SeqCst);
-    for frame in output.chunks_mut(request.nchannels) {
-        let value: T = cpal::Sample::from::<f32>(&on_sample(request, active, volume));
-        for sample in frame.iter_mut() {
-            *sample = value;
+    for (i, frame) in output.chunks_mut(request.nchannels).enumerate() {
+        let click = scheduler.next_frame(request);
+        if let Some(events) = clock.as_mut() {
+            scheduler.take_clock_events(|event| events.push((i, event)));
+        }
+        if let (Some(events), Some(beat)) = (beats.as_mut(), scheduler.take_beat()) {
+            events.push((i, beat));
+        }
+        let volume = scheduler.click_volume(click);
+        let voice = click.map(|class| scheduler.voice(class));
+        let value = on_sample(request, voice, volume);
+        let cue = scheduler.cue_sample();
+        for (channel, sample) in frame.iter_mut().enumerate() {
+            let mut mixed = if routing.carries(channel, click) { value } else { 0. };
+            if let (Some(cue), true) = (cue, routing.cues(channel)) {
+                mixed = (mixed + cue).clamp(-1., 1.);
+            }
+            *sample = cpal::Sample::from::<f32>(&mixed);
         }
     }
 }
@@ -0,0 +1,298 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use super::meter::{BeatClass, TimeSignature};
+use super::tempo::Bpm;
+
+// MIDI clock runs at 24 pulses per quarter note
+pub const PULSES_PER_QUARTER: u32 = 24;
+
+// Events a stream can queue before the listener takes them, a few seconds of pulses at fast tempos
+const QUEUE_SIZE: usize = 1024;
+// Events kept from a single callback, more than enough for 24 pulses a quarter and every click
+// of a long buffer at the highest tempo
+const CALLBACK_EVENTS: usize = 512;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockEvent {
+    // Playback (re)started on the first beat of a bar
+    Start { pulses_per_bar: u32 },
+    Stop,
+    Pulse,
+}
+
+// A click of the engine, subdivisions included
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BeatEvent {
+    pub bar: u32,
+    // Counted from 1
+    pub beat: u32,
+    // 0 on the beat, then the subdivisions of the beat
+    pub tick: u8,
+    pub class: BeatClass,
+    pub bpm: Bpm,
+}
+
+// An event of the engine together with the moment its frame reaches the speakers
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timed<E> {
+    pub at: Instant,
+    pub event: E,
+}
+
+pub type TimedClock = Timed<ClockEvent>;
+pub type TimedBeat = Timed<BeatEvent>;
+
+// What a clock master tells the engine
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SyncEvent {
+    Start,
+    Continue,
+    Stop,
+    // Smoothed tempo in quarter notes per minute
+    Tempo(f64),
+    // Sent on every sixteenth while running, counted from the song start
+    Sixteenth(u32),
+    Lost,
+}
+
+// A sync event together with the moment it arrived
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TimedSync {
+    pub at: Instant,
+    pub event: SyncEvent,
+}
+
+pub fn pulses_per_bar(signature: &TimeSignature) -> u32 {
+    (signature.numerator() as u32 * 4 * PULSES_PER_QUARTER / signature.denominator() as u32).max(1)
+}
+
+// The events of one stream, in the order the engine produced them
+pub type Events<E> = Consumer<Timed<E>>;
+pub type ClockEvents = Events<ClockEvent>;
+pub type BeatEvents = Events<BeatEvent>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueueProblem {
+    // The listener is gone
+    Stopped,
+    // The listener fell behind and events were dropped
+    Overflowed,
+}
+
+// What went wrong in the audio thread, which must not log. Read and reset by the listener side.
+#[derive(Debug, Default)]
+pub struct QueueStatus {
+    overflowed: AtomicBool,
+    disconnected: AtomicBool,
+}
+
+impl QueueStatus {
+    pub fn take_problem(&self) -> Option<QueueProblem> {
+        if self.disconnected.swap(false, Ordering::Relaxed) {
+            Some(QueueProblem::Stopped)
+        } else if self.overflowed.swap(false, Ordering::Relaxed) {
+            Some(QueueProblem::Overflowed)
+        } else {
+            None
+        }
+    }
+}
+
+// Hand this to `AudioHandle::set_clock_sink` or `set_beat_sink`. Every stream the handle opens
+// gets its own queue, the listener receives the consumer ends in the order the streams were opened.
+#[derive(Debug, Clone)]
+pub struct EventSink<E> {
+    queues: Sender<Events<E>>,
+    status: Arc<QueueStatus>,
+}
+
+pub type ClockSink = EventSink<ClockEvent>;
+pub type BeatSink = EventSink<BeatEvent>;
+
+impl<E> EventSink<E> {
+    pub fn new() -> (EventSink<E>, Receiver<Events<E>>) {
+        let (queues, rx) = channel();
+        let sink = EventSink {
+            queues,
+            status: Arc::new(QueueStatus::default()),
+        };
+        (sink, rx)
+    }
+
+    pub fn status(&self) -> &Arc<QueueStatus> {
+        &self.status
+    }
+
+    // Called outside the audio thread, for a stream that is about to open
+    pub(crate) fn queue(&self) -> Option<EventQueue<E>> {
+        let (producer, consumer) = RingBuffer::new(QUEUE_SIZE);
+        if self.queues.send(consumer).is_err() {
+            self.status.disconnected.store(true, Ordering::Relaxed);
+            return None;
+        }
+        Some(EventQueue {
+            producer,
+            status: self.status.clone(),
+            abandoned: false,
+        })
+    }
+}
+
+// Hand this to `AudioHandle::set_sync_source`. Every stream the handle opens gets its own queue,
+// the source receives the producer ends and has to push to the last one.
+#[derive(Debug, Clone)]
+pub struct SyncSource {
+    queues: Sender<Producer<TimedSync>>,
+}
+
+impl SyncSource {
+    pub fn new() -> (SyncSource, Receiver<Producer<TimedSync>>) {
+        let (queues, rx) = channel();
+        (SyncSource { queues }, rx)
+    }
+
+    // Called outside the audio thread, for a stream that is about to open
+    pub(crate) fn queue(&self) -> Option<Consumer<TimedSync>> {
+        let (producer, consumer) = RingBuffer::new(QUEUE_SIZE);
+        self.queues.send(producer).ok()?;
+        Some(consumer)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct EventQueue<E> {
+    producer: Producer<Timed<E>>,
+    status: Arc<QueueStatus>,
+    // The listener is gone and that was reported
+    abandoned: bool,
+}
+
+pub(crate) type ClockQueue = EventQueue<ClockEvent>;
+pub(crate) type BeatQueue = EventQueue<BeatEvent>;
+
+// The events of one callback by frame. Once full, further events are dropped instead of growing.
+#[derive(Debug)]
+pub(crate) struct CallbackEvents<E> {
+    list: Vec<(usize, E)>,
+    overflowed: bool,
+}
+
+impl<E> CallbackEvents<E> {
+    pub fn push(&mut self, event: (usize, E)) {
+        if self.list.len() < self.list.capacity() {
+            self.list.push(event);
+        } else {
+            self.overflowed = true;
+        }
+    }
+}
+
+// Collects the events of one callback and queues them with their timestamps.
+// Nothing here allocates, frees or blocks, it runs in the audio thread.
+#[derive(Debug)]
+pub(crate) struct EventOutput<E> {
+    queue: Option<EventQueue<E>>,
+    events: CallbackEvents<E>,
+}
+
+pub(crate) type ClockOutput = EventOutput<ClockEvent>;
+pub(crate) type BeatOutput = EventOutput<BeatEvent>;
+
+impl<E: Copy> EventOutput<E> {
+    pub fn new(queue: Option<EventQueue<E>>) -> EventOutput<E> {
+        EventOutput {
+            queue,
+            events: CallbackEvents {
+                list: Vec::with_capacity(CALLBACK_EVENTS),
+                overflowed: false,
+            },
+        }
+    }
+
+    // Returns the replaced queue, which must be freed outside the audio thread
+    pub fn set_queue(&mut self, queue: Option<EventQueue<E>>) -> Option<EventQueue<E>> {
+        std::mem::replace(&mut self.queue, queue)
+    }
+
+    // Only collect events if somebody listens
+    pub fn events(&mut self) -> Option<&mut CallbackEvents<E>> {
+        match &self.queue {
+            Some(queue) if !queue.producer.is_abandoned() => Some(&mut self.events),
+            _ => None,
+        }
+    }
+
+    // `playback` is when the first frame of the callback will be heard
+    pub fn flush(&mut self, playback: Instant, sample_rate: f32) {
+        if let Some(queue) = &mut self.queue {
+            if queue.producer.is_abandoned() {
+                if !queue.abandoned {
+                    queue.abandoned = true;
+                    queue.status.disconnected.store(true, Ordering::Relaxed);
+                }
+            } else {
+                if self.events.overflowed {
+                    queue.status.overflowed.store(true, Ordering::Relaxed);
+                }
+                for (frame, event) in self.events.list.iter() {
+                    let at = playback + Duration::from_secs_f64(*frame as f64 / sample_rate as f64);
+                    if queue.producer.push(Timed { at, event: *event }).is_err() {
+                        queue.status.overflowed.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                }
+            }
+        }
+        self.events.list.clear();
+        self.events.overflowed = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_a_full_or_abandoned_queue() {
+        let (sink, queues) = ClockSink::new();
+        let mut output = ClockOutput::new(sink.queue());
+        let mut events = queues.try_recv().unwrap();
+        let now = Instant::now();
+
+        output.events().unwrap().push((48, ClockEvent::Pulse));
+        output.flush(now, 48000.);
+        assert_eq!(events.pop().unwrap(), TimedClock { at: now + Duration::from_millis(1), event: ClockEvent::Pulse });
+        assert_eq!(sink.status().take_problem(), None);
+
+        for _ in 0..QUEUE_SIZE + 1 {
+            output.events().unwrap().push((0, ClockEvent::Pulse));
+            output.flush(now, 48000.);
+        }
+        assert_eq!(sink.status().take_problem(), Some(QueueProblem::Overflowed));
+        assert_eq!(sink.status().take_problem(), None);
+
+        // A single callback with more events than are kept
+        while events.pop().is_ok() {}
+        let callback = output.events().unwrap();
+        let capacity = callback.list.capacity();
+        for _ in 0..capacity + 1 {
+            callback.push((0, ClockEvent::Pulse));
+        }
+        assert_eq!(callback.list.capacity(), capacity);
+        output.flush(now, 48000.);
+        assert_eq!(sink.status().take_problem(), Some(QueueProblem::Overflowed));
+        assert_eq!(events.slots(), QUEUE_SIZE - capacity);
+
+        drop(events);
+        assert!(output.events().is_none());
+        output.flush(now, 48000.);
+        output.flush(now, 48000.);
+        assert_eq!(sink.status().take_problem(), Some(QueueProblem::Stopped));
+        assert_eq!(sink.status().take_problem(), None);
+    }
+}
@@ -0,0 +1,455 @@
+use std::fmt::{Debug, Formatter};
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use jack::jack_sys;
+use log::{debug, error, info};
+
+use super::meter::TimeSignature;
+use super::scheduler::{ClickScheduler, EngineState};
+use super::tempo::{Bpm, Position};
+use super::voice::NoiseState;
+use super::routing::Routing;
+use super::{drain_messages, on_window, sample_next, AudioMessage, Inbox, SampleRequestOptions};
+use crate::config::{JackConfig, JackTransport};
+
+const AUDIO_TYPE: &str = "32 bit float mono audio";
+const TICKS_PER_BEAT: f64 = 1920.;
+
+// The position the timebase callback publishes, updated by the process callback of the same cycle
+#[derive(Debug, Default)]
+struct Timebase {
+    // Bar in the upper and beat in the lower half, like `Position`
+    position: AtomicU64,
+    beat_phase: AtomicU64,
+    bpm: AtomicU64,
+    // Numerator in the upper and denominator in the lower byte
+    meter: AtomicU32,
+    // Ticks before the current bar, summed up bar by bar as the meter changes
+    bar_start_tick: AtomicU64,
+}
+
+impl Timebase {
+    fn update(&self, scheduler: &ClickScheduler, position: &Position) {
+        let (bar, beat) = position.get();
+        let state = scheduler.state();
+        let meter = &state.time_signature;
+
+        let previous = (self.position.load(Ordering::Relaxed) >> 32) as u32;
+        if bar != previous {
+            let start = if previous > 0 && bar > previous {
+                // Bars are shorter than a cycle, so only the bar before had the meter of the last update
+                let ticks_per_bar = (self.meter.load(Ordering::Relaxed) >> 8) as f64 * TICKS_PER_BEAT;
+                f64::from_bits(self.bar_start_tick.load(Ordering::Relaxed)) + (bar - previous) as f64 * ticks_per_bar
+            } else {
+                // A new song, its bars before this one are not known
+                bar.saturating_sub(1) as f64 * meter.numerator() as f64 * TICKS_PER_BEAT
+            };
+            self.bar_start_tick.store(start.to_bits(), Ordering::Relaxed);
+        }
+        self.position.store((bar as u64) << 32 | beat as u64, Ordering::Relaxed);
+        self.beat_phase.store(scheduler.beat_phase().to_bits(), Ordering::Relaxed);
+        self.bpm.store(state.bpm.as_f64().to_bits(), Ordering::Relaxed);
+        self.meter.store((meter.numerator() as u32) << 8 | meter.denominator() as u32, Ordering::Relaxed);
+    }
+
+    fn bbt(&self) -> jack::TransportBBT {
+        let position = self.position.load(Ordering::Relaxed);
+        let meter = self.meter.load(Ordering::Relaxed);
+        let beats_per_bar = ((meter >> 8) as f32).max(1.);
+        // Nothing has played yet before the first downbeat
+        let bar = ((position >> 32) as usize).max(1);
+        let beat = ((position as u32) as usize).clamp(1, beats_per_bar as usize);
+        let phase = f64::from_bits(self.beat_phase.load(Ordering::Relaxed));
+        jack::TransportBBT {
+            bar,
+            beat,
+            tick: ((phase * TICKS_PER_BEAT) as usize).min(TICKS_PER_BEAT as usize - 1),
+            sig_num: beats_per_bar,
+            sig_denom: ((meter & 0xFF) as f32).max(1.),
+            ticks_per_beat: TICKS_PER_BEAT,
+            bpm: f64::from_bits(self.bpm.load(Ordering::Relaxed)),
+            bar_start_tick: f64::from_bits(self.bar_start_tick.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+// Called by JACK in the process thread, right after the process callback, while this client is timebase master
+unsafe extern "C" fn publish_timebase(
+    _state: jack_sys::jack_transport_state_t,
+    _frames: jack_sys::jack_nframes_t,
+    position: *mut jack_sys::jack_position_t,
+    _new_position: c_int,
+    timebase: *mut c_void,
+) {
+    let timebase = &*(timebase as *const Timebase);
+    // TransportPosition is a transparent wrapper around jack_position_t
+    let position = &mut *(position as *mut jack::TransportPosition);
+    let _ = position.set_bbt(Some(timebase.bbt()));
+}
+
+// Frames from the start of the cycle to the next downbeat of the transport
+fn frames_to_downbeat(bbt: &jack::TransportBBT, frame_rate: f64) -> Option<f64> {
+    if bbt.bpm <= 0. || bbt.sig_num <= 0. || bbt.ticks_per_beat <= 0. {
+        return None;
+    }
+    let beats_into_bar = (bbt.beat - 1) as f64 + bbt.tick as f64 / bbt.ticks_per_beat;
+    if beats_into_bar == 0. {
+        return Some(0.);
+    }
+    let beats_left = bbt.sig_num as f64 - beats_into_bar;
+    Some(beats_left * 60. / bbt.bpm * frame_rate)
+}
+
+// Makes the engine of a slave start, stop, and change tempo and meter with the transport.
+// Runs in the process callback, changes are only applied when the transport changed.
+#[derive(Debug, Default)]
+struct TransportFollower {
+    rolling: bool,
+    bpm: Option<Bpm>,
+    meter: Option<TimeSignature>,
+}
+
+impl TransportFollower {
+    fn update(&mut self, rolling: bool, bbt: Option<jack::TransportBBT>, scheduler: &mut ClickScheduler) {
+        if let Some(bbt) = bbt {
+            let meter = TimeSignature::new(bbt.sig_num as u8, bbt.sig_denom as u8).ok();
+            if let Some(meter) = meter.filter(|meter| Some(*meter) != self.meter) {
+                self.meter = Some(meter);
+                scheduler.apply(AudioMessage::SetTimeSignature(meter));
+            }
+            let bpm = Bpm::from_f64(bbt.bpm).ok();
+            if let Some(bpm) = bpm.filter(|bpm| Some(*bpm) != self.bpm) {
+                self.bpm = Some(bpm);
+                scheduler.apply(AudioMessage::SetBpm(bpm));
+            }
+        }
+        if rolling != self.rolling {
+            self.rolling = rolling;
+            scheduler.apply(if rolling { AudioMessage::Play } else { AudioMessage::Pause });
+        }
+    }
+}
+
+struct Process {
+    ports: Vec<jack::Port<jack::AudioOut>>,
+    inbox: Inbox,
+    scheduler: ClickScheduler,
+    routing: Routing,
+    request: SampleRequestOptions,
+    // The frames of a cycle, interleaved like the buffer of a stream
+    frames: Vec<f32>,
+    transport: JackTransport,
+    follower: TransportFollower,
+    timebase: Arc<Timebase>,
+    position: Arc<Position>,
+    was_playing: bool,
+}
+
+impl Process {
+    fn render(&mut self, from: usize, to: usize, playback: Instant) {
+        let channels = self.ports.len();
+        let frames = &mut self.frames[from * channels..to * channels];
+        let (clock, beats) = (self.inbox.clock.events(), self.inbox.beats.events());
+        on_window(frames, &mut self.request, &mut self.scheduler, &self.routing, clock, beats, sample_next);
+        let offset = Duration::from_secs_f64(from as f64 / self.request.sample_rate as f64);
+        self.inbox.flush(playback + offset, self.request.sample_rate);
+    }
+
+    // Follows the transport and returns the frame of this cycle its next downbeat falls on, if it rolls
+    fn follow_transport(&mut self, client: &jack::Client, frames: usize) -> Option<usize> {
+        let status = client.transport().query().ok()?;
+        let rolling = status.state == jack::TransportState::Rolling;
+        let bbt = status.pos.bbt();
+        self.follower.update(rolling, bbt, &mut self.scheduler);
+        if !rolling || !self.scheduler.state().playing {
+            return None;
+        }
+        let frame = frames_to_downbeat(&bbt?, self.request.sample_rate as f64)?.ceil() as usize;
+        Some(frame).filter(|frame| *frame < frames)
+    }
+}
+
+impl jack::ProcessHandler for Process {
+    fn process(&mut self, client: &jack::Client, scope: &jack::ProcessScope) -> jack::Control {
+        let channels = self.ports.len();
+        // Sized in `buffer_size`, a longer cycle than announced is cut short rather than allocating here
+        let frames = (scope.n_frames() as usize).min(self.frames.len() / channels);
+        // The first frame is heard once the cycle is through
+        let playback = Instant::now() + Duration::from_secs_f64(frames as f64 / self.request.sample_rate as f64);
+        drain_messages(&mut self.inbox, &mut self.scheduler, playback);
+
+        let downbeat = match self.transport {
+            JackTransport::Slave => self.follow_transport(client, frames),
+            _ => None,
+        };
+        match downbeat {
+            Some(downbeat) => {
+                self.render(0, downbeat, playback);
+                self.scheduler.follow(0.);
+                self.render(downbeat, frames, playback);
+            }
+            None => self.render(0, frames, playback),
+        }
+        for (channel, port) in self.ports.iter_mut().enumerate() {
+            let output = port.as_mut_slice(scope);
+            let (heard, rest) = output.split_at_mut(frames.min(output.len()));
+            for (sample, frame) in heard.iter_mut().zip(self.frames.chunks(channels)) {
+                *sample = frame[channel];
+            }
+            rest.fill(0.);
+        }
+
+        if let JackTransport::Master = self.transport {
+            let playing = self.scheduler.state().playing;
+            if playing != self.was_playing {
+                let transport = client.transport();
+                let _ = if playing { transport.start() } else { transport.stop() };
+                self.was_playing = playing;
+            }
+            self.timebase.update(&self.scheduler, &self.position);
+        }
+        jack::Control::Continue
+    }
+
+    // Called before the first cycle with a new size, the only place the buffer grows
+    fn buffer_size(&mut self, _: &jack::Client, size: jack::Frames) -> jack::Control {
+        self.frames.resize(size as usize * self.ports.len(), 0.);
+        jack::Control::Continue
+    }
+}
+
+// Plays the click on ports of its own JACK client instead of a cpal stream
+pub struct JackOutput {
+    client: jack::AsyncClient<(), Process>,
+    // Has to live as long as the timebase callback is registered
+    timebase: Option<Arc<Timebase>>,
+    name: String,
+    sample_rate: u32,
+}
+
+impl JackOutput {
+    pub fn open(
+        config: &JackConfig,
+        routing: Routing,
+        inbox: Inbox,
+        state: EngineState,
+        position: Arc<Position>,
+    ) -> Result<JackOutput, anyhow::Error> {
+        let (client, status) = jack::Client::new(&config.client_name, jack::ClientOptions::NO_START_SERVER)
+            .map_err(|e| anyhow::anyhow!("Could not connect to the JACK server ({:?})", e))?;
+        debug!("JACK client status: {:?}", status);
+        let name = client.name().to_string();
+        let sample_rate = client.sample_rate() as u32;
+
+        let ports = (1..=config.outputs)
+            .map(|n| client.register_port(&format!("click_{}", n), jack::AudioOut))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow::anyhow!("Could not register the JACK outputs ({:?})", e))?;
+        let port_names = ports.iter().map(|p| p.name()).collect::<Result<Vec<_>, _>>()?;
+
+        let mut scheduler = ClickScheduler::new(state, sample_rate as f32);
+        scheduler.set_position(Some(position.clone()));
+        let timebase = Arc::new(Timebase::default());
+        let process = Process {
+            frames: vec![0.; client.buffer_size() as usize * ports.len()],
+            ports,
+            inbox,
+            scheduler,
+            routing,
+            request: SampleRequestOptions {
+                sample_rate: sample_rate as f32,
+                sample_clock: 0.,
+                nchannels: config.outputs as usize,
+                noise: NoiseState::default(),
+            },
+            transport: config.transport,
+            follower: TransportFollower::default(),
+            timebase: timebase.clone(),
+            position,
+            was_playing: false,
+        };
+
+        let client = client.activate_async((), process)
+            .map_err(|e| anyhow::anyhow!("Could not activate the JACK client ({:?})", e))?;
+        let mut output = JackOutput {
+            client,
+            timebase: None,
+            name,
+            sample_rate,
+        };
+        output.connect(&port_names, &config.connect);
+
+        match config.transport {
+            JackTransport::Off => {}
+            JackTransport::Master => {
+                let registered = unsafe {
+                    jack_sys::jack_set_timebase_callback(
+                        output.client.as_client().raw(),
+                        0,
+                        Some(publish_timebase),
+                        Arc::as_ptr(&timebase) as *mut c_void,
+                    )
+                };
+                if registered != 0 {
+                    return Err(anyhow::anyhow!("Could not become JACK timebase master (error {})", registered));
+                }
+                output.timebase = Some(timebase);
+                info!("Publishing bar, beat and tempo as JACK timebase master");
+            }
+            JackTransport::Slave => info!("Following the JACK transport"),
+        }
+        Ok(output)
+    }
+
+    // Connects the outputs in order, to the physical playback ports if none are configured
+    fn connect(&self, outputs: &[String], destinations: &[String]) {
+        let client = self.client.as_client();
+        let physical;
+        let destinations = if destinations.is_empty() {
+            let flags = jack::PortFlags::IS_INPUT | jack::PortFlags::IS_PHYSICAL;
+            physical = client.ports(None, Some(AUDIO_TYPE), flags);
+            &physical
+        } else {
+            destinations
+        };
+        for (output, destination) in outputs.iter().zip(destinations) {
+            match client.connect_ports_by_name(output, destination) {
+                Ok(()) => debug!("Connected {} to {}", output, destination),
+                Err(e) => error!("Could not connect {} to {} ({:?})", output, destination, e),
+            }
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+impl Debug for JackOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "JackOutput({})", self.name)
+    }
+}
+
+impl Drop for JackOutput {
+    fn drop(&mut self) {
+        if self.timebase.is_some() {
+            unsafe {
+                jack_sys::jack_release_timebase(self.client.as_client().raw());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::InternalAudioMessage;
+
+    fn bbt(bar: usize, beat: usize, tick: usize, bpm: f64) -> jack::TransportBBT {
+        jack::TransportBBT {
+            bar,
+            beat,
+            tick,
+            sig_num: 4.,
+            sig_denom: 4.,
+            ticks_per_beat: TICKS_PER_BEAT,
+            bpm,
+            bar_start_tick: 0.,
+        }
+    }
+
+    #[test]
+    fn finds_the_next_downbeat() {
+        assert_eq!(frames_to_downbeat(&bbt(3, 1, 0, 120.), 48000.), Some(0.));
+        assert_eq!(frames_to_downbeat(&bbt(3, 4, 0, 120.), 48000.), Some(24000.));
+        assert_eq!(frames_to_downbeat(&bbt(3, 4, 960, 60.), 48000.), Some(24000.));
+        assert_eq!(frames_to_downbeat(&bbt(3, 1, 0, 0.), 48000.), None);
+    }
+
+    #[test]
+    fn follows_the_transport() {
+        let mut follower = TransportFollower::default();
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 48000.);
+        let mut bbt = bbt(1, 1, 0, 92.46);
+        bbt.sig_num = 7.;
+        bbt.sig_denom = 8.;
+        follower.update(true, Some(bbt), &mut scheduler);
+        let state = *scheduler.state();
+        assert_eq!(state.bpm, "92.46".parse().unwrap());
+        assert_eq!((state.time_signature.numerator(), state.time_signature.denominator()), (7, 8));
+        assert!(state.playing);
+
+        bbt.bpm = 92.51;
+        follower.update(true, Some(bbt), &mut scheduler);
+        assert_eq!(scheduler.state().bpm, "92.51".parse().unwrap());
+        follower.update(false, None, &mut scheduler);
+        assert!(!scheduler.state().playing);
+    }
+
+    #[test]
+    fn publishes_the_engine_position() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 1000.);
+        let position = Arc::new(Position::default());
+        scheduler.set_position(Some(position.clone()));
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(60).unwrap()));
+        scheduler.apply(AudioMessage::Play);
+        let mut request = SampleRequestOptions {
+            sample_rate: 1000.,
+            sample_clock: 0.,
+            nchannels: 1,
+            noise: NoiseState::default(),
+        };
+        for _ in 0..5500 {
+            scheduler.next_frame(&mut request);
+        }
+
+        let timebase = Timebase::default();
+        timebase.update(&scheduler, &position);
+        let bbt = timebase.bbt();
+        assert_eq!((bbt.bar, bbt.beat, bbt.tick), (2, 2, 960));
+        assert_eq!((bbt.sig_num, bbt.sig_denom, bbt.bpm), (4., 4., 60.));
+        assert_eq!(bbt.bar_start_tick, 4. * TICKS_PER_BEAT);
+        assert!(bbt.validated().is_ok());
+
+        // Bars keep the length they were played with, the second one ends after three beats
+        scheduler.apply(AudioMessage::SetTimeSignature("3/4".parse().unwrap()));
+        for _ in 0..3000 {
+            scheduler.next_frame(&mut request);
+            timebase.update(&scheduler, &position);
+        }
+        let bbt = timebase.bbt();
+        assert_eq!((bbt.bar, bbt.beat, bbt.sig_num), (3, 2, 3.));
+        assert_eq!(bbt.bar_start_tick, 7. * TICKS_PER_BEAT);
+    }
+
+    // Needs a running server, for example `jackd -d dummy`
+    #[test]
+    #[ignore]
+    fn plays_on_named_ports_as_timebase_master() {
+        let config = JackConfig {
+            client_name: String::from("metronome-test"),
+            outputs: 1,
+            connect: Vec::new(),
+            transport: JackTransport::Master,
+        };
+        let (tx, inbox, _retired) = Inbox::new();
+        let output = JackOutput::open(&config, Routing::default(), inbox, EngineState::default(), Arc::new(Position::default())).unwrap();
+        tx.send(InternalAudioMessage::External(AudioMessage::SetBpm(Bpm::whole(133).unwrap()))).unwrap();
+        tx.send(InternalAudioMessage::External(AudioMessage::Play)).unwrap();
+
+        let client = output.client.as_client();
+        assert_eq!(client.ports(Some("metronome-test:click_.*"), None, jack::PortFlags::empty()), vec!["metronome-test:click_1"]);
+        std::thread::sleep(Duration::from_millis(500));
+        let status = client.transport().query().unwrap();
+        assert_eq!(status.state, jack::TransportState::Rolling);
+        assert_eq!(status.pos.bbt().unwrap().bpm, 133.);
+    }
+}
@@ -0,0 +1,373 @@
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+// The most beats a bar can have, limited by the accent bitmask
+pub const MAX_BEATS: u8 = 32;
+pub const MAX_DIVISIONS: u8 = 7;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BeatClass {
+    Accent,
+    SecondaryAccent,
+    Beat,
+    Subdivision,
+}
+
+impl BeatClass {
+    pub const ALL: [BeatClass; 4] = [
+        BeatClass::Accent,
+        BeatClass::SecondaryAccent,
+        BeatClass::Beat,
+        BeatClass::Subdivision,
+    ];
+}
+
+// Named like the voices in the config
+impl Display for BeatClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BeatClass::Accent => "accent",
+            BeatClass::SecondaryAccent => "secondary_accent",
+            BeatClass::Beat => "beat",
+            BeatClass::Subdivision => "subdivision",
+        })
+    }
+}
+
+impl FromStr for BeatClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BeatClass::ALL
+            .iter()
+            .find(|c| c.to_string().eq_ignore_ascii_case(s.trim()))
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Unknown beat class '{}' (available: accent, secondary_accent, beat, subdivision)", s))
+    }
+}
+
+// One click is played per `denominator` note, so the BPM always counts the notes
+// of the denominator (eighths in 7/8, quarters in 5/4).
+// `accents` is a bitmask of the beats that get a secondary accent, beat one is always accented.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeSignature {
+    numerator: u8,
+    denominator: u8,
+    accents: u32,
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        TimeSignature {
+            numerator: 4,
+            denominator: 4,
+            accents: 0,
+        }
+    }
+}
+
+impl TimeSignature {
+    pub fn new(numerator: u8, denominator: u8) -> Result<TimeSignature, anyhow::Error> {
+        if numerator == 0 || numerator > MAX_BEATS {
+            return Err(anyhow::anyhow!("Numerator must be between 1 and {} (got {})", MAX_BEATS, numerator));
+        }
+        if !denominator.is_power_of_two() || denominator > 64 {
+            return Err(anyhow::anyhow!("Denominator must be a power of two up to 64 (got {})", denominator));
+        }
+
+        let signature = TimeSignature {
+            numerator,
+            denominator,
+            accents: 0,
+        };
+
+        // Compound meters (6/8, 9/8, 12/8, ...) are felt in groups of three
+        if denominator >= 8 && numerator > 3 && numerator % 3 == 0 {
+            let groups = vec![3; (numerator / 3) as usize];
+            return signature.with_groups(&groups);
+        }
+
+        Ok(signature)
+    }
+
+    // Accents the first beat of every group, e.g. [3, 2, 2] for 7/8 played as 3+2+2
+    pub fn with_groups(mut self, groups: &[u8]) -> Result<TimeSignature, anyhow::Error> {
+        let total: u32 = groups.iter().map(|g| *g as u32).sum();
+        if groups.contains(&0) || total != self.numerator as u32 {
+            return Err(anyhow::anyhow!(
+                "Beat groups {} do not add up to {}",
+                groups.iter().map(|g| g.to_string()).collect::<Vec<_>>().join("+"),
+                self.numerator
+            ));
+        }
+
+        self.accents = 0;
+        let mut beat = 0;
+        for group in groups.iter().take(groups.len() - 1) {
+            beat += *group;
+            self.accents |= 1 << beat;
+        }
+        Ok(self)
+    }
+
+    pub fn numerator(&self) -> u8 {
+        self.numerator
+    }
+
+    pub fn denominator(&self) -> u8 {
+        self.denominator
+    }
+
+    // Packs the signature into one number, so the audio thread can share it through an atomic
+    pub fn to_bits(self) -> u64 {
+        (self.numerator as u64) << 40 | (self.denominator as u64) << 32 | self.accents as u64
+    }
+
+    pub fn from_bits(bits: u64) -> TimeSignature {
+        TimeSignature {
+            numerator: (bits >> 40) as u8,
+            denominator: (bits >> 32) as u8,
+            accents: bits as u32,
+        }
+    }
+
+    pub fn groups(&self) -> Vec<u8> {
+        let mut groups = Vec::new();
+        let mut start = 0;
+        for beat in 1..self.numerator {
+            if self.accents & (1 << beat) != 0 {
+                groups.push(beat - start);
+                start = beat;
+            }
+        }
+        groups.push(self.numerator - start);
+        groups
+    }
+
+    pub fn beat_class(&self, beat: u8) -> BeatClass {
+        if beat == 0 {
+            BeatClass::Accent
+        } else if beat < MAX_BEATS && self.accents & (1 << beat) != 0 {
+            BeatClass::SecondaryAccent
+        } else {
+            BeatClass::Beat
+        }
+    }
+}
+
+// Formats as "7/8", or "7/8 3+2+2" if there are secondary accents
+impl Display for TimeSignature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)?;
+        if self.accents != 0 {
+            let groups: Vec<String> = self.groups().iter().map(|g| g.to_string()).collect();
+            write!(f, " {}", groups.join("+"))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TimeSignature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let fraction = parts.next().ok_or_else(|| anyhow::Error::msg("Empty time signature"))?;
+        let (numerator, denominator) = fraction
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("Time signature '{}' is not of the form N/D", s))?;
+        let numerator = numerator
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid numerator '{}'", numerator))?;
+        let denominator = denominator
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid denominator '{}'", denominator))?;
+        let signature = TimeSignature::new(numerator, denominator)?;
+
+        match parts.next() {
+            None => Ok(signature),
+            Some(groups) => {
+                let groups = groups
+                    .split('+')
+                    .map(|g| g.parse().map_err(|_| anyhow::anyhow!("Invalid beat group '{}'", g)))
+                    .collect::<Result<Vec<u8>, _>>()?;
+                if let Some(rest) = parts.next() {
+                    return Err(anyhow::anyhow!("Unexpected '{}' after the beat groups", rest));
+                }
+                signature.with_groups(&groups)
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for TimeSignature {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TimeSignature> for String {
+    fn from(signature: TimeSignature) -> Self {
+        signature.to_string()
+    }
+}
+
+// Number of ticks per beat, tick zero being the beat itself.
+// `muted` is a bitmask of the subdivision ticks that stay silent.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Subdivision {
+    divisions: u8,
+    muted: u8,
+}
+
+impl Default for Subdivision {
+    fn default() -> Self {
+        Subdivision {
+            divisions: 1,
+            muted: 0,
+        }
+    }
+}
+
+impl Subdivision {
+    pub fn new(divisions: u8) -> Result<Subdivision, anyhow::Error> {
+        if divisions == 0 || divisions > MAX_DIVISIONS {
+            return Err(anyhow::anyhow!("Subdivision must be between 1 and {} (got {})", MAX_DIVISIONS, divisions));
+        }
+        Ok(Subdivision { divisions, muted: 0 })
+    }
+
+    // Parses patterns like "x-xx", where every character is one tick:
+    // 'x' plays it and '-' mutes it. The first tick is the beat and cannot be muted.
+    pub fn from_pattern(pattern: &str) -> Result<Subdivision, anyhow::Error> {
+        let ticks = pattern.chars().count();
+        if ticks > MAX_DIVISIONS as usize {
+            return Err(anyhow::anyhow!("Invalid pattern '{}' (at most {} ticks per beat)", pattern, MAX_DIVISIONS));
+        }
+        let mut subdivision = Subdivision::new(ticks as u8)
+            .map_err(|e| anyhow::anyhow!("Invalid pattern '{}' ({})", pattern, e))?;
+        for (tick, c) in pattern.chars().enumerate() {
+            match c {
+                'x' | 'X' => {}
+                '-' | '.' if tick > 0 => subdivision.muted |= 1 << tick,
+                '-' | '.' => return Err(anyhow::anyhow!("The beat in pattern '{}' cannot be muted", pattern)),
+                c => return Err(anyhow::anyhow!("Invalid character '{}' in pattern '{}'", c, pattern)),
+            }
+        }
+        Ok(subdivision)
+    }
+
+    pub fn divisions(&self) -> u8 {
+        self.divisions
+    }
+
+    pub fn is_muted(&self, tick: u8) -> bool {
+        tick < MAX_DIVISIONS && self.muted & (1 << tick) != 0
+    }
+}
+
+// Formats as the number of divisions, or as a pattern if some ticks are muted
+impl Display for Subdivision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.muted == 0 {
+            write!(f, "{}", self.divisions)
+        } else {
+            for tick in 0..self.divisions {
+                f.write_str(if self.is_muted(tick) { "-" } else { "x" })?;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl FromStr for Subdivision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+            let divisions = s.parse().map_err(|_| anyhow::anyhow!("Invalid subdivision '{}'", s))?;
+            Subdivision::new(divisions)
+        } else {
+            Subdivision::from_pattern(s)
+        }
+    }
+}
+
+impl TryFrom<String> for Subdivision {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Subdivision> for String {
+    fn from(subdivision: Subdivision) -> Self {
+        subdivision.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accents_follow_groups() {
+        let signature: TimeSignature = "7/8 3+2+2".parse().unwrap();
+        let classes: Vec<BeatClass> = (0..7).map(|b| signature.beat_class(b)).collect();
+        use BeatClass::*;
+        assert_eq!(
+            classes,
+            vec![Accent, Beat, Beat, SecondaryAccent, Beat, SecondaryAccent, Beat]
+        );
+        assert_eq!(signature.to_string(), "7/8 3+2+2");
+    }
+
+    #[test]
+    fn beat_classes_round_trip() {
+        for class in BeatClass::ALL {
+            assert_eq!(class.to_string().parse::<BeatClass>().unwrap(), class);
+        }
+        assert_eq!("Secondary_Accent".parse::<BeatClass>().unwrap(), BeatClass::SecondaryAccent);
+        assert!("tick".parse::<BeatClass>().is_err());
+    }
+
+    #[test]
+    fn compound_meters_are_grouped_in_threes() {
+        let signature = TimeSignature::new(12, 8).unwrap();
+        assert_eq!(signature.groups(), vec![3, 3, 3, 3]);
+        assert_eq!(TimeSignature::new(5, 4).unwrap().groups(), vec![5]);
+    }
+
+    #[test]
+    fn rejects_invalid_signatures() {
+        assert!("0/4".parse::<TimeSignature>().is_err());
+        assert!("4/3".parse::<TimeSignature>().is_err());
+        assert!("7/8 3+3+2".parse::<TimeSignature>().is_err());
+        assert!("7-8".parse::<TimeSignature>().is_err());
+    }
+
+    #[test]
+    fn subdivision_patterns_round_trip() {
+        let gallop: Subdivision = "x-xx".parse().unwrap();
+        assert_eq!(gallop.divisions(), 4);
+        assert!(gallop.is_muted(1));
+        assert!(!gallop.is_muted(2));
+        assert_eq!(gallop.to_string(), "x-xx");
+        assert_eq!("5".parse::<Subdivision>().unwrap().to_string(), "5");
+        assert!("8".parse::<Subdivision>().is_err());
+        assert!("-xx".parse::<Subdivision>().is_err());
+        assert!("x-------".parse::<Subdivision>().is_err());
+        assert!("x".repeat(257).parse::<Subdivision>().is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use std::io::{Seek, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use log::debug;
+
+use std::sync::Arc;
+
+use super::meter::{BeatClass, Subdivision, TimeSignature};
+use super::sample::ClickSample;
+use super::scheduler::{ClickScheduler, EngineState};
+use super::tempo::Bpm;
+use super::voice::{NoiseState, Voices};
+use super::routing::{ChannelRouting, Routing};
+use super::{on_window, sample_next, SampleRequestOptions};
+
+// Frames rendered per call of `on_window`, roughly what a sound card would ask for
+const BLOCK_FRAMES: usize = 512;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WavFormat {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl WavFormat {
+    fn spec(&self, sample_rate: u32, channels: u16) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            WavFormat::Int16 => (16, hound::SampleFormat::Int),
+            WavFormat::Int24 => (24, hound::SampleFormat::Int),
+            WavFormat::Float32 => (32, hound::SampleFormat::Float),
+        };
+        hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+
+impl FromStr for WavFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "int16" => Ok(WavFormat::Int16),
+            "int24" => Ok(WavFormat::Int24),
+            "float32" => Ok(WavFormat::Float32),
+            _ => Err(anyhow::anyhow!("Unknown WAV format '{}' (available: int16, int24, float32)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bpm: Bpm,
+    pub time_signature: TimeSignature,
+    pub subdivision: Subdivision,
+    pub volume: u16,
+    pub subdivision_volume: u16,
+    pub voices: Voices,
+    pub duration: Duration,
+    pub format: WavFormat,
+    pub samples: Vec<(BeatClass, Arc<ClickSample>)>,
+    pub routing: ChannelRouting,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            sample_rate: 48000,
+            channels: 2,
+            bpm: Bpm::whole(120).unwrap(),
+            time_signature: TimeSignature::default(),
+            subdivision: Subdivision::default(),
+            volume: 1000,
+            subdivision_volume: EngineState::default().subdivision_volume,
+            voices: Voices::default(),
+            duration: Duration::from_secs(60),
+            format: WavFormat::Int16,
+            samples: Vec::new(),
+            routing: ChannelRouting::default(),
+        }
+    }
+}
+
+fn validate(options: &RenderOptions) -> Result<(), anyhow::Error> {
+    if options.channels == 0 {
+        return Err(anyhow::Error::msg("Cannot render with zero channels"));
+    }
+    if options.sample_rate == 0 {
+        return Err(anyhow::Error::msg("Cannot render with a sample rate of zero"));
+    }
+    options.routing.validate().context("routing")
+}
+
+// Runs the same scheduler and sample generator as the output stream, just without a device.
+// Hands out interleaved blocks, so long renders never have to fit into memory.
+fn render_blocks<F>(options: &RenderOptions, mut on_block: F) -> Result<(), anyhow::Error>
+where
+    F: FnMut(&[f32]) -> Result<(), anyhow::Error>,
+{
+    validate(options)?;
+    let mut request = SampleRequestOptions {
+        sample_rate: options.sample_rate as f32,
+        sample_clock: 0.,
+        nchannels: options.channels as usize,
+        noise: NoiseState::default(),
+    };
+    let mut scheduler = ClickScheduler::new(EngineState {
+        playing: true,
+        bpm: options.bpm,
+        volume: options.volume,
+        time_signature: options.time_signature,
+        subdivision: options.subdivision,
+        subdivision_volume: options.subdivision_volume,
+        voices: options.voices,
+        ..Default::default()
+    }, request.sample_rate);
+    for (class, sample) in &options.samples {
+        scheduler.set_sample(*class, Some(Arc::new(sample.resampled(options.sample_rate))));
+    }
+
+    let frames = (options.duration.as_secs_f64() * options.sample_rate as f64).round() as usize;
+    let mut block = vec![0f32; BLOCK_FRAMES * request.nchannels];
+    let routing = Routing::new(&options.routing, request.nchannels).context("routing")?;
+
+    debug!("Rendering {} frames ({:?})", frames, options);
+
+    let mut rendered = 0;
+    while rendered < frames {
+        let len = BLOCK_FRAMES.min(frames - rendered) * request.nchannels;
+        on_window(&mut block[..len], &mut request, &mut scheduler, &routing, None, None, sample_next);
+        on_block(&block[..len])?;
+        rendered += len / request.nchannels;
+    }
+    Ok(())
+}
+
+// Returns interleaved samples
+pub fn render(options: &RenderOptions) -> Result<Vec<f32>, anyhow::Error> {
+    let mut out = Vec::new();
+    render_blocks(options, |block| {
+        out.extend_from_slice(block);
+        Ok(())
+    })?;
+    Ok(out)
+}
+
+pub fn render_to_writer<W>(writer: W, options: &RenderOptions) -> Result<(), anyhow::Error>
+where
+    W: Write + Seek,
+{
+    validate(options)?;
+    let mut wav = hound::WavWriter::new(writer, options.format.spec(options.sample_rate, options.channels))?;
+
+    render_blocks(options, |block| {
+        match options.format {
+            WavFormat::Int16 => {
+                for sample in block {
+                    wav.write_sample((sample.clamp(-1., 1.) * i16::MAX as f32) as i16)?;
+                }
+            }
+            WavFormat::Int24 => {
+                for sample in block {
+                    wav.write_sample((sample.clamp(-1., 1.) * 8_388_607.) as i32)?;
+                }
+            }
+            WavFormat::Float32 => {
+                for sample in block {
+                    wav.write_sample(*sample)?;
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    wav.finalize()?;
+    Ok(())
+}
+
+pub fn render_to_wav<P: AsRef<Path>>(path: P, options: &RenderOptions) -> Result<(), anyhow::Error> {
+    let path = path.as_ref();
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Could not create {}", path.display()))?;
+    render_to_writer(std::io::BufWriter::new(file), options)
+        .with_context(|| format!("Could not render to {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn renders_clicks_at_beat_positions() {
+        let options = RenderOptions {
+            sample_rate: 8000,
+            channels: 2,
+            bpm: Bpm::whole(60).unwrap(),
+            duration: Duration::from_secs(3),
+            ..Default::default()
+        };
+        let samples = render(&options).unwrap();
+        assert_eq!(samples.len(), 8000 * 3 * 2);
+
+        for beat in 0..3 {
+            let frame = beat * 8000;
+            // the click is audible right after the onset and silent right before the next one
+            assert!(samples[(frame + 1) * 2..(frame + 50) * 2].iter().any(|s| *s != 0.));
+            assert!(samples[(frame + 7000) * 2..(frame + 8000) * 2].iter().all(|s| *s == 0.));
+        }
+    }
+
+    #[test]
+    fn renders_only_on_routed_channels() {
+        let options = RenderOptions {
+            sample_rate: 8000,
+            channels: 4,
+            duration: Duration::from_millis(100),
+            routing: ChannelRouting { click: vec![3, 4], ..Default::default() },
+            ..Default::default()
+        };
+        let samples = render(&options).unwrap();
+        let channel = |c: usize| samples.iter().skip(c).step_by(4).any(|s| *s != 0.);
+        assert_eq!((0..4).map(channel).collect::<Vec<_>>(), vec![false, false, true, true]);
+    }
+
+    #[test]
+    fn writes_requested_wav_format() {
+        for format in [WavFormat::Int16, WavFormat::Int24, WavFormat::Float32] {
+            let options = RenderOptions {
+                sample_rate: 22050,
+                channels: 1,
+                duration: Duration::from_millis(500),
+                format,
+                ..Default::default()
+            };
+            let mut buffer = Cursor::new(Vec::new());
+            render_to_writer(&mut buffer, &options).unwrap();
+
+            buffer.set_position(0);
+            let reader = hound::WavReader::new(buffer).unwrap();
+            assert_eq!(reader.spec(), format.spec(22050, 1));
+            assert_eq!(reader.duration(), 11025);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let options = RenderOptions {
+            channels: 0,
+            ..Default::default()
+        };
+        assert!(render_to_writer(Cursor::new(Vec::new()), &options).is_err());
+        assert!(render(&options).is_err());
+
+        let options = RenderOptions {
+            routing: ChannelRouting { click: vec![0], ..Default::default() },
+            ..Default::default()
+        };
+        assert!(render(&options).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+
+use super::meter::BeatClass;
+
+// The most channels an output is expected to have
+pub const MAX_CHANNEL: u16 = 256;
+
+// Which outputs of a device carry what, channels counted from 1 like on the interface.
+// Without click channels, every channel carries the click, also when accents or cues are routed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelRouting {
+    // Every click
+    pub click: Vec<u16>,
+    // Downbeats and secondary accents only
+    pub accent: Vec<u16>,
+    // The downbeat of every tempo map section, so the band hears where the song is
+    pub cue: Vec<u16>,
+}
+
+impl ChannelRouting {
+    pub fn is_empty(&self) -> bool {
+        self.click.is_empty() && self.accent.is_empty() && self.cue.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        for (name, channels) in [("click", &self.click), ("accent", &self.accent), ("cue", &self.cue)] {
+            if let Some(channel) = channels.iter().find(|c| !(1..=MAX_CHANNEL).contains(*c)) {
+                return Err(anyhow::anyhow!("{} channel must be between 1 and {} (got {})", name, MAX_CHANNEL, channel));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+struct Carries {
+    click: bool,
+    accent: bool,
+    cue: bool,
+}
+
+// A `ChannelRouting` resolved for an output with a fixed number of channels
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Routing {
+    // One entry per channel, empty if every channel carries the click
+    channels: Vec<Carries>,
+}
+
+impl Routing {
+    // Fails if a routed channel is not on the output
+    pub fn new(routing: &ChannelRouting, nchannels: usize) -> Result<Routing, anyhow::Error> {
+        routing.validate()?;
+        let all = routing.click.iter().chain(&routing.accent).chain(&routing.cue);
+        if let Some(channel) = all.into_iter().find(|c| **c as usize > nchannels) {
+            return Err(anyhow::anyhow!("Channel {} is routed, but the output only has {} channels", channel, nchannels));
+        }
+        Ok(Routing::resolve(routing, nchannels))
+    }
+
+    // Whatever is routed to a channel the output does not have plays on channel 1 instead
+    pub fn fallback(routing: &ChannelRouting, nchannels: usize) -> Routing {
+        Routing::resolve(routing, nchannels)
+    }
+
+    fn resolve(routing: &ChannelRouting, nchannels: usize) -> Routing {
+        if routing.is_empty() || nchannels == 0 {
+            return Routing::default();
+        }
+        let everywhere = Carries { click: routing.click.is_empty(), ..Default::default() };
+        let mut channels = vec![everywhere; nchannels];
+        let mut route = |list: &[u16], set: fn(&mut Carries)| {
+            for channel in list {
+                let index = (*channel as usize).checked_sub(1).filter(|i| *i < nchannels).unwrap_or(0);
+                set(&mut channels[index]);
+            }
+        };
+        route(&routing.click, |c| c.click = true);
+        route(&routing.accent, |c| c.accent = true);
+        route(&routing.cue, |c| c.cue = true);
+        Routing { channels }
+    }
+
+    // Whether the channel plays the click sounding on this frame
+    pub fn carries(&self, channel: usize, click: Option<BeatClass>) -> bool {
+        let carries = match self.channels.get(channel) {
+            Some(carries) => carries,
+            None => return self.channels.is_empty(),
+        };
+        match click {
+            None => false,
+            Some(_) if carries.click => true,
+            Some(BeatClass::Accent) | Some(BeatClass::SecondaryAccent) => carries.accent,
+            Some(_) => false,
+        }
+    }
+
+    // Whether the channel plays the section cue
+    pub fn cues(&self, channel: usize) -> bool {
+        self.channels.get(channel).map_or(false, |carries| carries.cue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_clicks_accents_and_cues() {
+        let routing = ChannelRouting { click: vec![3, 4], accent: vec![5], cue: vec![6] };
+        let routing = Routing::new(&routing, 18).unwrap();
+
+        use BeatClass::*;
+        let carrying = |click| (0..18).filter(|c| routing.carries(*c, click)).collect::<Vec<_>>();
+        assert_eq!(carrying(Some(Beat)), vec![2, 3]);
+        assert_eq!(carrying(Some(SecondaryAccent)), vec![2, 3, 4]);
+        assert_eq!(carrying(Some(Accent)), vec![2, 3, 4]);
+        assert_eq!(carrying(None), Vec::<usize>::new());
+        assert_eq!((0..18).filter(|c| routing.cues(*c)).collect::<Vec<_>>(), vec![5]);
+    }
+
+    #[test]
+    fn plays_everywhere_without_routing() {
+        let routing = Routing::new(&ChannelRouting::default(), 4).unwrap();
+        assert!((0..4).all(|c| routing.carries(c, Some(BeatClass::Subdivision))));
+        assert!(routing.carries(0, None));
+        assert!(!routing.cues(0));
+    }
+
+    #[test]
+    fn plays_the_click_everywhere_with_only_cues_routed() {
+        let routing = ChannelRouting { cue: vec![3], ..Default::default() };
+        let routing = Routing::new(&routing, 4).unwrap();
+        assert!((0..4).all(|c| routing.carries(c, Some(BeatClass::Beat))));
+        assert_eq!((0..4).filter(|c| routing.cues(*c)).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn missing_channels_fall_back_to_the_first() {
+        let routing = ChannelRouting { click: vec![2], cue: vec![3], ..Default::default() };
+        let error = Routing::new(&routing, 2).unwrap_err();
+        assert!(error.to_string().contains("only has 2 channels"));
+        let zero = ChannelRouting { cue: vec![0], ..Default::default() };
+        assert!(Routing::new(&zero, 2).is_err());
+
+        let routing = Routing::fallback(&routing, 2);
+        assert!(!routing.carries(0, Some(BeatClass::Beat)) && routing.carries(1, Some(BeatClass::Beat)));
+        assert!(routing.cues(0) && !routing.cues(1));
+        assert!(Routing::fallback(&zero, 2).cues(0));
+    }
+}
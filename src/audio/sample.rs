@@ -0,0 +1,210 @@
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use log::debug;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as DecodeError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use super::meter::BeatClass;
+
+// A decoded click sample, mixed down to mono
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClickSample {
+    frames: Vec<f32>,
+    sample_rate: u32,
+}
+
+impl ClickSample {
+    pub fn new(frames: Vec<f32>, sample_rate: u32) -> ClickSample {
+        ClickSample {
+            frames,
+            sample_rate,
+        }
+    }
+
+    // Decodes a WAV, FLAC or Ogg Vorbis file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ClickSample, anyhow::Error> {
+        let path = path.as_ref();
+        decode(path).with_context(|| format!("Could not load click sample {}", path.display()))
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn frame(&self, index: usize) -> f32 {
+        self.frames.get(index).copied().unwrap_or(0.)
+    }
+
+    // Linear interpolation, which is good enough for short percussive samples
+    pub fn resampled(&self, sample_rate: u32) -> ClickSample {
+        if sample_rate == self.sample_rate || self.frames.is_empty() {
+            return ClickSample::new(self.frames.clone(), sample_rate);
+        }
+
+        let ratio = self.sample_rate as f64 / sample_rate as f64;
+        let len = (self.frames.len() as f64 / ratio).ceil() as usize;
+        let frames = (0..len)
+            .map(|i| {
+                let position = i as f64 * ratio;
+                let index = position.floor() as usize;
+                let fraction = (position - index as f64) as f32;
+                let a = self.frame(index);
+                let b = self.frame(index + 1);
+                a + (b - a) * fraction
+            })
+            .collect();
+
+        ClickSample::new(frames, sample_rate)
+    }
+}
+
+fn decode(path: &Path) -> Result<ClickSample, anyhow::Error> {
+    let file = File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| anyhow::Error::msg("No audio track found"))?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or_else(|| anyhow::Error::msg("Unknown sample rate"))?;
+    let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut frames = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = decoder.decode(&packet)?;
+        let spec = *decoded.spec();
+        let channels = spec.channels.count().max(1);
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+        for frame in buffer.samples().chunks(channels) {
+            frames.push(frame.iter().sum::<f32>() / channels as f32);
+        }
+    }
+
+    if frames.is_empty() {
+        return Err(anyhow::Error::msg("The file contains no audio"));
+    }
+
+    debug!("Decoded {} ({} frames at {} Hz)", path.display(), frames.len(), sample_rate);
+
+    Ok(ClickSample::new(frames, sample_rate))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SampleBank {
+    pub accent: Option<Arc<ClickSample>>,
+    pub secondary_accent: Option<Arc<ClickSample>>,
+    pub beat: Option<Arc<ClickSample>>,
+    pub subdivision: Option<Arc<ClickSample>>,
+}
+
+impl SampleBank {
+    pub fn get(&self, class: BeatClass) -> Option<&Arc<ClickSample>> {
+        match class {
+            BeatClass::Accent => self.accent.as_ref(),
+            BeatClass::SecondaryAccent => self.secondary_accent.as_ref(),
+            BeatClass::Beat => self.beat.as_ref(),
+            BeatClass::Subdivision => self.subdivision.as_ref(),
+        }
+    }
+
+    pub fn set(&mut self, class: BeatClass, sample: Option<Arc<ClickSample>>) {
+        match class {
+            BeatClass::Accent => self.accent = sample,
+            BeatClass::SecondaryAccent => self.secondary_accent = sample,
+            BeatClass::Beat => self.beat = sample,
+            BeatClass::Subdivision => self.subdivision = sample,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("metronome-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn loads_wav_and_mixes_to_mono() {
+        let path = temp_path("stereo.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 22050,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..100 {
+            writer.write_sample(i16::MAX / 2).unwrap();
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let sample = ClickSample::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(sample.sample_rate(), 22050);
+        assert_eq!(sample.len(), 100);
+        assert!((sample.frame(50) - 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn missing_and_corrupt_files_are_errors() {
+        assert!(ClickSample::load(temp_path("does-not-exist.wav")).is_err());
+
+        let path = temp_path("corrupt.wav");
+        std::fs::write(&path, b"RIFF\x10\x00\x00\x00WAVEgarbage").unwrap();
+        let result = ClickSample::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn resampling_keeps_duration() {
+        let sample = ClickSample::new(vec![1.; 441], 44100);
+        let resampled = sample.resampled(48000);
+        assert_eq!(resampled.sample_rate(), 48000);
+        assert_eq!(resampled.len(), 480);
+    }
+}
@@ -0,0 +1,820 @@
+use std::sync::Arc;
+
+use super::clock::{pulses_per_bar, BeatEvent, ClockEvent, SyncEvent, PULSES_PER_QUARTER};
+use super::meter::{BeatClass, Subdivision, TimeSignature};
+use super::sample::{ClickSample, SampleBank};
+use super::tempo::{Bpm, Position, TempoMap};
+use super::voice::{NoiseState, Voice, Voices};
+use super::{AudioMessage, SampleRequestOptions};
+
+// How much of the difference to a clock master is corrected on every sync point
+const PHASE_GAIN: f64 = 0.1;
+// Differences this large are not drift but a jump of the master, playback jumps along
+const RELOCATE_SECONDS: f64 = 0.05;
+
+#[derive(Debug, Copy, Clone)]
+pub struct EngineState {
+    pub playing: bool,
+    pub bpm: Bpm,
+    pub volume: u16,
+    pub time_signature: TimeSignature,
+    pub subdivision: Subdivision,
+    pub subdivision_volume: u16,
+    pub voices: Voices,
+}
+
+impl Default for EngineState {
+    fn default() -> Self {
+        EngineState {
+            playing: false,
+            bpm: Bpm::whole(55).unwrap(),
+            volume: 0,
+            time_signature: TimeSignature::default(),
+            subdivision: Subdivision::default(),
+            subdivision_volume: 600,
+            voices: Voices::default(),
+        }
+    }
+}
+
+impl EngineState {
+    pub fn apply(&mut self, msg: AudioMessage) {
+        match msg {
+            AudioMessage::Play => self.playing = true,
+            AudioMessage::Pause => self.playing = false,
+            AudioMessage::Toggle => self.playing = !self.playing,
+            AudioMessage::SetBpm(bpm) => self.bpm = bpm,
+            AudioMessage::SetVolume(vol) => self.volume = vol,
+            AudioMessage::SetTimeSignature(signature) => self.time_signature = signature,
+            AudioMessage::SetSubdivision(subdivision) => self.subdivision = subdivision,
+            AudioMessage::SetSubdivisionVolume(vol) => self.subdivision_volume = vol,
+            AudioMessage::SetVoice(class, voice) => self.voices.set(class, voice),
+            AudioMessage::SetCueVoice(voice) => self.voices.cue = voice,
+        }
+    }
+}
+
+// Runs inside the stream callback and decides, frame by frame, where a click starts.
+// Tick (beat and subdivision) positions are kept as fractional frame counts, so they never
+// drift, and the onset always lands on the first frame at or after the exact tick time.
+#[derive(Debug)]
+pub struct ClickScheduler {
+    state: EngineState,
+    samples: SampleBank,
+    sample_rate: f64,
+    frames_to_next_tick: f64,
+    frames_left_in_click: u64,
+    beat_in_bar: u8,
+    tick_in_beat: u8,
+    click: Option<BeatClass>,
+    // Frames since the onset of the section cue that is sounding
+    cue_frame: Option<u64>,
+    cue_noise: NoiseState,
+    // The bar that just started begins a section
+    section_starts: bool,
+    frames_to_next_pulse: f64,
+    clock: PendingClock,
+    // The click that started on the last frame
+    beat: Option<BeatEvent>,
+    tempo_map: Option<Arc<TempoMap>>,
+    // Index of the first change in the tempo map that has not been applied yet
+    next_change: usize,
+    // Bar whose downbeat comes next, counted from 1 since the song was set
+    next_bar: u32,
+    position: Option<Arc<Position>>,
+    // A clock master started, playback starts on its next downbeat
+    sync_start_pending: bool,
+}
+
+// Clock events that happened since they were last taken
+#[derive(Debug, Default, Copy, Clone)]
+struct PendingClock {
+    start: bool,
+    stop: bool,
+    pulse: bool,
+}
+
+impl ClickScheduler {
+    pub fn new(state: EngineState, sample_rate: f32) -> ClickScheduler {
+        ClickScheduler {
+            state,
+            samples: SampleBank::default(),
+            sample_rate: sample_rate as f64,
+            frames_to_next_tick: 0.,
+            frames_left_in_click: 0,
+            beat_in_bar: 0,
+            tick_in_beat: 0,
+            click: None,
+            cue_frame: None,
+            cue_noise: NoiseState::default(),
+            section_starts: false,
+            frames_to_next_pulse: 0.,
+            clock: PendingClock::default(),
+            beat: None,
+            tempo_map: None,
+            next_change: 0,
+            next_bar: 1,
+            position: None,
+            sync_start_pending: false,
+        }
+    }
+
+    pub fn state(&self) -> &EngineState {
+        &self.state
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    // The sample has to be resampled to the rate of the scheduler already
+    pub fn set_sample(&mut self, class: BeatClass, sample: Option<Arc<ClickSample>>) {
+        self.samples.set(class, sample);
+    }
+
+    // Receives the bar that is playing, and the tempo and meter
+    pub fn set_position(&mut self, position: Option<Arc<Position>>) {
+        self.position = position;
+        self.publish();
+    }
+
+    fn publish(&self) {
+        if let Some(position) = &self.position {
+            position.set_engine(self.state.bpm, self.state.time_signature, self.state.playing);
+        }
+    }
+
+    // Starts a new song, its first bar is the next downbeat. Returns the previous map.
+    pub fn set_tempo_map(&mut self, map: Option<Arc<TempoMap>>) -> Option<Arc<TempoMap>> {
+        self.resume_tempo_map(map, 1)
+    }
+
+    // Continues a song on `next_bar`. The changes before it are already in the tempo and meter.
+    pub fn resume_tempo_map(&mut self, map: Option<Arc<TempoMap>>, next_bar: u32) -> Option<Arc<TempoMap>> {
+        let previous = std::mem::replace(&mut self.tempo_map, map);
+        self.next_bar = next_bar.max(1);
+        self.next_change = match &self.tempo_map {
+            Some(map) => map.changes().iter().take_while(|c| c.bar < self.next_bar).count(),
+            None => 0,
+        };
+        if let Some(position) = &self.position {
+            position.set(self.next_bar - 1, 0);
+        }
+        previous
+    }
+
+    pub fn voice(&self, class: BeatClass) -> Voice {
+        match self.samples.get(class) {
+            Some(sample) => Voice::Sample(sample),
+            None => Voice::Synth(self.state.voices.get(class)),
+        }
+    }
+
+    // How far the current beat has progressed, from 0 up to 1
+    pub fn beat_phase(&self) -> f64 {
+        if !self.state.playing {
+            return 0.;
+        }
+        let divisions = self.state.subdivision.divisions() as f64;
+        let next_tick = if self.tick_in_beat == 0 { divisions } else { self.tick_in_beat as f64 };
+        let ticks = next_tick - self.frames_to_next_tick.max(0.) / self.frames_per_tick();
+        (ticks / divisions).clamp(0., 1.)
+    }
+
+    pub fn volume(&self) -> u16 {
+        self.state.volume
+    }
+
+    pub fn click_volume(&self, click: Option<BeatClass>) -> u16 {
+        match click {
+            Some(BeatClass::Subdivision) => {
+                (self.state.volume as u32 * self.state.subdivision_volume as u32 / 1000) as u16
+            }
+            _ => self.state.volume,
+        }
+    }
+
+    pub fn apply(&mut self, msg: AudioMessage) {
+        let was_playing = self.state.playing;
+        let old_bpm = self.state.bpm;
+        let old_divisions = self.state.subdivision.divisions();
+        let old_frames_per_pulse = self.frames_per_pulse();
+
+        self.state.apply(msg);
+
+        if self.state.playing && !was_playing {
+            self.frames_to_next_tick = 0.;
+            self.frames_to_next_pulse = 0.;
+            self.beat_in_bar = 0;
+            self.tick_in_beat = 0;
+            self.clock.start = true;
+        }
+        if was_playing && !self.state.playing {
+            self.clock.start = false;
+            self.clock.stop = true;
+        }
+        // Tempo and denominator both change the pulse length, the phase inside the pulse stays
+        self.frames_to_next_pulse *= self.frames_per_pulse() / old_frames_per_pulse;
+        if self.beat_in_bar >= self.state.time_signature.numerator() {
+            self.beat_in_bar = 0;
+        }
+        if !self.state.playing {
+            self.frames_left_in_click = 0;
+            self.cue_frame = None;
+        }
+        if self.state.bpm != old_bpm {
+            // Keep the phase inside the current tick
+            self.frames_to_next_tick *= old_bpm.as_f64() / self.state.bpm.as_f64();
+        }
+        if self.state.subdivision.divisions() != old_divisions && self.tick_in_beat != 0 {
+            // Skip the rest of the old subdivisions and continue on the next beat
+            let ticks_left = (old_divisions - self.tick_in_beat) as f64;
+            self.frames_to_next_tick += ticks_left * self.frames_per_beat() / old_divisions as f64;
+            self.tick_in_beat = 0;
+            self.beat_in_bar = (self.beat_in_bar + 1) % self.state.time_signature.numerator();
+        }
+        self.publish();
+    }
+
+    // Follows a clock master. `late` is how many frames before the next frame the event happened.
+    pub fn on_sync(&mut self, event: SyncEvent, late: f64) {
+        match event {
+            SyncEvent::Start | SyncEvent::Continue => self.sync_start_pending = true,
+            SyncEvent::Stop => {
+                self.sync_start_pending = false;
+                self.apply(AudioMessage::Pause);
+            }
+            SyncEvent::Tempo(quarters) => {
+                // The engine counts denominator notes, the clock counts quarters
+                let denominator = self.state.time_signature.denominator() as f64;
+                let bpm = Bpm::saturating(quarters * denominator / 4.);
+                if bpm != self.state.bpm {
+                    self.apply(AudioMessage::SetBpm(bpm));
+                }
+            }
+            SyncEvent::Sixteenth(count) => self.on_sync_sixteenth(count, late),
+            SyncEvent::Lost => {}
+        }
+    }
+
+    fn on_sync_sixteenth(&mut self, count: u32, late: f64) {
+        let signature = self.state.time_signature;
+        let sixteenths = signature.numerator() as u32 * 16;
+        // Bars that are no whole number of sixteenths cannot be found in the clock
+        if sixteenths % signature.denominator() as u32 != 0 {
+            return;
+        }
+        let in_bar = count % (sixteenths / signature.denominator() as u32);
+        if self.sync_start_pending && in_bar == 0 {
+            self.sync_start_pending = false;
+            self.apply(AudioMessage::Play);
+        }
+        if self.state.playing {
+            let frames_per_sixteenth = self.frames_per_beat() * signature.denominator() as f64 / 16.;
+            self.follow(in_bar as f64 * frames_per_sixteenth + late);
+        }
+    }
+
+    // Pulls playback towards a master that is this many frames into the bar on the next frame.
+    // Small differences are corrected a bit on every call, large ones by jumping there.
+    pub fn follow(&mut self, master: f64) {
+        let bar = self.frames_per_bar();
+        let engine = bar - self.frames_to_downbeat();
+        // The master is either a bit ahead or a bit behind, never most of a bar
+        let ahead = (engine - master + bar / 2.).rem_euclid(bar) - bar / 2.;
+        if ahead.abs() > RELOCATE_SECONDS * self.sample_rate {
+            self.locate(master.rem_euclid(bar));
+        } else {
+            self.frames_to_next_tick += ahead * PHASE_GAIN;
+            self.frames_to_next_pulse += ahead * PHASE_GAIN;
+        }
+    }
+
+    // Moves playback to this many frames into the bar, counted on the next frame
+    fn locate(&mut self, into_bar: f64) {
+        let divisions = self.state.subdivision.divisions() as u32;
+        let ticks = (into_bar / self.frames_per_tick()).ceil();
+        self.frames_to_next_tick = ticks * self.frames_per_tick() - into_bar;
+        let tick = ticks as u32 % (self.state.time_signature.numerator() as u32 * divisions);
+        self.beat_in_bar = (tick / divisions) as u8;
+        self.tick_in_beat = (tick % divisions) as u8;
+        let pulses = (into_bar / self.frames_per_pulse()).ceil();
+        self.frames_to_next_pulse = pulses * self.frames_per_pulse() - into_bar;
+    }
+
+    // From the next frame on
+    fn frames_to_downbeat(&self) -> f64 {
+        let divisions = self.state.subdivision.divisions() as f64;
+        let bar_ticks = self.state.time_signature.numerator() as f64 * divisions;
+        let next_tick = self.beat_in_bar as f64 * divisions + self.tick_in_beat as f64;
+        self.frames_to_next_tick + (bar_ticks - next_tick) % bar_ticks * self.frames_per_tick()
+    }
+
+    fn frames_per_bar(&self) -> f64 {
+        self.state.time_signature.numerator() as f64 * self.frames_per_beat()
+    }
+
+    fn frames_per_beat(&self) -> f64 {
+        self.sample_rate * 60. / self.state.bpm.as_f64()
+    }
+
+    fn frames_per_tick(&self) -> f64 {
+        self.frames_per_beat() / self.state.subdivision.divisions() as f64
+    }
+
+    fn frames_per_pulse(&self) -> f64 {
+        let pulses_per_beat = (4 * PULSES_PER_QUARTER) as f64 / self.state.time_signature.denominator() as f64;
+        self.frames_per_beat() / pulses_per_beat
+    }
+
+    // Applies the tempo map changes of the bar that starts on this frame, so they land exactly
+    // on the downbeat. The new tempo is in effect before the length of the first tick is known.
+    fn on_downbeat(&mut self) {
+        let bar = self.next_bar;
+        self.next_bar = self.next_bar.saturating_add(1);
+
+        let old_frames_per_pulse = self.frames_per_pulse();
+        self.section_starts = false;
+        if let Some(map) = &self.tempo_map {
+            while let Some(change) = map.changes().get(self.next_change).filter(|c| c.bar <= bar) {
+                if change.bar == bar && change.section.is_some() {
+                    self.section_starts = true;
+                }
+                if let Some(bpm) = change.bpm {
+                    self.state.bpm = bpm;
+                }
+                if let Some(meter) = change.meter {
+                    self.state.time_signature = meter;
+                }
+                self.next_change += 1;
+            }
+        }
+        self.frames_to_next_pulse *= self.frames_per_pulse() / old_frames_per_pulse;
+        self.publish();
+    }
+
+    fn next_tick(&mut self) -> Option<BeatClass> {
+        let click = if self.tick_in_beat == 0 {
+            Some(self.state.time_signature.beat_class(self.beat_in_bar))
+        } else if self.state.subdivision.is_muted(self.tick_in_beat) {
+            None
+        } else {
+            Some(BeatClass::Subdivision)
+        };
+
+        self.tick_in_beat += 1;
+        if self.tick_in_beat >= self.state.subdivision.divisions() {
+            self.tick_in_beat = 0;
+            self.beat_in_bar = (self.beat_in_bar + 1) % self.state.time_signature.numerator();
+        }
+
+        click
+    }
+
+    // Advances the scheduler by one frame and returns the click sounding on it, if any
+    pub fn next_frame(&mut self, o: &mut SampleRequestOptions) -> Option<BeatClass> {
+        if !self.state.playing {
+            return None;
+        }
+
+        if self.frames_to_next_pulse <= 0. {
+            self.frames_to_next_pulse += self.frames_per_pulse();
+            self.clock.pulse = true;
+        }
+        self.frames_to_next_pulse -= 1.;
+
+        let cue_frames = (self.state.voices.cue.length() as f64 * self.sample_rate) as u64;
+        self.cue_frame = self.cue_frame.map(|frame| frame + 1).filter(|frame| *frame < cue_frames);
+
+        if self.frames_to_next_tick <= 0. {
+            if self.tick_in_beat == 0 {
+                if self.beat_in_bar == 0 {
+                    self.on_downbeat();
+                }
+                if let Some(position) = &self.position {
+                    position.set(self.next_bar - 1, self.beat_in_bar as u32 + 1);
+                }
+            }
+            self.frames_to_next_tick += self.frames_per_tick();
+            let (beat, tick) = (self.beat_in_bar, self.tick_in_beat);
+            if let Some(class) = self.next_tick() {
+                self.frames_left_in_click = self.voice(class).frames(self.sample_rate);
+                self.click = Some(class);
+                if beat == 0 && tick == 0 && self.section_starts {
+                    self.cue_frame = Some(0);
+                    self.cue_noise.reset();
+                }
+                self.beat = Some(BeatEvent { bar: self.next_bar - 1, beat: beat as u32 + 1, tick, class, bpm: self.state.bpm });
+                o.reset_clock();
+            }
+        }
+        self.frames_to_next_tick -= 1.;
+
+        if self.frames_left_in_click > 0 {
+            self.frames_left_in_click -= 1;
+            self.click
+        } else {
+            None
+        }
+    }
+
+    // The section cue on the last frame, at the playback volume
+    pub fn cue_sample(&mut self) -> Option<f32> {
+        let frame = self.cue_frame?;
+        let t = (frame as f64 / self.sample_rate) as f32;
+        let value = self.state.voices.cue.sample(t, self.sample_rate as f32, &mut self.cue_noise);
+        Some(value * self.state.volume as f32 / 1000.)
+    }
+
+    // The click that started on the last frame, once
+    pub fn take_beat(&mut self) -> Option<BeatEvent> {
+        self.beat.take()
+    }
+
+    // Hands out the MIDI clock events of the last frame, in the order they have to be sent
+    pub fn take_clock_events<F: FnMut(ClockEvent)>(&mut self, mut emit: F) {
+        let clock = std::mem::take(&mut self.clock);
+        if clock.stop {
+            emit(ClockEvent::Stop);
+        }
+        if clock.start {
+            emit(ClockEvent::Start { pulses_per_bar: pulses_per_bar(&self.state.time_signature) });
+        }
+        if clock.pulse {
+            emit(ClockEvent::Pulse);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::routing::{ChannelRouting, Routing};
+
+    fn clicks(scheduler: &mut ClickScheduler, frames: usize) -> Vec<(usize, BeatClass)> {
+        let mut o = SampleRequestOptions {
+            sample_rate: scheduler.sample_rate as f32,
+            sample_clock: 0.,
+            nchannels: 1,
+            noise: NoiseState::default(),
+        };
+        let mut ret = Vec::new();
+        for i in 0..frames {
+            // Onsets reset the clock
+            o.sample_clock = 1.;
+            let click = scheduler.next_frame(&mut o);
+            if let (Some(class), 0.) = (click, o.sample_clock) {
+                ret.push((i, class));
+            }
+        }
+        ret
+    }
+
+    fn clock_events(scheduler: &mut ClickScheduler, frames: usize) -> Vec<(usize, ClockEvent)> {
+        let mut o = SampleRequestOptions {
+            sample_rate: scheduler.sample_rate as f32,
+            sample_clock: 0.,
+            nchannels: 1,
+            noise: NoiseState::default(),
+        };
+        let mut ret = Vec::new();
+        for i in 0..frames {
+            scheduler.next_frame(&mut o);
+            scheduler.take_clock_events(|event| ret.push((i, event)));
+        }
+        ret
+    }
+
+    fn onsets(scheduler: &mut ClickScheduler, frames: usize) -> Vec<usize> {
+        clicks(scheduler, frames).into_iter().map(|(i, _)| i).collect()
+    }
+
+    #[test]
+    fn clicks_land_on_exact_frames() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 48000.);
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(120).unwrap()));
+        scheduler.apply(AudioMessage::Play);
+        assert_eq!(onsets(&mut scheduler, 96001), vec![0, 24000, 48000, 72000, 96000]);
+    }
+
+    #[test]
+    fn fractional_beats_do_not_drift() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 44100.);
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(47).unwrap()));
+        scheduler.apply(AudioMessage::Play);
+        let frames_per_beat = 44100. * 60. / 47.;
+        for (n, onset) in onsets(&mut scheduler, 44100 * 60).into_iter().enumerate() {
+            assert_eq!(onset, (n as f64 * frames_per_beat).ceil() as usize);
+        }
+    }
+
+    #[test]
+    fn paused_scheduler_is_silent() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 48000.);
+        assert!(onsets(&mut scheduler, 48000).is_empty());
+        scheduler.apply(AudioMessage::Toggle);
+        scheduler.apply(AudioMessage::Toggle);
+        assert!(onsets(&mut scheduler, 48000).is_empty());
+    }
+
+    #[test]
+    fn downbeat_is_accented() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 1000.);
+        scheduler.apply(AudioMessage::SetTimeSignature("5/4 3+2".parse().unwrap()));
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(60).unwrap()));
+        scheduler.apply(AudioMessage::Play);
+
+        use BeatClass::*;
+        let classes: Vec<BeatClass> = clicks(&mut scheduler, 6000).into_iter().map(|(_, c)| c).collect();
+        assert_eq!(classes, vec![Accent, Beat, Beat, SecondaryAccent, Beat, Accent]);
+    }
+
+    #[test]
+    fn subdivisions_fall_between_beats() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 1200.);
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(60).unwrap()));
+        scheduler.apply(AudioMessage::SetSubdivision("x-xx".parse().unwrap()));
+        scheduler.apply(AudioMessage::Play);
+
+        use BeatClass::*;
+        assert_eq!(
+            clicks(&mut scheduler, 2400),
+            vec![(0, Accent), (600, Subdivision), (900, Subdivision), (1200, Beat), (1800, Subdivision), (2100, Subdivision)]
+        );
+    }
+
+    #[test]
+    fn beat_events_include_subdivisions() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 1200.);
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(60).unwrap()));
+        scheduler.apply(AudioMessage::SetTimeSignature("2/4".parse().unwrap()));
+        scheduler.apply(AudioMessage::SetSubdivision("x-xx".parse().unwrap()));
+        scheduler.apply(AudioMessage::Play);
+
+        let mut o = SampleRequestOptions {
+            sample_rate: 1200.,
+            sample_clock: 0.,
+            nchannels: 1,
+            noise: NoiseState::default(),
+        };
+        let mut events = Vec::new();
+        for i in 0..2700 {
+            scheduler.next_frame(&mut o);
+            events.extend(scheduler.take_beat().map(|event| (i, event)));
+        }
+        use BeatClass::*;
+        let bpm = Bpm::whole(60).unwrap();
+        let beat = |bar, beat, tick, class| BeatEvent { bar, beat, tick, class, bpm };
+        assert_eq!(events, vec![
+            (0, beat(1, 1, 0, Accent)),
+            (600, beat(1, 1, 2, Subdivision)),
+            (900, beat(1, 1, 3, Subdivision)),
+            (1200, beat(1, 2, 0, Beat)),
+            (1800, beat(1, 2, 2, Subdivision)),
+            (2100, beat(1, 2, 3, Subdivision)),
+            (2400, beat(2, 1, 0, Accent)),
+        ]);
+    }
+
+    #[test]
+    fn subdivision_changes_continue_on_the_next_beat() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 1200.);
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(60).unwrap()));
+        scheduler.apply(AudioMessage::SetTimeSignature("2/4".parse().unwrap()));
+        scheduler.apply(AudioMessage::SetSubdivision("x-xx".parse().unwrap()));
+        scheduler.apply(AudioMessage::Play);
+
+        let mut o = SampleRequestOptions {
+            sample_rate: 1200.,
+            sample_clock: 0.,
+            nchannels: 1,
+            noise: NoiseState::default(),
+        };
+        let mut events = Vec::new();
+        for i in 0..3700 {
+            // Between the last two sixteenths of the first beat
+            if i == 700 {
+                scheduler.apply(AudioMessage::SetSubdivision(Subdivision::new(3).unwrap()));
+            }
+            scheduler.next_frame(&mut o);
+            events.extend(scheduler.take_beat().map(|event| (i, event.bar, event.beat, event.tick)));
+        }
+        assert_eq!(events, vec![
+            (0, 1, 1, 0),
+            (600, 1, 1, 2),
+            (1200, 1, 2, 0),
+            (1600, 1, 2, 1),
+            (2000, 1, 2, 2),
+            (2400, 2, 1, 0),
+            (2800, 2, 1, 1),
+            (3200, 2, 1, 2),
+            (3600, 2, 2, 0),
+        ]);
+    }
+
+    #[test]
+    fn triplets_at_fractional_positions() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 1000.);
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(60).unwrap()));
+        scheduler.apply(AudioMessage::SetSubdivision(Subdivision::new(3).unwrap()));
+        scheduler.apply(AudioMessage::Play);
+        assert_eq!(onsets(&mut scheduler, 2001), vec![0, 334, 667, 1000, 1334, 1667, 2000]);
+    }
+
+    #[test]
+    fn clock_pulses_follow_the_beat() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 48000.);
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(120).unwrap()));
+        scheduler.apply(AudioMessage::Play);
+
+        let events = clock_events(&mut scheduler, 24000);
+        assert_eq!(events[0], (0, ClockEvent::Start { pulses_per_bar: 96 }));
+        let pulses: Vec<usize> = events.iter().filter(|(_, e)| *e == ClockEvent::Pulse).map(|(i, _)| *i).collect();
+        assert_eq!(pulses, (0..24).map(|n| n * 1000).collect::<Vec<_>>());
+
+        scheduler.apply(AudioMessage::Pause);
+        assert_eq!(clock_events(&mut scheduler, 2000), vec![(0, ClockEvent::Stop)]);
+    }
+
+    #[test]
+    fn clock_counts_quarters_in_eighth_meters() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 48000.);
+        scheduler.apply(AudioMessage::SetTimeSignature("6/8".parse().unwrap()));
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(120).unwrap()));
+        scheduler.apply(AudioMessage::Play);
+
+        let events = clock_events(&mut scheduler, 24001);
+        assert_eq!(events[0], (0, ClockEvent::Start { pulses_per_bar: 72 }));
+        // An eighth at 120 BPM is 24000 frames and takes 12 pulses
+        let pulses: Vec<usize> = events.iter().filter(|(_, e)| *e == ClockEvent::Pulse).map(|(i, _)| *i).collect();
+        assert_eq!(pulses, (0..=12).map(|n| n * 2000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn beat_phase_counts_through_the_beat() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 1000.);
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(60).unwrap()));
+        assert_eq!(scheduler.beat_phase(), 0.);
+        scheduler.apply(AudioMessage::SetSubdivision(Subdivision::new(2).unwrap()));
+        scheduler.apply(AudioMessage::Play);
+        onsets(&mut scheduler, 250);
+        assert_eq!(scheduler.beat_phase(), 0.25);
+        onsets(&mut scheduler, 500);
+        assert_eq!(scheduler.beat_phase(), 0.75);
+        // The next beat starts on the next frame
+        onsets(&mut scheduler, 250);
+        assert_eq!(scheduler.beat_phase(), 1.);
+    }
+
+    #[test]
+    fn following_jumps_to_a_distant_master() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 1000.);
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(60).unwrap()));
+        scheduler.apply(AudioMessage::Play);
+        onsets(&mut scheduler, 2500);
+
+        // The master is on the downbeat
+        scheduler.follow(0.);
+        use BeatClass::*;
+        assert_eq!(clicks(&mut scheduler, 1001), vec![(0, Accent), (1000, Beat)]);
+        // The master is a quarter into the third beat
+        scheduler.follow(2250.);
+        assert_eq!(clicks(&mut scheduler, 1751), vec![(750, Beat), (1750, Accent)]);
+    }
+
+    #[test]
+    fn following_corrects_small_differences_gradually() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 1000.);
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(60).unwrap()));
+        scheduler.apply(AudioMessage::Play);
+        onsets(&mut scheduler, 10);
+        // The master is 20 frames further
+        let mut master = 30.;
+        scheduler.follow(master);
+        assert_eq!(onsets(&mut scheduler, 1000), vec![988]);
+        master += 1000.;
+
+        let mut beats = Vec::new();
+        for n in 0..80 {
+            beats.extend(onsets(&mut scheduler, 100).into_iter().map(|onset| onset + n * 100));
+            master += 100.;
+            scheduler.follow(master % 4000.);
+        }
+        for pair in beats.windows(2) {
+            assert!((990..=1000).contains(&(pair[1] - pair[0])), "{:?}", beats);
+        }
+        // In step with the master, whose next beat is 970 frames away
+        assert!((scheduler.frames_to_next_tick - 970.).abs() < 0.01);
+    }
+
+    #[test]
+    fn follows_a_midi_clock() {
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 48000.);
+        scheduler.apply(AudioMessage::SetBpm(Bpm::whole(100).unwrap()));
+        // 123.456 BPM would have been rounded to a tenth before
+        let interval = 48000. * 60. / 123.456 / 4.;
+        scheduler.on_sync(SyncEvent::Tempo(123.456), 0.);
+        scheduler.on_sync(SyncEvent::Start, 0.);
+        assert!(!scheduler.state().playing);
+
+        // Sixteenths reach the first callback at least 30 frames later, callbacks take 256 frames
+        let mut beats = Vec::new();
+        let mut frame = 0usize;
+        for count in 0..16 * 8 {
+            let at = (count as f64 * interval).round() as usize;
+            while frame < at + 30 {
+                beats.extend(onsets(&mut scheduler, 256).into_iter().map(|onset| onset + frame));
+                frame += 256;
+            }
+            scheduler.on_sync(SyncEvent::Sixteenth(count), (frame - at) as f64);
+        }
+        assert_eq!(scheduler.state().bpm.to_string(), "123.456");
+        // Starts on the first callback after the downbeat, then catches up by less than 2 ms a beat
+        assert_eq!(beats[0], 256);
+        for (n, pair) in beats.windows(2).enumerate() {
+            let length = (pair[1] - pair[0]) as f64;
+            assert!((length - 4. * interval).abs() < 96., "beat {} is {} frames", n, length);
+        }
+        let last = *beats.last().unwrap() as f64;
+        let expected = ((last / (4. * interval)).round() * 4. * interval).ceil();
+        assert!((last - expected).abs() <= 1., "{} instead of {}", last, expected);
+    }
+
+    #[test]
+    fn follows_the_tempo_map_on_downbeats() {
+        let map = TempoMap::parse("- bar: 1\n  bpm: 60\n- bar: 3\n  bpm: 120\n  meter: 3/4\n").unwrap();
+        let position = Arc::new(Position::default());
+        let mut scheduler = ClickScheduler::new(EngineState::default(), 1000.);
+        scheduler.set_position(Some(position.clone()));
+        scheduler.set_tempo_map(Some(Arc::new(map)));
+        scheduler.apply(AudioMessage::Play);
+
+        use BeatClass::*;
+        let clicks = clicks(&mut scheduler, 10001);
+        assert_eq!(&clicks[..2], &[(0, Accent), (1000, Beat)]);
+        // Two bars of 4/4 at 60 BPM, then 3/4 at 120 BPM
+        assert_eq!(&clicks[8..], &[(8000, Accent), (8500, Beat), (9000, Beat), (9500, Accent), (10000, Beat)]);
+        assert_eq!(position.get(), (4, 2));
+        let (bpm, meter, playing) = position.engine().unwrap();
+        assert_eq!((bpm.to_string(), meter.to_string(), playing), ("120".to_string(), "3/4".to_string(), true));
+
+        // A pause continues on the next bar
+        scheduler.apply(AudioMessage::Pause);
+        scheduler.apply(AudioMessage::Play);
+        onsets(&mut scheduler, 1);
+        assert_eq!(position.get(), (5, 1));
+    }
+
+    #[test]
+    fn resumes_the_tempo_map_on_a_later_bar() {
+        let map = Arc::new(TempoMap::parse("- bar: 1\n  bpm: 60\n- bar: 3\n  bpm: 120\n  meter: 3/4\n- bar: 5\n  bpm: 60\n").unwrap());
+        // A rebuilt stream gets the tempo and meter the previous one played
+        let mut state = EngineState::default();
+        state.bpm = Bpm::whole(120).unwrap();
+        state.time_signature = "3/4".parse().unwrap();
+        let position = Arc::new(Position::default());
+        let mut scheduler = ClickScheduler::new(state, 1000.);
+        scheduler.set_position(Some(position.clone()));
+        assert!(scheduler.resume_tempo_map(Some(map.clone()), 4).is_none());
+        assert_eq!(position.get(), (3, 0));
+        scheduler.apply(AudioMessage::Play);
+
+        use BeatClass::*;
+        let clicks = clicks(&mut scheduler, 2501);
+        // Bar 4 in 3/4 at 120 BPM, bar 5 at 60 BPM
+        assert_eq!(&clicks[..5], &[(0, Accent), (500, Beat), (1000, Beat), (1500, Accent), (2500, Beat)]);
+        assert_eq!(position.get(), (5, 2));
+
+        let previous = scheduler.set_tempo_map(None).unwrap();
+        assert!(Arc::ptr_eq(&previous, &map));
+        assert_eq!(position.get(), (0, 0));
+    }
+
+    #[test]
+    fn section_downbeats_are_cues() {
+        let map = TempoMap::parse("- bar: 1\n  bpm: 60\n  section: Intro\n- bar: 2\n  section: Verse\n- bar: 3\n  bpm: 120\n").unwrap();
+        let mut scheduler = ClickScheduler::new(EngineState { volume: 1000, ..Default::default() }, 1000.);
+        scheduler.set_tempo_map(Some(Arc::new(map)));
+        scheduler.apply(AudioMessage::Play);
+
+        let routing = ChannelRouting { click: vec![1], cue: vec![2], ..Default::default() };
+        let routing = Routing::new(&routing, 2).unwrap();
+        let mut o = SampleRequestOptions {
+            sample_rate: 1000.,
+            sample_clock: 0.,
+            nchannels: 2,
+            noise: NoiseState::default(),
+        };
+        let mut output = vec![0f32; 2 * 10000];
+        crate::audio::on_window(&mut output, &mut o, &mut scheduler, &routing, None, None, crate::audio::sample_next);
+
+        // The cue only sounds on its own channel, from the downbeat of each section. Voices
+        // start silent, so the first frame that is heard is the one after it.
+        let cue_frames = (Voices::default().cue.length() * 1000.) as usize;
+        let sounding: Vec<usize> = output.chunks(2).enumerate().filter(|(_, f)| f[1] != 0.).map(|(i, _)| i).collect();
+        let starts: Vec<usize> = sounding.iter().copied().filter(|i| *i == 0 || !sounding.contains(&(i - 1))).collect();
+        assert_eq!(starts, vec![1, 4001]);
+        assert!(sounding.iter().all(|i| *i % 4000 < cue_frames));
+        assert!(output.chunks(2).take(cue_frames).any(|f| f[0] != f[1]));
+    }
+}
@@ -0,0 +1,335 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::meter::TimeSignature;
+
+const MILLIS_PER_BPM: u32 = 1000;
+const MIN_MILLIS: u32 = MILLIS_PER_BPM;
+const MAX_MILLIS: u32 = 1000 * MILLIS_PER_BPM;
+
+// How often the position watcher looks for a new bar
+const WATCH_INTERVAL: Duration = Duration::from_millis(30);
+
+// A tempo in thousandths of a beat per minute, between 1 and 1000 BPM.
+// Fixed point, so 92.5 BPM stays exactly 92.5 through the setlist and the engine.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bpm(u32);
+
+impl Default for Bpm {
+    fn default() -> Self {
+        Bpm(120 * MILLIS_PER_BPM)
+    }
+}
+
+impl Bpm {
+    pub const MIN: Bpm = Bpm(MIN_MILLIS);
+    pub const MAX: Bpm = Bpm(MAX_MILLIS);
+
+    pub fn from_millis(millis: u32) -> Result<Bpm, anyhow::Error> {
+        if !(MIN_MILLIS..=MAX_MILLIS).contains(&millis) {
+            return Err(anyhow::anyhow!(
+                "BPM must be between {} and {} (got {})",
+                Bpm::MIN, Bpm::MAX, millis as f64 / MILLIS_PER_BPM as f64
+            ));
+        }
+        Ok(Bpm(millis))
+    }
+
+    pub fn whole(bpm: u16) -> Result<Bpm, anyhow::Error> {
+        Bpm::from_millis(bpm as u32 * MILLIS_PER_BPM)
+    }
+
+    // Rounds to the nearest thousandth
+    pub fn from_f64(bpm: f64) -> Result<Bpm, anyhow::Error> {
+        if !bpm.is_finite() || bpm <= 0. {
+            return Err(anyhow::anyhow!("Invalid BPM {}", bpm));
+        }
+        Bpm::from_millis((bpm * MILLIS_PER_BPM as f64).round().min(u32::MAX as f64) as u32)
+    }
+
+    // Like `from_f64`, but clamps to the valid range instead of failing
+    pub fn saturating(bpm: f64) -> Bpm {
+        if bpm.is_nan() {
+            return Bpm::default();
+        }
+        let millis = (bpm * MILLIS_PER_BPM as f64).round().clamp(MIN_MILLIS as f64, MAX_MILLIS as f64);
+        Bpm(millis as u32)
+    }
+
+    pub fn millis(&self) -> u32 {
+        self.0
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.0 as f64 / MILLIS_PER_BPM as f64
+    }
+}
+
+// Formats without trailing zeros, "120", "92.5" or "127.125"
+impl Display for Bpm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let whole = self.0 / MILLIS_PER_BPM;
+        let fraction = self.0 % MILLIS_PER_BPM;
+        if fraction == 0 {
+            return write!(f, "{}", whole);
+        }
+        let digits = format!("{:03}", fraction);
+        write!(f, "{}.{}", whole, digits.trim_end_matches('0'))
+    }
+}
+
+// Parses decimals exactly, without going through a float
+impl FromStr for Bpm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || anyhow::anyhow!("Invalid BPM '{}'", s);
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+        if whole.is_empty() || !whole.chars().all(|c| c.is_ascii_digit()) || !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        if fraction.len() > 3 {
+            return Err(anyhow::anyhow!("BPM '{}' has more than three decimals", s));
+        }
+
+        let whole: u32 = whole.parse().map_err(|_| invalid())?;
+        let fraction: u32 = format!("{:0<3}", fraction).parse().map_err(|_| invalid())?;
+        let millis = whole.checked_mul(MILLIS_PER_BPM).and_then(|m| m.checked_add(fraction)).ok_or_else(invalid)?;
+        Bpm::from_millis(millis)
+    }
+}
+
+// Whole tempos are written as integers, so existing setlists do not change when saved again
+impl Serialize for Bpm {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.0 % MILLIS_PER_BPM == 0 {
+            serializer.serialize_u32(self.0 / MILLIS_PER_BPM)
+        } else {
+            serializer.serialize_f64(self.as_f64())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Bpm {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bpm = f64::deserialize(deserializer)?;
+        Bpm::from_f64(bpm).map_err(serde::de::Error::custom)
+    }
+}
+
+// Tempo and meter from the given bar on. Bars count from 1 like in a score.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TempoChange {
+    pub bar: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bpm: Option<Bpm>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meter: Option<TimeSignature>,
+    // Name of the part starting here, like "Verse" or "Chorus"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub section: Option<String>,
+}
+
+// The changes of a song, sorted by bar. The first one is on bar 1 and has a tempo.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct TempoMap {
+    changes: Vec<TempoChange>,
+}
+
+impl TempoMap {
+    pub fn new(changes: Vec<TempoChange>) -> Result<TempoMap, anyhow::Error> {
+        let first = changes.first().ok_or_else(|| anyhow::Error::msg("Tempo map is empty"))?;
+        if first.bar != 1 {
+            return Err(anyhow::anyhow!("Tempo map must start on bar 1 (starts on bar {})", first.bar));
+        }
+        if first.bpm.is_none() {
+            return Err(anyhow::Error::msg("Tempo map needs a tempo on bar 1"));
+        }
+        for pair in changes.windows(2) {
+            if pair[1].bar <= pair[0].bar {
+                return Err(anyhow::anyhow!("Bar {} comes after bar {} in the tempo map", pair[1].bar, pair[0].bar));
+            }
+        }
+        Ok(TempoMap { changes })
+    }
+
+    pub fn parse(yaml: &str) -> Result<TempoMap, anyhow::Error> {
+        TempoMap::new(serde_yaml::from_str(yaml)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TempoMap, anyhow::Error> {
+        let path = path.as_ref();
+        let yaml = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        TempoMap::parse(&yaml).with_context(|| format!("Malformed tempo map {}", path.display()))
+    }
+
+    pub fn changes(&self) -> &[TempoChange] {
+        &self.changes
+    }
+
+    // Tempo and meter at the start of the song
+    pub fn start(&self) -> (Bpm, TimeSignature) {
+        self.at(1)
+    }
+
+    // Tempo and meter in effect on the given bar
+    pub fn at(&self, bar: u32) -> (Bpm, TimeSignature) {
+        let mut bpm = Bpm::default();
+        let mut meter = TimeSignature::default();
+        for change in self.changes.iter().take_while(|c| c.bar <= bar.max(1)) {
+            bpm = change.bpm.unwrap_or(bpm);
+            meter = change.meter.unwrap_or(meter);
+        }
+        (bpm, meter)
+    }
+
+    pub fn section(&self, bar: u32) -> Option<&str> {
+        self.changes
+            .iter()
+            .take_while(|c| c.bar <= bar.max(1))
+            .filter_map(|c| c.section.as_deref())
+            .last()
+    }
+}
+
+// The bar and beat the engine is playing, and how it plays them, written by the audio thread.
+// Bar and beat count from 1 and are 0 before the song started.
+#[derive(Debug, Default)]
+pub struct Position {
+    // Bar in the upper and beat in the lower half, so they are always read together
+    packed: AtomicU64,
+    // Thousandths of a BPM, 0 until an engine ran
+    bpm: AtomicU32,
+    meter: AtomicU64,
+    playing: AtomicBool,
+}
+
+impl Position {
+    pub fn get(&self) -> (u32, u32) {
+        let packed = self.packed.load(Ordering::Relaxed);
+        ((packed >> 32) as u32, packed as u32)
+    }
+
+    pub fn bar(&self) -> u32 {
+        self.get().0
+    }
+
+    pub fn set(&self, bar: u32, beat: u32) {
+        self.packed.store((bar as u64) << 32 | beat as u64, Ordering::Relaxed);
+    }
+
+    // Tempo, meter and play state of the engine. Tempo maps and clock masters change them
+    // without the control thread knowing.
+    pub fn engine(&self) -> Option<(Bpm, TimeSignature, bool)> {
+        // Pairs with the release in `set_engine`, once the tempo is there the meter is as well
+        let bpm = Bpm::from_millis(self.bpm.load(Ordering::Acquire)).ok()?;
+        let meter = TimeSignature::from_bits(self.meter.load(Ordering::Relaxed));
+        Some((bpm, meter, self.playing.load(Ordering::Relaxed)))
+    }
+
+    pub fn set_engine(&self, bpm: Bpm, meter: TimeSignature, playing: bool) {
+        self.meter.store(meter.to_bits(), Ordering::Relaxed);
+        self.playing.store(playing, Ordering::Relaxed);
+        self.bpm.store(bpm.millis(), Ordering::Release);
+    }
+}
+
+// Calls `on_bar` from a background thread whenever the bar changes, until the position is dropped
+pub fn watch_position<F>(position: &Arc<Position>, mut on_bar: F) -> Result<(), anyhow::Error>
+    where F: FnMut(u32) + Send + 'static
+{
+    watch(position, "position watcher", WATCH_INTERVAL, |p| p.bar(), move |bar| on_bar(bar))
+}
+
+fn watch<T, G, F>(position: &Arc<Position>, name: &str, interval: Duration, get: G, mut on_change: F) -> Result<(), anyhow::Error>
+    where T: Copy + PartialEq + Send + 'static,
+          G: Fn(&Position) -> T + Send + 'static,
+          F: FnMut(T) + Send + 'static
+{
+    let position: Weak<Position> = Arc::downgrade(position);
+    std::thread::Builder::new()
+        .name(String::from(name))
+        .spawn(move || {
+            let mut last = position.upgrade().map(|p| get(&p));
+            while let Some(value) = position.upgrade().map(|p| get(&p)) {
+                if last != Some(value) {
+                    last = Some(value);
+                    on_change(value);
+                }
+                std::thread::sleep(interval);
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats_decimals() {
+        assert_eq!("92.5".parse::<Bpm>().unwrap().millis(), 92_500);
+        assert_eq!("127.3".parse::<Bpm>().unwrap().to_string(), "127.3");
+        assert_eq!("120".parse::<Bpm>().unwrap().to_string(), "120");
+        assert_eq!("60.125".parse::<Bpm>().unwrap().to_string(), "60.125");
+        assert!("0".parse::<Bpm>().is_err());
+        assert!("1000.5".parse::<Bpm>().is_err());
+        assert!("92.5555".parse::<Bpm>().is_err());
+        assert!(".5".parse::<Bpm>().is_err());
+        assert!("-3".parse::<Bpm>().is_err());
+    }
+
+    #[test]
+    fn serializes_whole_tempos_as_integers() {
+        assert_eq!(serde_yaml::from_str::<Bpm>("120").unwrap(), Bpm::whole(120).unwrap());
+        assert_eq!(serde_yaml::from_str::<Bpm>("127.3").unwrap().millis(), 127_300);
+        assert!(serde_yaml::from_str::<Bpm>("0").is_err());
+
+        let yaml = serde_yaml::to_string(&Bpm::whole(120).unwrap()).unwrap();
+        assert!(!yaml.contains('.'));
+        let yaml = serde_yaml::to_string(&"92.5".parse::<Bpm>().unwrap()).unwrap();
+        assert_eq!(serde_yaml::from_str::<Bpm>(&yaml).unwrap().millis(), 92_500);
+    }
+
+    #[test]
+    fn saturates_into_range() {
+        assert_eq!(Bpm::saturating(0.), Bpm::MIN);
+        assert_eq!(Bpm::saturating(5000.), Bpm::MAX);
+        assert_eq!(Bpm::saturating(99.9996).millis(), 100_000);
+    }
+
+    #[test]
+    fn tempo_map_from_yaml() {
+        let map = TempoMap::parse(
+            "- bar: 1\n  bpm: 120\n  section: Intro\n- bar: 9\n  section: Verse\n- bar: 17\n  bpm: 92.5\n  meter: 7/8 3+2+2\n"
+        ).unwrap();
+        assert_eq!(map.start(), (Bpm::whole(120).unwrap(), TimeSignature::default()));
+        assert_eq!(map.section(8), Some("Intro"));
+        assert_eq!(map.section(16), Some("Verse"));
+        let (bpm, meter) = map.at(20);
+        assert_eq!(bpm.to_string(), "92.5");
+        assert_eq!(meter.to_string(), "7/8 3+2+2");
+        assert_eq!(map.section(20), Some("Verse"));
+    }
+
+    #[test]
+    fn rejects_malformed_tempo_maps() {
+        assert!(TempoMap::parse("[]").is_err());
+        assert!(TempoMap::parse("- bar: 2\n  bpm: 120\n").is_err());
+        assert!(TempoMap::parse("- bar: 1\n  meter: 3/4\n").is_err());
+        assert!(TempoMap::parse("- bar: 1\n  bpm: 120\n- bar: 5\n  bpm: 100\n- bar: 5\n  bpm: 90\n").is_err());
+        assert!(TempoMap::parse("- bar: 1\n  bpm: 120\n  meter: 4/3\n").is_err());
+        assert!(TempoMap::parse("- bar: 1\n  bpm: 120\n  tempo: 3\n").is_err());
+    }
+}
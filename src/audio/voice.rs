@@ -0,0 +1,415 @@
+use std::convert::TryFrom;
+use std::f32::consts::PI;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use super::meter::BeatClass;
+use super::sample::ClickSample;
+
+// Partials of a struck wooden bar as (frequency ratio, amplitude, decay time in seconds)
+const WOODBLOCK_MODES: [(f32, f32, f32); 4] = [
+    (1., 1., 0.030),
+    (2.76, 0.55, 0.018),
+    (5.40, 0.30, 0.010),
+    (8.93, 0.15, 0.006),
+];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Saw,
+    Noise,
+    Woodblock,
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 6] = [
+        Waveform::Square,
+        Waveform::Sine,
+        Waveform::Triangle,
+        Waveform::Saw,
+        Waveform::Noise,
+        Waveform::Woodblock,
+    ];
+}
+
+impl Display for Waveform {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Waveform::Square => "square",
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+            Waveform::Saw => "saw",
+            Waveform::Noise => "noise",
+            Waveform::Woodblock => "woodblock",
+        })
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Waveform::ALL
+            .iter()
+            .find(|w| w.to_string().eq_ignore_ascii_case(s.trim()))
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Unknown waveform '{}'", s))
+    }
+}
+
+impl TryFrom<String> for Waveform {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Waveform> for String {
+    fn from(waveform: Waveform) -> Self {
+        waveform.to_string()
+    }
+}
+
+// Linear attack followed by a quadratic decay that reaches exactly zero, so clicks never end on a step.
+// Both times are in seconds.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+}
+
+impl Envelope {
+    pub fn length(&self) -> f32 {
+        self.attack + self.decay
+    }
+
+    pub fn gain(&self, t: f32) -> f32 {
+        if t < 0. || t >= self.length() {
+            0.
+        } else if t < self.attack {
+            t / self.attack
+        } else {
+            let x = 1. - (t - self.attack) / self.decay;
+            x * x
+        }
+    }
+}
+
+// Band-passed white noise, the state is reset on every click onset
+#[derive(Debug, Copy, Clone)]
+pub struct NoiseState {
+    seed: u32,
+    low: f32,
+    band: f32,
+}
+
+impl Default for NoiseState {
+    fn default() -> Self {
+        NoiseState {
+            seed: 0x1234_5678,
+            low: 0.,
+            band: 0.,
+        }
+    }
+}
+
+impl NoiseState {
+    pub fn reset(&mut self) {
+        *self = NoiseState::default();
+    }
+
+    fn white(&mut self) -> f32 {
+        // xorshift32
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        (self.seed as f32 / u32::MAX as f32) * 2. - 1.
+    }
+
+    // Chamberlin state variable filter, returning the band pass output
+    fn filtered(&mut self, center: f32, sample_rate: f32) -> f32 {
+        let f = 2. * (PI * (center / sample_rate).min(0.25)).sin();
+        let damping = 0.5;
+        let input = self.white();
+        self.low += f * self.band;
+        let high = input - self.low - damping * self.band;
+        self.band += f * high;
+        self.band
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ClickVoice {
+    pub waveform: Waveform,
+    pub frequency: f32,
+    pub level: f32,
+    pub envelope: Envelope,
+}
+
+impl ClickVoice {
+    pub fn new(waveform: Waveform, frequency: f32, level: f32) -> ClickVoice {
+        ClickVoice {
+            waveform,
+            frequency,
+            level,
+            envelope: Envelope {
+                attack: 0.001,
+                decay: 0.04,
+            },
+        }
+    }
+
+    // Length of the click in seconds
+    pub fn length(&self) -> f32 {
+        self.envelope.length()
+    }
+
+    // `t` is the time in seconds since the onset of the click
+    pub fn sample(&self, t: f32, sample_rate: f32, noise: &mut NoiseState) -> f32 {
+        let gain = self.envelope.gain(t);
+        if gain == 0. {
+            return 0.;
+        }
+
+        let phase = (t * self.frequency).fract();
+        let value = match self.waveform {
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.
+                } else {
+                    -1.
+                }
+            }
+            Waveform::Sine => (2. * PI * phase).sin(),
+            Waveform::Triangle => 1. - 4. * (phase - 0.5).abs(),
+            Waveform::Saw => 2. * phase - 1.,
+            Waveform::Noise => noise.filtered(self.frequency, sample_rate),
+            Waveform::Woodblock => {
+                let sum: f32 = WOODBLOCK_MODES
+                    .iter()
+                    .map(|(ratio, amplitude, decay)| {
+                        amplitude * (-t / decay).exp() * (2. * PI * self.frequency * ratio * t).sin()
+                    })
+                    .sum();
+                sum / 2.
+            }
+        };
+
+        (value * gain * self.level).clamp(-1., 1.)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Voices {
+    pub accent: ClickVoice,
+    pub secondary_accent: ClickVoice,
+    pub beat: ClickVoice,
+    pub subdivision: ClickVoice,
+    // Section downbeats of a tempo map, on the cue channels only
+    pub cue: ClickVoice,
+}
+
+impl Default for Voices {
+    fn default() -> Self {
+        let mut accent = ClickVoice::new(Waveform::Sine, 1318.51, 1.);
+        accent.envelope.decay = 0.05;
+        // Longer and brighter than any click, so it is not taken for one
+        let mut cue = ClickVoice::new(Waveform::Triangle, 1567.98, 0.9);
+        cue.envelope.decay = 0.15;
+        Voices {
+            accent,
+            secondary_accent: ClickVoice::new(Waveform::Sine, 987.77, 0.8),
+            beat: ClickVoice::new(Waveform::Sine, 659.25, 0.6),
+            subdivision: ClickVoice::new(Waveform::Sine, 880., 0.5),
+            cue,
+        }
+    }
+}
+
+impl Voices {
+    pub fn get(&self, class: BeatClass) -> &ClickVoice {
+        match class {
+            BeatClass::Accent => &self.accent,
+            BeatClass::SecondaryAccent => &self.secondary_accent,
+            BeatClass::Beat => &self.beat,
+            BeatClass::Subdivision => &self.subdivision,
+        }
+    }
+
+    pub fn set(&mut self, class: BeatClass, voice: ClickVoice) {
+        match class {
+            BeatClass::Accent => self.accent = voice,
+            BeatClass::SecondaryAccent => self.secondary_accent = voice,
+            BeatClass::Beat => self.beat = voice,
+            BeatClass::Subdivision => self.subdivision = voice,
+        }
+    }
+}
+
+// A voice in the config. Unset fields keep the default sound of the beat class.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct VoiceConfig {
+    pub waveform: Option<Waveform>,
+    // Hz
+    pub frequency: Option<f32>,
+    // Between 0 and 1
+    pub level: Option<f32>,
+    // Seconds
+    pub attack: Option<f32>,
+    pub decay: Option<f32>,
+    // WAV, FLAC or Ogg Vorbis file played instead of the synthesized voice.
+    // Relative paths start in the config directory.
+    pub sample: Option<PathBuf>,
+}
+
+impl VoiceConfig {
+    pub fn apply(&self, mut voice: ClickVoice) -> ClickVoice {
+        voice.waveform = self.waveform.unwrap_or(voice.waveform);
+        voice.frequency = self.frequency.unwrap_or(voice.frequency);
+        voice.level = self.level.unwrap_or(voice.level);
+        voice.envelope.attack = self.attack.unwrap_or(voice.envelope.attack);
+        voice.envelope.decay = self.decay.unwrap_or(voice.envelope.decay);
+        voice
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if let Some(frequency) = self.frequency.filter(|f| !(20. ..=20000.).contains(f)) {
+            return Err(anyhow::anyhow!("frequency must be between 20 and 20000 Hz (got {})", frequency));
+        }
+        if let Some(level) = self.level.filter(|l| !(0. ..=1.).contains(l)) {
+            return Err(anyhow::anyhow!("level must be between 0 and 1 (got {})", level));
+        }
+        if let Some(attack) = self.attack.filter(|a| !(0.0001..=0.1).contains(a)) {
+            return Err(anyhow::anyhow!("attack must be between 0.0001 and 0.1 seconds (got {})", attack));
+        }
+        if let Some(decay) = self.decay.filter(|d| !(0.001..=1.).contains(d)) {
+            return Err(anyhow::anyhow!("decay must be between 0.001 and 1 seconds (got {})", decay));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct VoicesConfig {
+    pub accent: VoiceConfig,
+    pub secondary_accent: VoiceConfig,
+    pub beat: VoiceConfig,
+    pub subdivision: VoiceConfig,
+    // Synthesized only
+    pub cue: VoiceConfig,
+}
+
+impl VoicesConfig {
+    pub fn get(&self, class: BeatClass) -> &VoiceConfig {
+        match class {
+            BeatClass::Accent => &self.accent,
+            BeatClass::SecondaryAccent => &self.secondary_accent,
+            BeatClass::Beat => &self.beat,
+            BeatClass::Subdivision => &self.subdivision,
+        }
+    }
+
+    pub fn get_mut(&mut self, class: BeatClass) -> &mut VoiceConfig {
+        match class {
+            BeatClass::Accent => &mut self.accent,
+            BeatClass::SecondaryAccent => &mut self.secondary_accent,
+            BeatClass::Beat => &mut self.beat,
+            BeatClass::Subdivision => &mut self.subdivision,
+        }
+    }
+
+    pub fn voices(&self) -> Voices {
+        let mut voices = Voices::default();
+        for class in BeatClass::ALL {
+            voices.set(class, self.get(class).apply(*voices.get(class)));
+        }
+        voices.cue = self.cue.apply(voices.cue);
+        voices
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        self.accent.validate().context("accent")?;
+        self.secondary_accent.validate().context("secondary_accent")?;
+        self.beat.validate().context("beat")?;
+        self.subdivision.validate().context("subdivision")?;
+        if self.cue.sample.is_some() {
+            return Err(anyhow::anyhow!("cue: samples are only played for beat classes"));
+        }
+        self.cue.validate().context("cue")
+    }
+}
+
+// What actually plays a click: a synthesized voice, or a user sample if one is loaded for the beat class
+#[derive(Debug, Copy, Clone)]
+pub enum Voice<'a> {
+    Synth(&'a ClickVoice),
+    Sample(&'a ClickSample),
+}
+
+impl<'a> Voice<'a> {
+    pub fn frames(&self, sample_rate: f64) -> u64 {
+        match self {
+            Voice::Synth(voice) => (voice.length() as f64 * sample_rate).ceil() as u64,
+            Voice::Sample(sample) => sample.len() as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clicks_start_and_end_silent() {
+        let sample_rate = 48000.;
+        for waveform in Waveform::ALL {
+            let voice = ClickVoice::new(waveform, 1000., 1.);
+            let mut noise = NoiseState::default();
+            let frames = (voice.length() * sample_rate).ceil() as usize + 1;
+            let samples: Vec<f32> = (0..frames + 10)
+                .map(|i| voice.sample(i as f32 / sample_rate, sample_rate, &mut noise))
+                .collect();
+
+            assert_eq!(samples[0], 0., "{} does not start at zero", waveform);
+            assert!(samples[frames - 3].abs() < 0.01, "{} does not fade out", waveform);
+            assert!(samples[frames..].iter().all(|s| *s == 0.), "{} rings past its length", waveform);
+            assert!(samples.iter().any(|s| s.abs() > 0.1), "{} is silent", waveform);
+        }
+    }
+
+    #[test]
+    fn configured_voices_keep_unset_fields() {
+        let config: VoicesConfig = serde_yaml::from_str("accent:\n  waveform: woodblock\n  frequency: 1500\nsubdivision:\n  level: 0.2\n").unwrap();
+        let voices = config.voices();
+        assert_eq!(voices.accent.waveform, Waveform::Woodblock);
+        assert_eq!(voices.accent.frequency, 1500.);
+        assert_eq!(voices.accent.envelope, Voices::default().accent.envelope);
+        assert_eq!(voices.subdivision.level, 0.2);
+        assert_eq!(voices.beat, Voices::default().beat);
+        assert_eq!(voices.cue, Voices::default().cue);
+
+        assert!(serde_yaml::from_str::<VoicesConfig>("beat:\n  waveform: kazoo\n").is_err());
+        let loud: VoicesConfig = serde_yaml::from_str("beat:\n  level: 3\n").unwrap();
+        assert!(format!("{:#}", loud.validate().unwrap_err()).contains("beat"));
+        let sampled: VoicesConfig = serde_yaml::from_str("cue:\n  sample: cue.wav\n").unwrap();
+        assert!(sampled.validate().is_err());
+    }
+
+    #[test]
+    fn waveform_names_round_trip() {
+        for waveform in Waveform::ALL {
+            assert_eq!(waveform.to_string().parse::<Waveform>().unwrap(), waveform);
+        }
+    }
+}
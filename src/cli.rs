@@ -0,0 +1,424 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand};
+use futures_channel::mpsc::{unbounded, UnboundedReceiver};
+use log::{error, warn};
+
+use super::audio::{self, HostSelector};
+use super::audio::meter::{BeatClass, Subdivision, TimeSignature};
+use super::audio::render::{self, RenderOptions, WavFormat};
+use super::audio::sample::ClickSample;
+use super::audio::tempo::Bpm;
+use super::config::{AudioConfig, ConfigFile};
+use super::headless::Headless;
+use super::input::{self, OsInput};
+use super::song_listing::{self, BPM};
+use super::Message;
+
+#[derive(Parser, Debug)]
+#[clap(name = "metronome", version, about = "A metronome with setlists", args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Setlist to open in the window instead of the last one
+    #[clap(value_parser)]
+    pub setlist: Option<PathBuf>,
+    /// Log more, twice for everything
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Plays a click until stopped with Ctrl+C
+    Play(PlayArgs),
+    /// Plays setlists
+    #[clap(subcommand)]
+    Setlist(SetlistCommand),
+    /// Renders a click to a WAV file
+    Render(RenderArgs),
+    /// Shows the audio hosts and their output devices
+    #[clap(subcommand)]
+    Devices(DevicesCommand),
+    /// Checks a setlist and the tempo maps of its songs
+    Validate {
+        #[clap(value_parser)]
+        setlist: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SetlistCommand {
+    /// Plays a setlist, songs are changed with the configured keys and MIDI controls
+    Play(SetlistPlayArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DevicesCommand {
+    /// Lists the output devices of every host, or of one
+    List {
+        #[clap(long, value_parser)]
+        host: Option<HostSelector>,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct ClickArgs {
+    #[clap(short, long, value_parser, default_value = "120")]
+    pub bpm: Bpm,
+    /// Like "4/4", "7/8" or "7/8 2+2+3"
+    #[clap(short, long, value_parser, default_value = "4/4")]
+    pub meter: TimeSignature,
+    /// Ticks per beat, or a pattern like "x-x"
+    #[clap(short, long, value_parser, default_value = "1")]
+    pub subdivision: Subdivision,
+    /// Subdivision clicks relative to the volume, between 0 and 1000, the configured one by default
+    #[clap(long, value_parser = clap::value_parser!(u16).range(0..=1000))]
+    pub subdivision_volume: Option<u16>,
+    /// Plays a WAV, FLAC or Ogg Vorbis file for a beat class, like "accent=hi.wav". Repeatable.
+    #[clap(long = "sample", value_name = "CLASS=FILE", value_parser = parse_sample)]
+    pub samples: Vec<(BeatClass, PathBuf)>,
+}
+
+fn parse_sample(arg: &str) -> Result<(BeatClass, PathBuf), anyhow::Error> {
+    let (class, file) = arg.split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected CLASS=FILE, like accent=hi.wav"))?;
+    if file.is_empty() {
+        return Err(anyhow::anyhow!("No file for {}", class));
+    }
+    Ok((class.parse()?, PathBuf::from(file)))
+}
+
+impl ClickArgs {
+    // The sound options override the config. Sample paths are relative to the working directory.
+    fn apply_sound(&self, audio: &mut AudioConfig) -> Result<(), anyhow::Error> {
+        if let Some(volume) = self.subdivision_volume {
+            audio.subdivision_volume = volume;
+        }
+        for (class, path) in &self.samples {
+            let path = std::env::current_dir()?.join(path);
+            audio.voices.get_mut(*class).sample = Some(path);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct OutputArgs {
+    /// Audio host, the configured one by default
+    #[clap(long, value_parser)]
+    pub host: Option<HostSelector>,
+    /// Output device, the configured one by default
+    #[clap(long, value_parser)]
+    pub device: Option<String>,
+    /// Between 0 and 1000, the configured volume by default
+    #[clap(long, value_parser = clap::value_parser!(u16).range(0..=1000))]
+    pub volume: Option<u16>,
+}
+
+#[derive(Args, Debug)]
+pub struct PlayArgs {
+    #[clap(flatten)]
+    pub click: ClickArgs,
+    #[clap(flatten)]
+    pub output: OutputArgs,
+    /// Stops after this many seconds
+    #[clap(long, value_parser)]
+    pub seconds: Option<f64>,
+}
+
+#[derive(Args, Debug)]
+pub struct SetlistPlayArgs {
+    #[clap(value_parser)]
+    pub setlist: PathBuf,
+    /// Number of the song to start with
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..), default_value = "1")]
+    pub song: u16,
+    #[clap(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct RenderArgs {
+    /// WAV file to write
+    #[clap(value_parser)]
+    pub output: PathBuf,
+    #[clap(flatten)]
+    pub click: ClickArgs,
+    #[clap(long, value_parser, default_value = "60")]
+    pub seconds: f64,
+    #[clap(long, value_parser, default_value = "48000")]
+    pub sample_rate: u32,
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..), default_value = "2")]
+    pub channels: u16,
+    /// int16, int24 or float32
+    #[clap(long, value_parser, default_value = "int16")]
+    pub format: WavFormat,
+    #[clap(long, value_parser = clap::value_parser!(u16).range(0..=1000), default_value = "1000")]
+    pub volume: u16,
+}
+
+pub fn run(command: Command, config: ConfigFile) -> Result<(), anyhow::Error> {
+    if let Some(e) = &config.error {
+        warn!("{}", e);
+    }
+    match command {
+        Command::Play(args) => play(args, config),
+        Command::Setlist(SetlistCommand::Play(args)) => play_setlist(args, config),
+        Command::Render(args) => render(args, &config),
+        Command::Devices(DevicesCommand::List { host }) => {
+            list_devices(host);
+            Ok(())
+        }
+        Command::Validate { setlist } => validate(&setlist),
+    }
+}
+
+fn play(args: PlayArgs, mut config: ConfigFile) -> Result<(), anyhow::Error> {
+    let duration = args.seconds.map(seconds).transpose()?;
+    args.click.apply_sound(&mut config.config.audio)?;
+    with_input(|receiver| {
+        let (settings, volume) = output_settings(&args.output, &config);
+        let mut player = Headless::new(config, settings, volume)?;
+        player.set_click(args.click.bpm, args.click.meter, args.click.subdivision);
+        player.run(receiver, duration)
+    })
+}
+
+fn play_setlist(args: SetlistPlayArgs, config: ConfigFile) -> Result<(), anyhow::Error> {
+    let songs = load_songs(&args.setlist)?;
+    if songs.is_empty() {
+        return Err(anyhow::anyhow!("{} has no songs", args.setlist.display()));
+    }
+    let start = args.song as usize;
+    if start > songs.len() {
+        return Err(anyhow::anyhow!("{} only has {} songs", args.setlist.display(), songs.len()));
+    }
+    with_input(|receiver| {
+        let (settings, volume) = output_settings(&args.output, &config);
+        let mut player = Headless::new(config, settings, volume)?;
+        player.set_setlist(songs, start - 1);
+        player.run(receiver, None)
+    })
+}
+
+fn render(args: RenderArgs, config: &ConfigFile) -> Result<(), anyhow::Error> {
+    let mut audio = config.config.audio.clone();
+    args.click.apply_sound(&mut audio)?;
+    let dir = config.dir();
+    let samples = BeatClass::ALL
+        .iter()
+        .filter_map(|class| audio.voices.get(*class).sample.as_ref().map(|path| (*class, path)))
+        .map(|(class, path)| Ok((class, Arc::new(ClickSample::load(dir.join(path))?))))
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    let options = RenderOptions {
+        sample_rate: args.sample_rate,
+        channels: args.channels,
+        bpm: args.click.bpm,
+        time_signature: args.click.meter,
+        subdivision: args.click.subdivision,
+        volume: args.volume,
+        subdivision_volume: audio.subdivision_volume,
+        voices: audio.voices.voices(),
+        samples,
+        duration: seconds(args.seconds)?,
+        format: args.format,
+        ..RenderOptions::default()
+    };
+    render::render_to_wav(&args.output, &options)?;
+    println!("Rendered {} seconds to {}", args.seconds, args.output.display());
+    Ok(())
+}
+
+fn list_devices(host: Option<HostSelector>) {
+    let default_host = HostSelector::default();
+    let hosts = match host {
+        Some(host) => vec![host],
+        None => HostSelector::supported(),
+    };
+    for host in hosts {
+        if host == default_host {
+            println!("{} (default)", host);
+        } else {
+            println!("{}", host);
+        }
+        let devices = host.supported_output_devices();
+        if devices.is_empty() {
+            println!("    no output devices");
+        }
+        for device in devices {
+            println!("    {}", device);
+        }
+    }
+}
+
+fn validate(path: &Path) -> Result<(), anyhow::Error> {
+    let songs = song_listing::load_setlist(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut problems = 0;
+    for (number, song) in songs.iter().enumerate() {
+        match song.load_tempo_map(dir) {
+            Ok(Some(map)) => {
+                let (bpm, meter) = map.start();
+                println!("{:>3}. {} - tempo map with {} changes, from {} BPM in {}", number + 1, song.title(), map.changes().len(), bpm, meter);
+            }
+            Ok(None) => {
+                let bpm = match song.bpm() {
+                    BPM::Number(bpm) => bpm.to_string(),
+                    BPM::File(file) => file.clone(),
+                };
+                println!("{:>3}. {} - {} BPM", number + 1, song.title(), bpm);
+            }
+            Err(e) => {
+                problems += 1;
+                println!("{:>3}. {:#}", number + 1, e);
+            }
+        }
+    }
+    if problems > 0 {
+        return Err(anyhow::anyhow!("{} of {} songs in {} have problems", problems, songs.len(), path.display()));
+    }
+    println!("{} is valid", path.display());
+    Ok(())
+}
+
+fn load_songs(path: &Path) -> Result<Vec<(song_listing::FileSongListing, Option<audio::tempo::TempoMap>)>, anyhow::Error> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    song_listing::load_setlist(path)?
+        .into_iter()
+        .map(|song| {
+            let map = song.load_tempo_map(dir)?;
+            Ok((song, map))
+        })
+        .collect()
+}
+
+// A host given on the command line does not use the device configured for another host
+fn output_settings(args: &OutputArgs, config: &ConfigFile) -> (audio::OutputSettings, u16) {
+    let audio = &config.config.audio;
+    let device = match args.host {
+        Some(_) => args.device.clone(),
+        None => args.device.clone().or_else(|| audio.device.clone()),
+    };
+    let settings = audio::OutputSettings {
+        host: args.host.unwrap_or_else(|| config.config.host()),
+        device,
+        sample_rate: audio.sample_rate,
+        buffer_size: audio.buffer_size,
+        routing: audio.routing.clone(),
+        jack: config.config.jack.clone(),
+    };
+    (settings, args.volume.unwrap_or(audio.volume))
+}
+
+fn seconds(seconds: f64) -> Result<Duration, anyhow::Error> {
+    if !seconds.is_finite() || seconds <= 0. {
+        return Err(anyhow::anyhow!("Duration must be a positive number of seconds (got {})", seconds));
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+// Keys, MIDI controls and Ctrl+C arrive as messages, like in the window
+fn with_input<F>(play: F) -> Result<(), anyhow::Error>
+where
+    F: FnOnce(UnboundedReceiver<Message>) -> Result<(), anyhow::Error>,
+{
+    let (sender, receiver) = unbounded();
+    input::add_sender(sender);
+    let input_handler = input::init();
+    if let Err(e) = ctrlc::set_handler(|| input::send(Message::Quit)) {
+        error!("Could not handle Ctrl+C ({:?})", e);
+    }
+
+    let result = play(receiver).context("Could not play");
+    input_handler.on_shutdown();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("metronome").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn parses_play() {
+        let cli = parse(&["play", "--bpm", "92.5", "--meter", "7/8", "-s", "x-x"]).unwrap();
+        match cli.command {
+            Some(Command::Play(args)) => {
+                assert_eq!(args.click.bpm.to_string(), "92.5");
+                assert_eq!(args.click.meter, TimeSignature::new(7, 8).unwrap());
+                assert_eq!(args.click.subdivision, Subdivision::from_pattern("x-x").unwrap());
+                assert!(args.output.host.is_none() && args.seconds.is_none());
+                assert!(args.click.subdivision_volume.is_none());
+            }
+            command => panic!("Unexpected {:?}", command),
+        }
+
+        assert!(parse(&["play", "--bpm", "0"]).is_err());
+        assert!(parse(&["play", "--meter", "7/9"]).is_err());
+        assert!(parse(&["play", "--volume", "1001"]).is_err());
+        assert!(parse(&["play", "--subdivision-volume", "1001"]).is_err());
+        assert!(parse(&["play", "-s", &"x".repeat(300)]).is_err());
+    }
+
+    #[test]
+    fn parses_samples() {
+        let cli = parse(&["render", "out.wav", "--sample", "accent=hi.wav", "--sample", "Subdivision=sub dir/lo.flac"]).unwrap();
+        match cli.command {
+            Some(Command::Render(args)) => assert_eq!(args.click.samples, vec![
+                (BeatClass::Accent, PathBuf::from("hi.wav")),
+                (BeatClass::Subdivision, PathBuf::from("sub dir/lo.flac")),
+            ]),
+            command => panic!("Unexpected {:?}", command),
+        }
+
+        assert!(parse(&["play", "--sample", "hi.wav"]).is_err());
+        assert!(parse(&["play", "--sample", "accent="]).is_err());
+        assert!(parse(&["play", "--sample", "tick=hi.wav"]).is_err());
+    }
+
+    #[test]
+    fn parses_the_other_commands() {
+        let cli = parse(&["setlist", "play", "gig.yaml", "--song", "3"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Setlist(SetlistCommand::Play(args))) if args.song == 3 && args.setlist == Path::new("gig.yaml")));
+        assert!(parse(&["setlist", "play", "gig.yaml", "--song", "0"]).is_err());
+
+        let cli = parse(&["render", "out.wav", "--seconds", "10", "--format", "float32"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Render(args)) if args.format == WavFormat::Float32 && args.sample_rate == 48000));
+
+        let cli = parse(&["devices", "list", "-vv"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Devices(DevicesCommand::List { host: None }))));
+        assert_eq!(cli.verbose, 2);
+        assert!(matches!(parse(&["validate", "gig.yaml"]).unwrap().command, Some(Command::Validate { .. })));
+    }
+
+    #[test]
+    fn opens_a_setlist_in_the_window_without_a_command() {
+        let cli = parse(&["gig.yaml"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.setlist.as_deref(), Some(Path::new("gig.yaml")));
+        assert_eq!(parse(&[]).unwrap().verbose, 0);
+    }
+
+    #[test]
+    fn validates_setlists() {
+        let dir = std::env::temp_dir().join(format!("metronome-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let setlist = dir.join("gig.yaml");
+        std::fs::write(dir.join("map.yaml"), "- bar: 1\n  bpm: 100\n- bar: 9\n  meter: 7/8\n").unwrap();
+
+        std::fs::write(&setlist, "- title: One\n  bpm:\n    Number: 120\n- title: Two\n  bpm:\n    File: map.yaml\n").unwrap();
+        assert!(validate(&setlist).is_ok());
+        assert_eq!(load_songs(&setlist).unwrap()[1].1.as_ref().map(|m| m.changes().len()), Some(2));
+
+        std::fs::write(&setlist, "- title: One\n  bpm:\n    File: missing.yaml\n").unwrap();
+        assert!(format!("{:#}", validate(&setlist).unwrap_err()).contains("1 of 1 songs"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::audio::routing::ChannelRouting;
+use crate::audio::voice::VoicesConfig;
+use crate::audio::HostSelector;
+use crate::input::keymap::KeyMap;
+use crate::midi::controller::ControlMap;
+use crate::tap::TapRounding;
+
+const CONFIG_FILE: &str = "config.yaml";
+const MAX_RECENT_SETLISTS: usize = 8;
+pub const MIN_WINDOW_SIZE: (u32, u32) = (600, 400);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    pub host: Option<String>,
+    pub device: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub buffer_size: Option<u32>,
+    pub volume: u16,
+    // Subdivision clicks relative to the volume, between 0 and 1000
+    pub subdivision_volume: u16,
+    // Sound of every beat class
+    pub voices: VoicesConfig,
+    // Output channels by device name
+    pub routing: BTreeMap<String, ChannelRouting>,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            host: None,
+            device: None,
+            sample_rate: None,
+            buffer_size: None,
+            volume: 0,
+            subdivision_volume: 600,
+            voices: VoicesConfig::default(),
+            routing: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WindowGeometry {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Default for WindowGeometry {
+    fn default() -> Self {
+        WindowGeometry {
+            width: 1024,
+            height: 768,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    pub last_setlist: Option<PathBuf>,
+    pub recent_setlists: Vec<PathBuf>,
+    pub window: WindowGeometry,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MidiConfig {
+    // Sends MIDI clock, start/stop and song changes
+    pub clock_output: bool,
+    // Output port to connect to, a virtual port is created if unset
+    pub output_port: Option<String>,
+    // Channel for program changes, 1 to 16
+    pub program_channel: u8,
+    // Follows tempo, start and stop of an incoming MIDI clock
+    pub sync_input: bool,
+    // Input port to connect to, a virtual port is created if unset
+    pub input_port: Option<String>,
+    // Triggers actions from notes, control and program changes of a controller
+    pub controller_input: bool,
+    // Controller port to connect to, a virtual port is created if unset
+    pub controller_port: Option<String>,
+    pub controls: ControlMap,
+}
+
+impl Default for MidiConfig {
+    fn default() -> Self {
+        MidiConfig {
+            clock_output: false,
+            output_port: None,
+            program_channel: 1,
+            sync_input: false,
+            input_port: None,
+            controller_input: false,
+            controller_port: None,
+            controls: ControlMap::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TapConfig {
+    // Number of taps the tempo is averaged over
+    pub window: usize,
+    // A pause this long starts a new measurement
+    pub timeout_ms: u64,
+    pub rounding: TapRounding,
+    // Writes the tapped tempo into the current song of the setlist
+    pub update_song: bool,
+}
+
+impl Default for TapConfig {
+    fn default() -> Self {
+        TapConfig {
+            window: 8,
+            timeout_ms: 2000,
+            rounding: TapRounding::Off,
+            update_song: false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JackTransport {
+    Off,
+    // Publishes bar, beat and tempo to the JACK transport and starts and stops it with playback
+    Master,
+    // Plays, stops and takes tempo and meter from the JACK transport
+    Slave,
+}
+
+impl Default for JackTransport {
+    fn default() -> Self {
+        JackTransport::Off
+    }
+}
+
+// Used when the audio host is Jack
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct JackConfig {
+    // The outputs are <client_name>:click_1, <client_name>:click_2 and so on
+    pub client_name: String,
+    pub outputs: u8,
+    // Ports the outputs are connected to, in order. The physical playback ports if empty.
+    pub connect: Vec<String>,
+    pub transport: JackTransport,
+}
+
+impl Default for JackConfig {
+    fn default() -> Self {
+        JackConfig {
+            client_name: String::from("metronome"),
+            outputs: 2,
+            connect: Vec::new(),
+            transport: JackTransport::Off,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteConfig {
+    // Takes JSON commands from scripts on this machine, Unix only
+    pub socket: bool,
+    // $XDG_RUNTIME_DIR/metronome.sock if unset
+    pub socket_path: Option<PathBuf>,
+    // Takes OSC commands like /metronome/bpm 120 over UDP
+    pub osc: bool,
+    pub osc_listen: SocketAddr,
+    // Sends /metronome/beat and /metronome/tick here on every click, may be a broadcast address
+    pub osc_beat_target: Option<SocketAddr>,
+    // Serves a control page for phones and a WebSocket on http://<web_listen>/
+    pub web: bool,
+    pub web_listen: SocketAddr,
+    // Commands over the web are only taken with this token, open the page as /?token=<web_token>
+    pub web_token: Option<String>,
+    // Names the page is opened by besides IP addresses and localhost, like raspberrypi.local.
+    // Other names are refused, so that sites cannot reach the remote through their own domain.
+    pub web_hosts: Vec<String>,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        RemoteConfig {
+            socket: false,
+            socket_path: None,
+            osc: false,
+            osc_listen: SocketAddr::from(([0, 0, 0, 0], 9000)),
+            osc_beat_target: None,
+            web: false,
+            web_listen: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            web_token: None,
+            web_hosts: Vec::new(),
+        }
+    }
+}
+
+impl RemoteConfig {
+    pub fn socket_path(&self) -> PathBuf {
+        if let Some(path) = &self.socket_path {
+            return path.clone();
+        }
+        let dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .filter(|d| !d.is_empty())
+            .map_or_else(std::env::temp_dir, PathBuf::from);
+        dir.join("metronome.sock")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub audio: AudioConfig,
+    pub ui: UiConfig,
+    pub keys: KeyMap,
+    pub midi: MidiConfig,
+    pub tap: TapConfig,
+    pub remote: RemoteConfig,
+    pub jack: JackConfig,
+}
+
+impl AppConfig {
+    pub fn parse(yaml: &str) -> Result<AppConfig, anyhow::Error> {
+        let config: AppConfig = serde_yaml::from_str(yaml)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if let Some(host) = &self.audio.host {
+            host.parse::<HostSelector>()
+                .with_context(|| "audio.host")?;
+        }
+        if self.audio.volume > 1000 {
+            return Err(anyhow::anyhow!("audio.volume must be between 0 and 1000 (got {})", self.audio.volume));
+        }
+        if self.audio.subdivision_volume > 1000 {
+            return Err(anyhow::anyhow!(
+                "audio.subdivision_volume must be between 0 and 1000 (got {})",
+                self.audio.subdivision_volume
+            ));
+        }
+        if let Some(rate) = self.audio.sample_rate {
+            if !(8000..=384000).contains(&rate) {
+                return Err(anyhow::anyhow!("audio.sample_rate must be between 8000 and 384000 Hz (got {})", rate));
+            }
+        }
+        if let Some(size) = self.audio.buffer_size {
+            if !(16..=8192).contains(&size) {
+                return Err(anyhow::anyhow!("audio.buffer_size must be between 16 and 8192 frames (got {})", size));
+            }
+        }
+        self.audio.voices.validate().context("audio.voices")?;
+        for (device, routing) in &self.audio.routing {
+            routing.validate().with_context(|| format!("audio.routing.{}", device))?;
+        }
+        self.keys.validate().with_context(|| "keys")?;
+        self.midi.controls.validate().with_context(|| "midi.controls")?;
+        if !(1..=16).contains(&self.midi.program_channel) {
+            return Err(anyhow::anyhow!("midi.program_channel must be between 1 and 16 (got {})", self.midi.program_channel));
+        }
+        if !(2..=32).contains(&self.tap.window) {
+            return Err(anyhow::anyhow!("tap.window must be between 2 and 32 taps (got {})", self.tap.window));
+        }
+        if !(250..=10000).contains(&self.tap.timeout_ms) {
+            return Err(anyhow::anyhow!("tap.timeout_ms must be between 250 and 10000 (got {})", self.tap.timeout_ms));
+        }
+        if self.jack.client_name.trim().is_empty() {
+            return Err(anyhow::anyhow!("jack.client_name must not be empty"));
+        }
+        if !(1..=32).contains(&self.jack.outputs) {
+            return Err(anyhow::anyhow!("jack.outputs must be between 1 and 32 (got {})", self.jack.outputs));
+        }
+        if self.jack.connect.len() > self.jack.outputs as usize {
+            return Err(anyhow::anyhow!(
+                "jack.connect lists {} ports for {} outputs",
+                self.jack.connect.len(), self.jack.outputs
+            ));
+        }
+        let window = self.ui.window;
+        if window.width < MIN_WINDOW_SIZE.0 || window.height < MIN_WINDOW_SIZE.1 {
+            return Err(anyhow::anyhow!(
+                "ui.window must be at least {}x{} (got {}x{})",
+                MIN_WINDOW_SIZE.0, MIN_WINDOW_SIZE.1, window.width, window.height
+            ));
+        }
+        Ok(())
+    }
+
+    // Makes the setlist the last one and moves it to the front of the recent list
+    pub fn remember_setlist(&mut self, path: &Path) {
+        self.ui.recent_setlists.retain(|p| p != path);
+        self.ui.recent_setlists.insert(0, path.to_path_buf());
+        self.ui.recent_setlists.truncate(MAX_RECENT_SETLISTS);
+        self.ui.last_setlist = Some(path.to_path_buf());
+    }
+
+    pub fn host(&self) -> HostSelector {
+        self.audio.host
+            .as_ref()
+            .and_then(|h| h.parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+// $XDG_CONFIG_HOME/metronome, falling back to ~/.config/metronome (or %APPDATA%\metronome on Windows)
+pub fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir).join("metronome"));
+    }
+    #[cfg(target_os = "windows")]
+    {
+        if let Some(dir) = std::env::var_os("APPDATA") {
+            return Some(PathBuf::from(dir).join("metronome"));
+        }
+    }
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join("metronome"))
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    pub path: Option<PathBuf>,
+    pub config: AppConfig,
+    // Set if the file exists but could not be loaded. It is never overwritten in that case.
+    pub error: Option<String>,
+}
+
+impl ConfigFile {
+    pub fn load() -> ConfigFile {
+        let path = config_dir().map(|dir| dir.join(CONFIG_FILE));
+        match &path {
+            Some(path) => ConfigFile::load_from(path),
+            None => {
+                error!("Could not determine the config directory, settings will not be saved");
+                ConfigFile {
+                    path: None,
+                    config: AppConfig::default(),
+                    error: None,
+                }
+            }
+        }
+    }
+
+    pub fn load_from(path: &Path) -> ConfigFile {
+        let loaded = if path.exists() {
+            std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|yaml| AppConfig::parse(&yaml))
+                .with_context(|| format!("Invalid config file {}", path.display()))
+        } else {
+            debug!("No config file at {}, using defaults", path.display());
+            Ok(AppConfig::default())
+        };
+
+        match loaded {
+            Ok(config) => ConfigFile {
+                path: Some(path.to_path_buf()),
+                config,
+                error: None,
+            },
+            Err(e) => {
+                error!("{:#}", e);
+                ConfigFile {
+                    path: Some(path.to_path_buf()),
+                    config: AppConfig::default(),
+                    error: Some(format!("{:#}", e)),
+                }
+            }
+        }
+    }
+
+    // Relative paths in the config start here
+    pub fn dir(&self) -> PathBuf {
+        self.path.as_ref()
+            .and_then(|p| p.parent())
+            .map_or_else(|| PathBuf::from("."), Path::to_path_buf)
+    }
+
+    pub fn save(&self) -> Result<(), anyhow::Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if self.error.is_some() {
+            debug!("Not overwriting the invalid config file {}", path.display());
+            return Ok(());
+        }
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Could not create {}", dir.display()))?;
+        }
+        let yaml = serde_yaml::to_string(&self.config)?;
+        // Write next to the file and rename, so a crash never leaves a truncated config behind
+        let tmp = path.with_extension("yaml.tmp");
+        std::fs::write(&tmp, yaml).with_context(|| format!("Could not write {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("Could not write {}", path.display()))?;
+        debug!("Saved config to {}", path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_yaml() {
+        let mut config = AppConfig::default();
+        config.audio.device = Some(String::from("Focusrite USB"));
+        config.audio.sample_rate = Some(48000);
+        config.audio.volume = 700;
+        config.audio.routing.insert(String::from("Scarlett 18i20"), ChannelRouting {
+            click: vec![3, 4],
+            accent: vec![5],
+            cue: Vec::new(),
+        });
+        config.ui.last_setlist = Some(PathBuf::from("/tmp/setlist.yaml"));
+
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        assert_eq!(AppConfig::parse(&yaml).unwrap(), config);
+    }
+
+    #[test]
+    fn recent_setlists_are_unique_and_bounded() {
+        let mut config = AppConfig::default();
+        for i in 0..20 {
+            config.remember_setlist(Path::new(&format!("/setlists/{}.yaml", i % 10)));
+        }
+        config.remember_setlist(Path::new("/setlists/5.yaml"));
+        assert_eq!(config.ui.recent_setlists.len(), MAX_RECENT_SETLISTS);
+        assert_eq!(config.ui.recent_setlists[0], Path::new("/setlists/5.yaml"));
+        assert_eq!(config.ui.recent_setlists[1], Path::new("/setlists/9.yaml"));
+        assert_eq!(config.ui.last_setlist.as_deref(), Some(Path::new("/setlists/5.yaml")));
+    }
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let config = AppConfig::parse("audio:\n  volume: 300\n").unwrap();
+        assert_eq!(config.keys, KeyMap::default());
+        assert_eq!(config.audio.volume, 300);
+        assert_eq!(config.ui, UiConfig::default());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let error = AppConfig::parse("audio:\n  volume: 5000\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("audio.volume"));
+        assert!(AppConfig::parse("audio:\n  sample_rate: 12\n").is_err());
+        assert!(AppConfig::parse("audio:\n  subdivision_volume: 1001\n").is_err());
+        assert!(AppConfig::parse("audio:\n  voices:\n    accent:\n      frequency: 5\n").is_err());
+        assert!(AppConfig::parse("audio:\n  host: NotAHost\n").is_err());
+        assert!(AppConfig::parse("ui:\n  window: { width: 10, height: 10 }\n").is_err());
+        assert!(AppConfig::parse("audio:\n  volum: 300\n").is_err());
+        assert!(AppConfig::parse("tap:\n  window: 1\n").is_err());
+        assert!(AppConfig::parse("jack:\n  outputs: 1\n  connect: [system:playback_1, system:playback_2]\n").is_err());
+        assert!(AppConfig::parse("jack:\n  transport: Leader\n").is_err());
+        assert!(AppConfig::parse("audio:\n  routing:\n    Scarlett 18i20:\n      click: [0]\n").is_err());
+        assert!(AppConfig::parse("audio:\n  routing:\n    Scarlett 18i20:\n      clicks: [3, 4]\n").is_err());
+    }
+}
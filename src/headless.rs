@@ -0,0 +1,247 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_channel::mpsc::UnboundedReceiver;
+use iced_futures::futures::{executor, StreamExt};
+use log::{debug, error, info, trace, warn};
+
+use super::audio::{self, AudioHandle, AudioMessage};
+use super::audio::meter::{Subdivision, TimeSignature};
+use super::audio::tempo::{Bpm, TempoMap};
+use super::config::ConfigFile;
+use super::input::{self, keycode::KeyCode};
+use super::input::keymap::{Action, KeyBinding, Modifiers};
+use super::midi;
+use super::song_listing::{FileSongListing, BPM};
+use super::remote;
+use super::tap;
+use super::transport::{SetlistSong, Transport};
+use super::{Message, VOLUME_STEP};
+
+#[derive(Debug)]
+struct Song {
+    listing: FileSongListing,
+    tempo_map: Option<Arc<TempoMap>>,
+}
+
+impl SetlistSong for Song {
+    fn title(&self) -> &str {
+        self.listing.title()
+    }
+
+    fn bpm(&self) -> Option<Bpm> {
+        match (&self.tempo_map, self.listing.bpm()) {
+            (Some(map), _) => Some(map.start().0),
+            (None, BPM::Number(bpm)) => Some(*bpm),
+            (None, BPM::File(_)) => None,
+        }
+    }
+
+    fn tempo_map(&self) -> Option<&Arc<TempoMap>> {
+        self.tempo_map.as_ref()
+    }
+
+    fn subdivision(&self) -> Subdivision {
+        self.listing.subdivision()
+    }
+
+    fn program(&self) -> Option<u8> {
+        self.listing.program()
+    }
+}
+
+// Plays without a window. Keys and MIDI controls do what they do in the window,
+// apart from learning new bindings.
+#[derive(Debug)]
+pub struct Headless {
+    config: ConfigFile,
+    audio_handle: AudioHandle,
+    midi_clock: Option<midi::clock::MidiClock>,
+    // Kept open while playing, triggers arrive as messages
+    _midi_controller: Option<midi::controller::MidiController>,
+    // The engine follows it on its own
+    midi_sync: Option<midi::sync::MidiSync>,
+    remotes: remote::Remotes,
+    transport: Transport,
+    songs: Vec<Song>,
+}
+
+impl Headless {
+    pub fn new(config: ConfigFile, settings: audio::OutputSettings, volume: u16) -> Result<Headless, anyhow::Error> {
+        let audio_handle = audio::setup(settings.clone());
+        if audio_handle.device_name().is_none() {
+            return Err(match &settings.device {
+                Some(device) => anyhow::anyhow!("Output device '{}' is not available on {}", device, settings.host),
+                None => anyhow::anyhow!("No output device available on {}", settings.host),
+            });
+        }
+        info!("Playing on '{}' ({})", audio_handle.device_name().unwrap_or_default(), settings.host);
+
+        let midi_config = &config.config.midi;
+        let midi_clock = if midi_config.clock_output {
+            match midi::clock::MidiClock::open(midi_config) {
+                Ok(clock) => Some(clock),
+                Err(e) => {
+                    error!("Could not open MIDI clock output ({:#})", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let midi_controller = if midi_config.controller_input {
+            match midi::controller::MidiController::open(midi_config) {
+                Ok(controller) => Some(controller),
+                Err(e) => {
+                    error!("Could not open MIDI controller input ({:#})", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let midi_sync = if midi_config.sync_input {
+            match midi::sync::MidiSync::open(midi_config) {
+                Ok(sync) => Some(sync),
+                Err(e) => {
+                    error!("Could not open MIDI sync input ({:#})", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let remotes = remote::Remotes::open(&config.config.remote, |e| {
+            error!("Could not start remote control ({:#})", e);
+        });
+
+        let tap_config = &config.config.tap;
+        let tap_tempo = tap::TapTempo::new(tap_config.window, Duration::from_millis(tap_config.timeout_ms), tap_config.rounding);
+        let transport = Transport::new(tap_tempo, midi_sync.is_some(), remotes.publisher());
+
+        let mut headless = Headless {
+            config,
+            audio_handle,
+            midi_clock,
+            _midi_controller: midi_controller,
+            midi_sync,
+            remotes,
+            transport,
+            songs: Vec::new(),
+        };
+        if let Some(clock) = &headless.midi_clock {
+            headless.audio_handle.set_clock_sink(Some(clock.sink()));
+        }
+        if let Some(sync) = &headless.midi_sync {
+            headless.audio_handle.set_sync_source(Some(sync.source()));
+        }
+        headless.audio_handle.set_beat_sink(headless.remotes.beat_sink());
+        headless.audio_handle.send(AudioMessage::SetVolume(volume));
+        let dir = headless.config.dir();
+        headless.audio_handle.apply_sound(&headless.config.config.audio, &dir)?;
+
+        let watched = audio::tempo::watch_position(headless.audio_handle.position(), |bar| input::send(Message::BarChanged(bar)));
+        if let Err(e) = watched {
+            error!("Could not watch the playback position ({:?})", e);
+        }
+        Ok(headless)
+    }
+
+    pub fn set_click(&mut self, bpm: Bpm, meter: TimeSignature, subdivision: Subdivision) {
+        self.audio_handle.send(AudioMessage::SetTimeSignature(meter));
+        self.audio_handle.send(AudioMessage::SetBpm(bpm));
+        self.audio_handle.send(AudioMessage::SetSubdivision(subdivision));
+        info!("{} BPM in {}", bpm, meter);
+    }
+
+    // Tempo maps must be loaded already
+    pub fn set_setlist(&mut self, songs: Vec<(FileSongListing, Option<TempoMap>)>, current: usize) {
+        self.songs = songs
+            .into_iter()
+            .map(|(listing, map)| Song { listing, tempo_map: map.map(Arc::new) })
+            .collect();
+        self.transport.select(current, &self.songs, &mut self.audio_handle, self.midi_clock.as_ref());
+    }
+
+    // Handles messages until `Message::Quit` or until `duration` is over
+    pub fn run(mut self, mut receiver: UnboundedReceiver<Message>, duration: Option<Duration>) -> Result<(), anyhow::Error> {
+        if let Some(duration) = duration {
+            std::thread::Builder::new()
+                .name(String::from("play timer"))
+                .spawn(move || {
+                    std::thread::sleep(duration);
+                    input::send(Message::Quit);
+                })?;
+        }
+
+        self.audio_handle.send(AudioMessage::Play);
+        while let Some(message) = executor::block_on(receiver.next()) {
+            match message {
+                Message::Quit => break,
+                Message::KeyEvent(code, modifiers) => self.handle_keystroke(code, modifiers),
+                Message::Action(action) => self.perform(action),
+                Message::MidiTrigger(trigger) => match self.config.config.midi.controls.action(&trigger) {
+                    Some(action) => self.perform(action),
+                    None => trace!("No action bound to {}", trigger),
+                },
+                Message::AudioMessage(msg) => self.transport.send(&mut self.audio_handle, msg),
+                Message::VolumeChanged(volume) => self.set_volume(volume),
+                Message::Tap => self.perform(Action::TapTempo),
+                Message::BarChanged(bar) => self.on_bar(bar),
+                Message::ClockSync(event) => self.on_clock_sync(event),
+                _ => {}
+            }
+        }
+        self.audio_handle.send(AudioMessage::Pause);
+        debug!("Stopped playing");
+        Ok(())
+    }
+
+    fn handle_keystroke(&mut self, code: KeyCode, modifiers: Modifiers) {
+        let binding = KeyBinding::new(code, modifiers);
+        match self.config.config.keys.action(&binding) {
+            Some(action) => self.perform(action),
+            None => trace!("No action bound to {}", binding),
+        }
+    }
+
+    fn perform(&mut self, action: Action) {
+        debug!("Performing {:?}", action);
+        match action {
+            Action::VolumeUp => self.set_volume(self.audio_handle.state().volume as f32 + VOLUME_STEP),
+            Action::VolumeDown => self.set_volume(self.audio_handle.state().volume as f32 - VOLUME_STEP),
+            _ => {
+                self.transport.perform(action, &self.songs, &mut self.audio_handle, self.midi_clock.as_ref());
+            }
+        }
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        let volume = volume.clamp(0., 1000.) as u16;
+        info!("Volume {}", volume);
+        self.audio_handle.send(AudioMessage::SetVolume(volume));
+    }
+
+    fn on_clock_sync(&mut self, event: midi::sync::SyncEvent) {
+        use midi::sync::SyncEvent::*;
+        match event {
+            Start | Continue => info!("Following the MIDI clock"),
+            Lost => warn!("MIDI clock lost"),
+            Tempo(tempo) => debug!("MIDI sync: {:.1} BPM", tempo),
+            Stop | Sixteenth(_) => {}
+        }
+    }
+
+    fn on_bar(&mut self, bar: u32) {
+        if let Some(problem) = self.midi_clock.as_ref().and_then(|c| c.problem()) {
+            warn!("{}", problem);
+        }
+        let map = self.songs.get(self.transport.current()).and_then(|s| s.tempo_map.as_ref());
+        let section = map.and_then(|m| m.changes().iter().find(|c| c.bar == bar)).and_then(|c| c.section.as_ref());
+        match section {
+            Some(section) => info!("{} (bar {})", section, bar),
+            None => trace!("Bar {}", bar),
+        }
+    }
+}
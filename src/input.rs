@@ -1,20 +1,31 @@
 use super::Message;
+#[cfg(target_os = "linux")]
+use crate::input::linux::LinuxInput;
+#[cfg(target_os = "windows")]
 use crate::input::windows::WindowsInput;
 use futures_channel::mpsc::UnboundedSender;
-use log::error;
+use log::{error, warn};
 use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use super::util::SyncWrapper;
 
+#[cfg(target_os = "linux")]
+mod linux;
 #[cfg(target_os = "windows")]
 mod windows;
 
 pub mod keycode;
+pub mod keymap;
 
 type WrappedSender = SyncWrapper<UnboundedSender<Message>>;
 
 static SENDER: OnceCell<WrappedSender> = OnceCell::new();
 
+// Set while an OS backend delivers key presses regardless of focus, so the window
+// must not handle them a second time
+static GLOBAL: AtomicBool = AtomicBool::new(false);
+
 pub trait OsInput {
     fn new() -> Self;
     fn on_shutdown(&self);
@@ -25,10 +36,35 @@ pub fn init() -> impl OsInput {
     {
         return WindowsInput::new();
     }
-    #[cfg(not(target_os = "windows"))]
+    #[cfg(target_os = "linux")]
+    {
+        return LinuxInput::new();
+    }
+    #[cfg(not(any(target_os = "windows", target_os = "linux")))]
     {
-        todo!()
+        return WindowInput::new();
+    }
+}
+
+pub fn is_global() -> bool {
+    GLOBAL.load(Ordering::SeqCst)
+}
+
+fn set_global(global: bool) {
+    GLOBAL.store(global, Ordering::SeqCst);
+}
+
+// Used where there is no global backend, keys only arrive through the focused window
+#[allow(dead_code)]
+struct WindowInput;
+
+impl OsInput for WindowInput {
+    fn new() -> WindowInput {
+        warn!("No global keyboard input on this platform, keys are only handled while the window is focused");
+        WindowInput
     }
+
+    fn on_shutdown(&self) {}
 }
 
 pub fn add_sender(sender: UnboundedSender<Message>) {
@@ -36,3 +72,15 @@ pub fn add_sender(sender: UnboundedSender<Message>) {
         error!("Could not set sender!");
     }
 }
+
+// Delivers a message to the application from any thread
+pub fn send(message: Message) {
+    match SENDER.get() {
+        Some(sender) => {
+            if let Err(e) = sender.get().unbounded_send(message) {
+                error!("Failed to send Message! ({:?})", e);
+            }
+        }
+        None => warn!("Application is not ready, dropping {:?}", message),
+    }
+}
@@ -1,7 +1,20 @@
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::anyhow;
 use iced_native::keyboard::KeyCode as IcedKeyCode;
-use winapi::shared::minwindef::DWORD;
+use serde::{Deserialize, Serialize};
+#[cfg(target_os = "windows")]
+pub use winapi::shared::minwindef::DWORD;
+
+// Virtual key codes are 32 bit, other platforms store their own codes in the same width
+#[cfg(not(target_os = "windows"))]
+pub type DWORD = u32;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+// Stored as its name ("Space", "F5", "Num7", ...) or as a raw platform code ("0xE9") for keys without one
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum KeyCode {
     LeftMouseButton,
     RightMouseButton,
@@ -170,6 +183,250 @@ pub enum KeyCode {
     Invalid(DWORD),
 }
 
+const NAMES: &[(KeyCode, &str)] = &[
+    (KeyCode::LeftMouseButton, "LeftMouseButton"),
+    (KeyCode::RightMouseButton, "RightMouseButton"),
+    (KeyCode::MiddleMouseButton, "MiddleMouseButton"),
+    (KeyCode::Cancel, "Cancel"),
+    (KeyCode::X1MouseButton, "X1MouseButton"),
+    (KeyCode::X2MouseButton, "X2MouseButton"),
+    (KeyCode::Backspace, "Backspace"),
+    (KeyCode::Tab, "Tab"),
+    (KeyCode::Clear, "Clear"),
+    (KeyCode::Enter, "Enter"),
+    (KeyCode::Shift, "Shift"),
+    (KeyCode::Control, "Control"),
+    (KeyCode::Alt, "Alt"),
+    (KeyCode::Pause, "Pause"),
+    (KeyCode::CapsLock, "CapsLock"),
+    (KeyCode::Escape, "Escape"),
+    (KeyCode::SpaceBar, "Space"),
+    (KeyCode::PageUp, "PageUp"),
+    (KeyCode::PageDown, "PageDown"),
+    (KeyCode::End, "End"),
+    (KeyCode::Home, "Home"),
+    (KeyCode::LeftArrow, "Left"),
+    (KeyCode::UpArrow, "Up"),
+    (KeyCode::RightArrow, "Right"),
+    (KeyCode::DownArrow, "Down"),
+    (KeyCode::Select, "Select"),
+    (KeyCode::Print, "Print"),
+    (KeyCode::Execute, "Execute"),
+    (KeyCode::PrintScreen, "PrintScreen"),
+    (KeyCode::Insert, "Insert"),
+    (KeyCode::Delete, "Delete"),
+    (KeyCode::Help, "Help"),
+    (KeyCode::Zero, "0"),
+    (KeyCode::One, "1"),
+    (KeyCode::Two, "2"),
+    (KeyCode::Three, "3"),
+    (KeyCode::Four, "4"),
+    (KeyCode::Five, "5"),
+    (KeyCode::Six, "6"),
+    (KeyCode::Seven, "7"),
+    (KeyCode::Eight, "8"),
+    (KeyCode::Nine, "9"),
+    (KeyCode::A, "A"),
+    (KeyCode::B, "B"),
+    (KeyCode::C, "C"),
+    (KeyCode::D, "D"),
+    (KeyCode::E, "E"),
+    (KeyCode::F, "F"),
+    (KeyCode::G, "G"),
+    (KeyCode::H, "H"),
+    (KeyCode::I, "I"),
+    (KeyCode::J, "J"),
+    (KeyCode::K, "K"),
+    (KeyCode::L, "L"),
+    (KeyCode::M, "M"),
+    (KeyCode::N, "N"),
+    (KeyCode::O, "O"),
+    (KeyCode::P, "P"),
+    (KeyCode::Q, "Q"),
+    (KeyCode::R, "R"),
+    (KeyCode::S, "S"),
+    (KeyCode::T, "T"),
+    (KeyCode::U, "U"),
+    (KeyCode::V, "V"),
+    (KeyCode::W, "W"),
+    (KeyCode::X, "X"),
+    (KeyCode::Y, "Y"),
+    (KeyCode::Z, "Z"),
+    (KeyCode::LeftWindows, "LeftWindows"),
+    (KeyCode::RightWindows, "RightWindows"),
+    (KeyCode::Applications, "Applications"),
+    (KeyCode::ComputerSleep, "ComputerSleep"),
+    (KeyCode::NumZero, "Num0"),
+    (KeyCode::NumOne, "Num1"),
+    (KeyCode::NumTwo, "Num2"),
+    (KeyCode::NumThree, "Num3"),
+    (KeyCode::NumFour, "Num4"),
+    (KeyCode::NumFive, "Num5"),
+    (KeyCode::NumSix, "Num6"),
+    (KeyCode::NumSeven, "Num7"),
+    (KeyCode::NumEight, "Num8"),
+    (KeyCode::NumNine, "Num9"),
+    (KeyCode::Multiply, "Multiply"),
+    (KeyCode::Add, "Add"),
+    (KeyCode::Separator, "Separator"),
+    (KeyCode::Subtract, "Subtract"),
+    (KeyCode::Decimal, "Decimal"),
+    (KeyCode::Divide, "Divide"),
+    (KeyCode::F1, "F1"),
+    (KeyCode::F2, "F2"),
+    (KeyCode::F3, "F3"),
+    (KeyCode::F4, "F4"),
+    (KeyCode::F5, "F5"),
+    (KeyCode::F6, "F6"),
+    (KeyCode::F7, "F7"),
+    (KeyCode::F8, "F8"),
+    (KeyCode::F9, "F9"),
+    (KeyCode::F10, "F10"),
+    (KeyCode::F11, "F11"),
+    (KeyCode::F12, "F12"),
+    (KeyCode::F13, "F13"),
+    (KeyCode::F14, "F14"),
+    (KeyCode::F15, "F15"),
+    (KeyCode::F16, "F16"),
+    (KeyCode::F17, "F17"),
+    (KeyCode::F18, "F18"),
+    (KeyCode::F19, "F19"),
+    (KeyCode::F20, "F20"),
+    (KeyCode::F21, "F21"),
+    (KeyCode::F22, "F22"),
+    (KeyCode::F23, "F23"),
+    (KeyCode::F24, "F24"),
+    (KeyCode::NumLock, "NumLock"),
+    (KeyCode::ScrollLock, "ScrollLock"),
+    (KeyCode::LeftShift, "LeftShift"),
+    (KeyCode::RightShift, "RightShift"),
+    (KeyCode::LeftControl, "LeftControl"),
+    (KeyCode::RightControl, "RightControl"),
+    (KeyCode::LeftMenu, "LeftMenu"),
+    (KeyCode::RightMenu, "RightMenu"),
+    (KeyCode::BrowserBack, "BrowserBack"),
+    (KeyCode::BrowserForward, "BrowserForward"),
+    (KeyCode::BrowserRefresh, "BrowserRefresh"),
+    (KeyCode::BrowserStop, "BrowserStop"),
+    (KeyCode::BrowserSearch, "BrowserSearch"),
+    (KeyCode::BrowserFavorites, "BrowserFavorites"),
+    (KeyCode::BrowserStartAndHome, "BrowserStartAndHome"),
+    (KeyCode::VolumeMute, "VolumeMute"),
+    (KeyCode::VolumeDown, "VolumeDown"),
+    (KeyCode::VolumeUp, "VolumeUp"),
+    (KeyCode::NextTrack, "NextTrack"),
+    (KeyCode::PreviousTrack, "PreviousTrack"),
+    (KeyCode::StopMedia, "StopMedia"),
+    (KeyCode::PlayPauseMedia, "PlayPauseMedia"),
+    (KeyCode::StartMail, "StartMail"),
+    (KeyCode::SelectMedia, "SelectMedia"),
+    (KeyCode::StartApp1, "StartApp1"),
+    (KeyCode::StartApp2, "StartApp2"),
+    (KeyCode::Oem1, "Oem1"),
+    (KeyCode::OemPlus, "OemPlus"),
+    (KeyCode::OemComma, "OemComma"),
+    (KeyCode::OemMinus, "OemMinus"),
+    (KeyCode::OemPeriod, "OemPeriod"),
+    (KeyCode::Oem2, "Oem2"),
+    (KeyCode::Oem3, "Oem3"),
+    (KeyCode::Oem4, "Oem4"),
+    (KeyCode::Oem5, "Oem5"),
+    (KeyCode::Oem6, "Oem6"),
+    (KeyCode::Oem7, "Oem7"),
+    (KeyCode::Oem8, "Oem8"),
+    (KeyCode::Oem102, "Oem102"),
+    (KeyCode::Attn, "Attn"),
+    (KeyCode::CrSel, "CrSel"),
+    (KeyCode::ExSel, "ExSel"),
+    (KeyCode::EraseEOF, "EraseEOF"),
+    (KeyCode::Play, "Play"),
+    (KeyCode::Zoom, "Zoom"),
+    (KeyCode::Pa1, "Pa1"),
+    (KeyCode::OemClear, "OemClear"),
+];
+
+// Accepted when parsing, but never written
+const ALIASES: &[(KeyCode, &str)] = &[
+    (KeyCode::SpaceBar, "SpaceBar"),
+    (KeyCode::LeftArrow, "LeftArrow"),
+    (KeyCode::RightArrow, "RightArrow"),
+    (KeyCode::UpArrow, "UpArrow"),
+    (KeyCode::DownArrow, "DownArrow"),
+    (KeyCode::Escape, "Esc"),
+    (KeyCode::Enter, "Return"),
+    (KeyCode::Control, "Ctrl"),
+    (KeyCode::Alt, "Menu"),
+    (KeyCode::PageUp, "PgUp"),
+    (KeyCode::PageDown, "PgDn"),
+    (KeyCode::Delete, "Del"),
+    (KeyCode::Insert, "Ins"),
+    (KeyCode::LeftControl, "LeftCtrl"),
+    (KeyCode::RightControl, "RightCtrl"),
+    (KeyCode::LeftMenu, "LeftAlt"),
+    (KeyCode::RightMenu, "RightAlt"),
+    (KeyCode::PlayPauseMedia, "PlayPause"),
+];
+
+impl KeyCode {
+    // Raw codes are virtual key codes on Windows, other backends only store unknown codes
+    pub fn from_raw(code: DWORD) -> KeyCode {
+        #[cfg(target_os = "windows")]
+        {
+            KeyCode::from(code)
+        }
+        #[cfg(not(target_os = "windows"))]
+        {
+            KeyCode::NotImplemented(code)
+        }
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        NAMES.iter().find(|(code, _)| code == self).map(|(_, name)| *name)
+    }
+}
+
+impl Display for KeyCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "0x{:02X}", DWORD::from(self.clone())),
+        }
+    }
+}
+
+impl FromStr for KeyCode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            return DWORD::from_str_radix(hex, 16)
+                .map(KeyCode::from_raw)
+                .map_err(|_| anyhow!("Invalid key code '{}'", s));
+        }
+        NAMES
+            .iter()
+            .chain(ALIASES)
+            .find(|(_, name)| name.eq_ignore_ascii_case(s))
+            .map(|(code, _)| code.clone())
+            .ok_or_else(|| anyhow!("Unknown key '{}'", s))
+    }
+}
+
+impl TryFrom<String> for KeyCode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<KeyCode> for String {
+    fn from(code: KeyCode) -> Self {
+        code.to_string()
+    }
+}
+
 impl From<DWORD> for KeyCode {
     fn from(dword: DWORD) -> KeyCode {
         use KeyCode::*;
@@ -706,8 +963,7 @@ impl From<IcedKeyCode> for KeyCode {
 
 #[cfg(test)]
 mod tests {
-    use crate::keyboard::KeyCode;
-    use winapi::shared::minwindef::DWORD;
+    use super::{KeyCode, DWORD};
 
     #[test]
     fn from_dword_works() {
@@ -716,4 +972,24 @@ mod tests {
             assert_eq!(DWORD::from(KeyCode::from(dword)), dword);
         }
     }
+
+    #[test]
+    fn names_roundtrip() {
+        for dword in 0x00..0xFF {
+            let code = KeyCode::from(dword);
+            if code.name().is_some() {
+                assert_eq!(code.to_string().parse::<KeyCode>().unwrap(), code);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_readable_names() {
+        assert_eq!("space".parse::<KeyCode>().unwrap(), KeyCode::SpaceBar);
+        assert_eq!("PgDn".parse::<KeyCode>().unwrap(), KeyCode::PageDown);
+        assert_eq!("7".parse::<KeyCode>().unwrap(), KeyCode::Seven);
+        assert_eq!(KeyCode::NumSeven.to_string(), "Num7");
+        assert_eq!(KeyCode::LeftArrow.to_string(), "Left");
+        assert!("Hyper".parse::<KeyCode>().is_err());
+    }
 }
@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use iced_native::keyboard::Modifiers as IcedModifiers;
+use serde::{Deserialize, Serialize};
+
+use super::keycode::KeyCode;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Modifiers {
+    pub control: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub logo: bool,
+}
+
+impl From<IcedModifiers> for Modifiers {
+    fn from(modifiers: IcedModifiers) -> Self {
+        Modifiers {
+            control: modifiers.control,
+            alt: modifiers.alt,
+            shift: modifiers.shift,
+            logo: modifiers.logo,
+        }
+    }
+}
+
+// A key together with the modifiers that have to be held, written as "Ctrl+Shift+Right"
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct KeyBinding {
+    pub key: KeyCode,
+    pub modifiers: Modifiers,
+}
+
+impl KeyBinding {
+    pub fn new(key: KeyCode, modifiers: Modifiers) -> KeyBinding {
+        KeyBinding { key, modifiers }
+    }
+
+    pub fn key(key: KeyCode) -> KeyBinding {
+        KeyBinding::new(key, Modifiers::default())
+    }
+}
+
+impl Display for KeyBinding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let m = self.modifiers;
+        for (held, name) in [(m.control, "Ctrl"), (m.alt, "Alt"), (m.shift, "Shift"), (m.logo, "Logo")] {
+            if held {
+                write!(f, "{}+", name)?;
+            }
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+impl FromStr for KeyBinding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+        let key = parts.pop().filter(|key| !key.is_empty()).ok_or_else(|| anyhow!("Key binding '{}' has no key", s))?;
+
+        let mut modifiers = Modifiers::default();
+        for part in parts {
+            let held = match part.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => &mut modifiers.control,
+                "alt" => &mut modifiers.alt,
+                "shift" => &mut modifiers.shift,
+                "logo" | "super" | "win" | "cmd" => &mut modifiers.logo,
+                _ => return Err(anyhow!("Unknown modifier '{}' in key binding '{}'", part, s)),
+            };
+            *held = true;
+        }
+
+        Ok(KeyBinding::new(key.parse()?, modifiers))
+    }
+}
+
+impl TryFrom<String> for KeyBinding {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<KeyBinding> for String {
+    fn from(binding: KeyBinding) -> Self {
+        binding.to_string()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Action {
+    NextSong,
+    PreviousSong,
+    // Songs are counted from 1, like in the setlist view
+    JumpToSong(usize),
+    Play,
+    Pause,
+    Toggle,
+    TapTempo,
+    BpmUp,
+    BpmDown,
+    VolumeUp,
+    VolumeDown,
+}
+
+impl Action {
+    // Everything that can be bound without a parameter
+    pub const ALL: [Action; 10] = [
+        Action::NextSong,
+        Action::PreviousSong,
+        Action::Play,
+        Action::Pause,
+        Action::Toggle,
+        Action::TapTempo,
+        Action::BpmUp,
+        Action::BpmDown,
+        Action::VolumeUp,
+        Action::VolumeDown,
+    ];
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::NextSong => f.write_str("Next song"),
+            Action::PreviousSong => f.write_str("Previous song"),
+            Action::JumpToSong(song) => write!(f, "Song {}", song),
+            Action::Play => f.write_str("Play"),
+            Action::Pause => f.write_str("Pause"),
+            Action::Toggle => f.write_str("Play/Pause"),
+            Action::TapTempo => f.write_str("Tap tempo"),
+            Action::BpmUp => f.write_str("BPM up"),
+            Action::BpmDown => f.write_str("BPM down"),
+            Action::VolumeUp => f.write_str("Volume up"),
+            Action::VolumeDown => f.write_str("Volume down"),
+        }
+    }
+}
+
+// Configured bindings replace the defaults completely
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct KeyMap {
+    bindings: BTreeMap<KeyBinding, Action>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        use KeyCode::*;
+        let bindings = [
+            (LeftArrow, Action::PreviousSong),
+            (RightArrow, Action::NextSong),
+            (PageUp, Action::PreviousSong),
+            (PageDown, Action::NextSong),
+            (SpaceBar, Action::Toggle),
+            (UpArrow, Action::BpmUp),
+            (DownArrow, Action::BpmDown),
+            (T, Action::TapTempo),
+        ];
+        KeyMap {
+            bindings: bindings.into_iter().map(|(key, action)| (KeyBinding::key(key), action)).collect(),
+        }
+    }
+}
+
+impl KeyMap {
+    pub fn action(&self, binding: &KeyBinding) -> Option<Action> {
+        self.bindings.get(binding).copied()
+    }
+
+    pub fn bind(&mut self, binding: KeyBinding, action: Action) {
+        self.bindings.insert(binding, action);
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        for (binding, action) in &self.bindings {
+            if let Action::JumpToSong(0) = action {
+                return Err(anyhow!("'{}' jumps to song 0, songs are counted from 1", binding));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bindings() {
+        let binding: KeyBinding = "ctrl+Shift+Right".parse().unwrap();
+        assert_eq!(binding.key, KeyCode::RightArrow);
+        assert!(binding.modifiers.control && binding.modifiers.shift);
+        assert!(!binding.modifiers.alt && !binding.modifiers.logo);
+        assert_eq!(binding.to_string(), "Ctrl+Shift+Right");
+
+        assert!("Hyper+A".parse::<KeyBinding>().is_err());
+        assert!("Ctrl+".parse::<KeyBinding>().is_err());
+    }
+
+    #[test]
+    fn keymap_from_yaml() {
+        let map: KeyMap = serde_yaml::from_str("Space: Toggle\nCtrl+Up: BpmUp\n1:\n  JumpToSong: 1\n").unwrap();
+        assert_eq!(map.action(&KeyBinding::key(KeyCode::SpaceBar)), Some(Action::Toggle));
+        assert_eq!(map.action(&"Ctrl+Up".parse().unwrap()), Some(Action::BpmUp));
+        assert_eq!(map.action(&KeyBinding::key(KeyCode::UpArrow)), None);
+        assert_eq!(map.action(&KeyBinding::key(KeyCode::One)), Some(Action::JumpToSong(1)));
+
+        let yaml = serde_yaml::to_string(&map).unwrap();
+        assert_eq!(serde_yaml::from_str::<KeyMap>(&yaml).unwrap(), map);
+    }
+
+    #[test]
+    fn rejects_song_zero() {
+        let mut map = KeyMap::default();
+        assert!(map.validate().is_ok());
+        map.bind(KeyBinding::key(KeyCode::Zero), Action::JumpToSong(0));
+        assert!(map.validate().is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use evdev::{Device, InputEventKind, Key};
+use log::{debug, error, info, warn};
+
+use super::keycode::{KeyCode, DWORD};
+use super::keymap::Modifiers;
+use super::Message;
+use super::OsInput;
+
+const INPUT_DIR: &str = "/dev/input";
+
+// Codes from 0x100 on are mouse, joystick and gamepad buttons
+const FIRST_BUTTON: u16 = 0x100;
+
+// evdev values of key events
+const KEY_RELEASE: i32 = 0;
+const KEY_PRESS: i32 = 1;
+
+const CONTROL: u8 = 1;
+const ALT: u8 = 1 << 1;
+const SHIFT: u8 = 1 << 2;
+const LOGO: u8 = 1 << 3;
+
+// Modifiers held on any of the keyboards
+static HELD: AtomicU8 = AtomicU8::new(0);
+
+// Readers that are still reading, keys are global until the last one stopped
+static LIVE_READERS: AtomicUsize = AtomicUsize::new(0);
+
+// Reads key presses from every keyboard in /dev/input, no matter which window has focus.
+// This needs read access to the event devices, which usually means being in the `input` group.
+pub(super) struct LinuxInput {
+    running: Arc<AtomicBool>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+impl OsInput for LinuxInput {
+    fn new() -> LinuxInput {
+        let running = Arc::new(AtomicBool::new(true));
+        // Set before the readers start, so one that fails right away clears it again
+        super::set_global(true);
+        let readers: Vec<JoinHandle<()>> = evdev::enumerate()
+            .filter(|(_, device)| is_keyboard(device))
+            .filter_map(|(path, device)| spawn_reader(path, device, running.clone()))
+            .collect();
+
+        if readers.is_empty() {
+            super::set_global(false);
+            if event_device_count() > 0 {
+                warn!(
+                    "Could not open any keyboard in {} (is the user in the 'input' group?), \
+                     keys are only handled while the window is focused",
+                    INPUT_DIR
+                );
+            } else {
+                warn!("No keyboards found, keys are only handled while the window is focused");
+            }
+        }
+
+        LinuxInput { running, readers }
+    }
+
+    fn on_shutdown(&self) {
+        // The readers block on the devices, so they are not joined and exit with the process
+        self.running.store(false, Ordering::SeqCst);
+        debug!("Stopping {} keyboard reader(s)", self.readers.len());
+    }
+}
+
+fn is_keyboard(device: &Device) -> bool {
+    device
+        .supported_keys()
+        .map_or(false, |keys| keys.iter().any(|key| key.code() < FIRST_BUTTON))
+}
+
+fn event_device_count() -> usize {
+    std::fs::read_dir(INPUT_DIR)
+        .map(|dir| {
+            dir.filter_map(Result::ok)
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with("event"))
+                .count()
+        })
+        .unwrap_or(0)
+}
+
+fn stop_reader() {
+    if LIVE_READERS.fetch_sub(1, Ordering::SeqCst) == 1 {
+        warn!("No keyboard is read anymore, keys are only handled while the window is focused");
+        super::set_global(false);
+    }
+}
+
+fn spawn_reader(path: PathBuf, mut device: Device, running: Arc<AtomicBool>) -> Option<JoinHandle<()>> {
+    let name = device.name().unwrap_or("unknown").to_owned();
+    let location = path.display().to_string();
+    LIVE_READERS.fetch_add(1, Ordering::SeqCst);
+    let spawned = std::thread::Builder::new()
+        .name(format!("evdev {}", location))
+        .spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                let events = match device.fetch_events() {
+                    Ok(events) => events,
+                    Err(e) => {
+                        warn!("Stopped reading keyboard '{}' at {} ({})", name, location, e);
+                        stop_reader();
+                        return;
+                    }
+                };
+                for event in events {
+                    if let InputEventKind::Key(key) = event.kind() {
+                        match (event.value(), modifier(key)) {
+                            (KEY_PRESS, Some(bit)) => {
+                                HELD.fetch_or(bit, Ordering::SeqCst);
+                            }
+                            (KEY_RELEASE, Some(bit)) => {
+                                HELD.fetch_and(!bit, Ordering::SeqCst);
+                            }
+                            _ => {}
+                        }
+                        if event.value() == KEY_PRESS && running.load(Ordering::SeqCst) {
+                            send(KeyCode::from(key));
+                        }
+                    }
+                }
+            }
+        });
+
+    match spawned {
+        Ok(handle) => {
+            info!("Listening to keyboard at {}", path.display());
+            Some(handle)
+        }
+        Err(e) => {
+            error!("Could not start keyboard reader for {} ({})", path.display(), e);
+            stop_reader();
+            None
+        }
+    }
+}
+
+fn modifier(key: Key) -> Option<u8> {
+    match key {
+        Key::KEY_LEFTCTRL | Key::KEY_RIGHTCTRL => Some(CONTROL),
+        Key::KEY_LEFTALT | Key::KEY_RIGHTALT => Some(ALT),
+        Key::KEY_LEFTSHIFT | Key::KEY_RIGHTSHIFT => Some(SHIFT),
+        Key::KEY_LEFTMETA | Key::KEY_RIGHTMETA => Some(LOGO),
+        _ => None,
+    }
+}
+
+fn modifiers() -> Modifiers {
+    let held = HELD.load(Ordering::SeqCst);
+    Modifiers {
+        control: held & CONTROL != 0,
+        alt: held & ALT != 0,
+        shift: held & SHIFT != 0,
+        logo: held & LOGO != 0,
+    }
+}
+
+fn send(code: KeyCode) {
+    if let Some(sender) = super::SENDER.get() {
+        if let Err(e) = sender.get().unbounded_send(Message::KeyEvent(code, modifiers())) {
+            error!("Failed to send Message! ({:?})", e);
+        }
+    }
+}
+
+impl From<Key> for KeyCode {
+    fn from(key: Key) -> KeyCode {
+        use KeyCode::*;
+        match key {
+            Key::KEY_ESC => Escape,
+            Key::KEY_1 => One,
+            Key::KEY_2 => Two,
+            Key::KEY_3 => Three,
+            Key::KEY_4 => Four,
+            Key::KEY_5 => Five,
+            Key::KEY_6 => Six,
+            Key::KEY_7 => Seven,
+            Key::KEY_8 => Eight,
+            Key::KEY_9 => Nine,
+            Key::KEY_0 => Zero,
+            Key::KEY_MINUS => OemMinus,
+            Key::KEY_EQUAL => OemPlus,
+            Key::KEY_BACKSPACE => Backspace,
+            Key::KEY_TAB => Tab,
+            Key::KEY_Q => Q,
+            Key::KEY_W => W,
+            Key::KEY_E => E,
+            Key::KEY_R => R,
+            Key::KEY_T => T,
+            Key::KEY_Y => Y,
+            Key::KEY_U => U,
+            Key::KEY_I => I,
+            Key::KEY_O => O,
+            Key::KEY_P => P,
+            Key::KEY_LEFTBRACE => Oem4,
+            Key::KEY_RIGHTBRACE => Oem6,
+            Key::KEY_ENTER => Enter,
+            Key::KEY_LEFTCTRL => LeftControl,
+            Key::KEY_A => A,
+            Key::KEY_S => S,
+            Key::KEY_D => D,
+            Key::KEY_F => F,
+            Key::KEY_G => G,
+            Key::KEY_H => H,
+            Key::KEY_J => J,
+            Key::KEY_K => K,
+            Key::KEY_L => L,
+            Key::KEY_SEMICOLON => Oem1,
+            Key::KEY_APOSTROPHE => Oem7,
+            Key::KEY_GRAVE => Oem3,
+            Key::KEY_LEFTSHIFT => LeftShift,
+            Key::KEY_BACKSLASH => Oem5,
+            Key::KEY_Z => Z,
+            Key::KEY_X => X,
+            Key::KEY_C => C,
+            Key::KEY_V => V,
+            Key::KEY_B => B,
+            Key::KEY_N => N,
+            Key::KEY_M => M,
+            Key::KEY_COMMA => OemComma,
+            Key::KEY_DOT => OemPeriod,
+            Key::KEY_SLASH => Oem2,
+            Key::KEY_RIGHTSHIFT => RightShift,
+            Key::KEY_KPASTERISK => Multiply,
+            Key::KEY_LEFTALT => LeftMenu,
+            Key::KEY_SPACE => SpaceBar,
+            Key::KEY_CAPSLOCK => CapsLock,
+            Key::KEY_F1 => F1,
+            Key::KEY_F2 => F2,
+            Key::KEY_F3 => F3,
+            Key::KEY_F4 => F4,
+            Key::KEY_F5 => F5,
+            Key::KEY_F6 => F6,
+            Key::KEY_F7 => F7,
+            Key::KEY_F8 => F8,
+            Key::KEY_F9 => F9,
+            Key::KEY_F10 => F10,
+            Key::KEY_NUMLOCK => NumLock,
+            Key::KEY_SCROLLLOCK => ScrollLock,
+            Key::KEY_KP7 => NumSeven,
+            Key::KEY_KP8 => NumEight,
+            Key::KEY_KP9 => NumNine,
+            Key::KEY_KPMINUS => Subtract,
+            Key::KEY_KP4 => NumFour,
+            Key::KEY_KP5 => NumFive,
+            Key::KEY_KP6 => NumSix,
+            Key::KEY_KPPLUS => Add,
+            Key::KEY_KP1 => NumOne,
+            Key::KEY_KP2 => NumTwo,
+            Key::KEY_KP3 => NumThree,
+            Key::KEY_KP0 => NumZero,
+            Key::KEY_KPDOT => Decimal,
+            Key::KEY_102ND => Oem102,
+            Key::KEY_F11 => F11,
+            Key::KEY_F12 => F12,
+            Key::KEY_KPENTER => Enter,
+            Key::KEY_RIGHTCTRL => RightControl,
+            Key::KEY_KPSLASH => Divide,
+            Key::KEY_SYSRQ => PrintScreen,
+            Key::KEY_RIGHTALT => RightMenu,
+            Key::KEY_HOME => Home,
+            Key::KEY_UP => UpArrow,
+            Key::KEY_PAGEUP => PageUp,
+            Key::KEY_LEFT => LeftArrow,
+            Key::KEY_RIGHT => RightArrow,
+            Key::KEY_END => End,
+            Key::KEY_DOWN => DownArrow,
+            Key::KEY_PAGEDOWN => PageDown,
+            Key::KEY_INSERT => Insert,
+            Key::KEY_DELETE => Delete,
+            Key::KEY_MUTE => VolumeMute,
+            Key::KEY_VOLUMEDOWN => VolumeDown,
+            Key::KEY_VOLUMEUP => VolumeUp,
+            Key::KEY_KPCOMMA => Separator,
+            Key::KEY_PAUSE => Pause,
+            Key::KEY_LEFTMETA => LeftWindows,
+            Key::KEY_RIGHTMETA => RightWindows,
+            Key::KEY_COMPOSE => Applications,
+            Key::KEY_STOP => BrowserStop,
+            Key::KEY_HELP => Help,
+            Key::KEY_SLEEP => ComputerSleep,
+            Key::KEY_MAIL => StartMail,
+            Key::KEY_BACK => BrowserBack,
+            Key::KEY_FORWARD => BrowserForward,
+            Key::KEY_NEXTSONG => NextTrack,
+            Key::KEY_PLAYPAUSE => PlayPauseMedia,
+            Key::KEY_PREVIOUSSONG => PreviousTrack,
+            Key::KEY_STOPCD => StopMedia,
+            Key::KEY_HOMEPAGE => BrowserStartAndHome,
+            Key::KEY_REFRESH => BrowserRefresh,
+            Key::KEY_F13 => F13,
+            Key::KEY_F14 => F14,
+            Key::KEY_F15 => F15,
+            Key::KEY_F16 => F16,
+            Key::KEY_F17 => F17,
+            Key::KEY_F18 => F18,
+            Key::KEY_F19 => F19,
+            Key::KEY_F20 => F20,
+            Key::KEY_F21 => F21,
+            Key::KEY_F22 => F22,
+            Key::KEY_F23 => F23,
+            Key::KEY_F24 => F24,
+            Key::KEY_PLAYCD => Play,
+            Key::KEY_PRINT => Print,
+            Key::KEY_SEARCH => BrowserSearch,
+            Key::KEY_MEDIA => SelectMedia,
+            Key::KEY_BOOKMARKS => BrowserFavorites,
+            Key::KEY_SELECT => Select,
+            Key::KEY_CLEAR => Clear,
+            Key::KEY_ZOOM => Zoom,
+            Key::KEY_CANCEL => Cancel,
+            _ => NotImplemented(key.code() as DWORD),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_stage_keys() {
+        assert_eq!(KeyCode::from(Key::KEY_SPACE), KeyCode::SpaceBar);
+        assert_eq!(KeyCode::from(Key::KEY_RIGHT), KeyCode::RightArrow);
+        assert_eq!(KeyCode::from(Key::KEY_KP5), KeyCode::NumFive);
+        assert_eq!(KeyCode::from(Key::KEY_PAGEDOWN), KeyCode::PageDown);
+        assert_eq!(KeyCode::from(Key::KEY_FN), KeyCode::NotImplemented(Key::KEY_FN.code() as DWORD));
+    }
+}
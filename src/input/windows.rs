@@ -5,11 +5,12 @@ use rust_win32error::Win32Error;
 use std::process::exit;
 use winapi::shared::windef::HHOOK;
 use winapi::um::winuser::{
-    CallNextHookEx, SetWindowsHookExA, UnhookWindowsHookEx, LPKBDLLHOOKSTRUCT, WH_KEYBOARD_LL,
-    WM_KEYDOWN,
+    CallNextHookEx, GetAsyncKeyState, SetWindowsHookExA, UnhookWindowsHookEx, LPKBDLLHOOKSTRUCT,
+    VK_CONTROL, VK_LWIN, VK_MENU, VK_RWIN, VK_SHIFT, WH_KEYBOARD_LL, WM_KEYDOWN,
 };
 
 use super::keycode::KeyCode;
+use super::keymap::Modifiers;
 use super::Message;
 use super::OsInput;
 
@@ -28,6 +29,7 @@ impl OsInput for WindowsInput {
             exit(2);
         } else {
             trace!("Created hook -> {:?}", hook_id);
+            super::set_global(true);
         }
 
         WindowsInput { hook_id }
@@ -52,7 +54,7 @@ unsafe extern "system" fn hook_callback(code: i32, w_param: usize, l_param: isiz
         if let Some(sender) = super::SENDER.get() {
             if let Err(e) = sender
                 .get()
-                .unbounded_send(Message::KeyEvent(KeyCode::from(s.vkCode)))
+                .unbounded_send(Message::KeyEvent(KeyCode::from(s.vkCode), modifiers()))
             {
                 eprintln!("Failed to send Message! ({:?})", e);
             }
@@ -60,3 +62,16 @@ unsafe extern "system" fn hook_callback(code: i32, w_param: usize, l_param: isiz
     }
     return CallNextHookEx(std::ptr::null_mut(), code, w_param, l_param);
 }
+
+fn held(vk: i32) -> bool {
+    unsafe { GetAsyncKeyState(vk) as u16 & 0x8000 != 0 }
+}
+
+fn modifiers() -> Modifiers {
+    Modifiers {
+        control: held(VK_CONTROL),
+        alt: held(VK_MENU),
+        shift: held(VK_SHIFT),
+        logo: held(VK_LWIN) || held(VK_RWIN),
+    }
+}
@@ -1,22 +1,20 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ops::Deref;
-
-use rust_win32error::*;
-use winapi::um::winuser::{
-    CallNextHookEx, SetWindowsHookExA, UnhookWindowsHookEx, LPKBDLLHOOKSTRUCT, WH_KEYBOARD_LL,
-    WM_KEYDOWN,
-};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 // TODO https://www.hackster.io/HiAmadeus/analog-inputs-on-windows-10-raspberry-pi-using-adc-493ab9
 
 use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
-use iced::{executor, Application, Button, Clipboard, Command, Container, Element, HorizontalAlignment, Length, Row, Scrollable, Settings, Text, TextInput, VerticalAlignment, Space, PickList};
+use iced::{executor, Application, Button, Clipboard, Color, Command, Container, Element, HorizontalAlignment, Length, Row, Scrollable, Settings, Text, TextInput, VerticalAlignment, Space, PickList};
 use iced_futures::{futures, BoxStream};
 use iced_native::subscription::Subscription;
 
 use iced_native::event::Status;
 use iced_native::{Align, Column, Slider};
-use log::{debug, error};
+use log::{debug, error, trace};
 use std::process::exit;
 use iced::futures::future::err;
 
@@ -32,9 +30,18 @@ mod ui;
 mod id;
 mod audio;
 mod song_listing;
+mod config;
+mod midi;
+mod tap;
+mod cli;
+mod headless;
+mod remote;
+mod transport;
 
+use clap::Parser;
 use input::OsInput;
 use audio::AudioMessage;
+use audio::tempo::Bpm;
 use crate::audio::AudioHandle;
 use crate::song_listing::FileSongListing;
 
@@ -44,15 +51,35 @@ lazy_static! {
     static ref SUPPORTED_HOSTS: Vec<audio::HostSelector> = audio::HostSelector::supported();
 }
 
+// How much the BPM and volume keys change per press
+const VOLUME_STEP: f32 = 50.;
+// Resizing sends a stream of events, the size is saved once it stopped changing for this long
+const WINDOW_SAVE_DELAY: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 struct Example {
     kb_worker: KbWorker,
     audio_handle: AudioHandle,
+    midi_clock: Option<midi::clock::MidiClock>,
+    midi_sync: Option<midi::sync::MidiSync>,
+    midi_controller: Option<midi::controller::MidiController>,
+    remotes: remote::Remotes,
+    // The next MIDI trigger is bound to this action
+    learning: Option<input::keymap::Action>,
+    learn_action: input::keymap::Action,
+    learn_picklist: iced::pick_list::State<input::keymap::Action>,
+    learn_button: iced::button::State,
+    transport: transport::Transport,
+    tap_button: iced::button::State,
+    // Tempo of the incoming MIDI clock in quarter notes per minute
+    sync_tempo: Option<f64>,
+    sync_lost: bool,
     slider: iced::slider::State,
     scrollable_state: iced::scrollable::State,
     slider_value: f32,
     songs: Vec<ui::SongListing>,
-    current: usize,
+    // Bar the engine is playing, 0 before the song started
+    bar: u32,
     play_button: iced::button::State,
     pause_button: iced::button::State,
     host_picklist: iced::pick_list::State<audio::HostSelector>,
@@ -61,61 +88,218 @@ struct Example {
     selected_device: Option<String>,
     supported_devices: Vec<String>,
     settings_apply_button: iced::button::State,
+    error_message: Option<String>,
+    config: config::ConfigFile,
+    setlist_path: Option<PathBuf>,
+    open_setlist_button: iced::button::State,
+    save_setlist_button: iced::button::State,
+    import_midi_button: iced::button::State,
+    export_midi_button: iced::button::State,
+    export_setlist_midi_button: iced::button::State,
+    recent_picklist: iced::pick_list::State<ui::RecentSetlist>,
+    recent_setlists: Vec<ui::RecentSetlist>,
+    // The window was resized since the config was last saved
+    window_resized: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Flags {
+    config: config::ConfigFile,
+    // Setlist given on the command line, opened instead of the last one
+    setlist: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
     Ready(UnboundedSender<Message>),
-    KeyEvent(input::keycode::KeyCode),
+    KeyEvent(input::keycode::KeyCode, input::keymap::Modifiers),
+    // Sent by remotes, does what the bound key would do
+    Action(input::keymap::Action),
     VolumeChanged(f32),
     AudioMessage(audio::AudioMessage),
     HostSelection(audio::HostSelector),
     DeviceSelection(String),
     ApplySettings,
+    WindowResized(u32, u32),
+    WindowSettled,
+    SaveConfig,
+    OpenSetlist,
+    SaveSetlist,
+    ImportMidi,
+    ExportMidi,
+    ExportSetlistMidi,
+    RecentSetlistSelected(ui::RecentSetlist),
+    ClockSync(midi::sync::SyncEvent),
+    MidiTrigger(midi::controller::MidiTrigger),
+    LearnActionSelected(input::keymap::Action),
+    ToggleLearn,
+    Tap,
+    BarChanged(u32),
+    Quit,
     None
 }
 
 impl Application for Example {
     type Executor = executor::Default;
     type Message = Message;
-    type Flags = ();
+    type Flags = Flags;
 
-    fn new(_: Self::Flags) -> (Self, Command<Self::Message>) {
-        let mut songs = Vec::new();
+    fn new(flags: Self::Flags) -> (Self, Command<Self::Message>) {
+        let config = flags.config;
+        let mut error_message = config.error.clone();
 
-        for i in 0..30 {
-            songs.push(
-                ui::SongListing::new(
-                    &format!("Song {}", i),
-                    rand::thread_rng().gen_range(50..200)
-                )
-            )
+        let output_settings = audio::OutputSettings {
+            host: config.config.host(),
+            device: config.config.audio.device.clone(),
+            sample_rate: config.config.audio.sample_rate,
+            buffer_size: config.config.audio.buffer_size,
+            routing: config.config.audio.routing.clone(),
+            jack: config.config.jack.clone(),
+        };
+        let mut audio_handle = audio::setup(output_settings.clone());
+        if audio_handle.device_name().is_none() && output_settings.device.is_some() {
+            error_message = Some(format!(
+                "Output device '{}' is not available, using the default device",
+                output_settings.device.as_ref().unwrap()
+            ));
+            if let Err(e) = audio_handle.rebuild(audio::OutputSettings { device: None, ..output_settings }) {
+                error_message = Some(format!("{:#}", e));
+            }
+        }
+        if let Some(problem) = audio_handle.routing_problem() {
+            error_message = Some(problem.to_string());
         }
 
-        (
-            Example {
-                kb_worker: KbWorker::new(),
-                audio_handle: audio::setup(),
-                slider: iced::slider::State::new(),
-                scrollable_state: iced::scrollable::State::new(),
-                slider_value: 0.0,
-                songs,
-                current: 0,
-                play_button: iced::button::State::new(),
-                pause_button: iced::button::State::new(),
-                host_picklist: iced::pick_list::State::<audio::HostSelector>::default(),
-                selected_host: *SUPPORTED_HOSTS.first().unwrap(),
-                device_picklist: iced::pick_list::State::<String>::default(),
-                supported_devices: SUPPORTED_HOSTS.first().unwrap().supported_output_devices(),
-                selected_device: None,
-                settings_apply_button: iced::button::State::new(),
-            },
-            Command::none(),
-        )
+        let volume = config.config.audio.volume;
+        audio_handle.send(AudioMessage::SetVolume(volume));
+        if let Err(e) = audio_handle.apply_sound(&config.config.audio, &config.dir()) {
+            error!("Could not set up the click sound ({:?})", e);
+            error_message = Some(format!("{:#}", e));
+        }
+
+        let midi_clock = if config.config.midi.clock_output {
+            match midi::clock::MidiClock::open(&config.config.midi) {
+                Ok(clock) => {
+                    audio_handle.set_clock_sink(Some(clock.sink()));
+                    Some(clock)
+                }
+                Err(e) => {
+                    error!("Could not open MIDI clock output ({:?})", e);
+                    error_message = Some(format!("{:#}", e));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let midi_sync = if config.config.midi.sync_input {
+            match midi::sync::MidiSync::open(&config.config.midi) {
+                Ok(sync) => {
+                    audio_handle.set_sync_source(Some(sync.source()));
+                    Some(sync)
+                }
+                Err(e) => {
+                    error!("Could not open MIDI sync input ({:?})", e);
+                    error_message = Some(format!("{:#}", e));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let midi_controller = if config.config.midi.controller_input {
+            match midi::controller::MidiController::open(&config.config.midi) {
+                Ok(controller) => Some(controller),
+                Err(e) => {
+                    error!("Could not open MIDI controller input ({:?})", e);
+                    error_message = Some(format!("{:#}", e));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let tap_config = &config.config.tap;
+        let tap_tempo = tap::TapTempo::new(tap_config.window, Duration::from_millis(tap_config.timeout_ms), tap_config.rounding);
+
+        let watched = audio::tempo::watch_position(audio_handle.position(), |bar| input::send(Message::BarChanged(bar)));
+        if let Err(e) = watched {
+            error!("Could not watch the playback position ({:?})", e);
+        }
+
+        let remotes = remote::Remotes::open(&config.config.remote, |e| {
+            error!("Could not start remote control ({:?})", e);
+            error_message = Some(format!("{:#}", e));
+        });
+        audio_handle.set_beat_sink(remotes.beat_sink());
+        let transport = transport::Transport::new(tap_tempo, midi_sync.is_some(), remotes.publisher());
+
+        let selected_host = audio_handle.host();
+        let selected_device = audio_handle.device_name().map(String::from);
+
+        let setlist = flags.setlist.or_else(|| config.config.ui.last_setlist.clone());
+
+        let mut example = Example {
+            kb_worker: KbWorker::new(),
+            audio_handle,
+            midi_clock,
+            midi_sync,
+            midi_controller,
+            remotes,
+            learning: None,
+            learn_action: input::keymap::Action::NextSong,
+            learn_picklist: iced::pick_list::State::<input::keymap::Action>::default(),
+            learn_button: iced::button::State::new(),
+            transport,
+            tap_button: iced::button::State::new(),
+            sync_tempo: None,
+            sync_lost: false,
+            slider: iced::slider::State::new(),
+            scrollable_state: iced::scrollable::State::new(),
+            slider_value: volume as f32,
+            songs: Vec::new(),
+            bar: 0,
+            play_button: iced::button::State::new(),
+            pause_button: iced::button::State::new(),
+            host_picklist: iced::pick_list::State::<audio::HostSelector>::default(),
+            selected_host,
+            device_picklist: iced::pick_list::State::<String>::default(),
+            supported_devices: selected_host.supported_output_devices(),
+            selected_device,
+            settings_apply_button: iced::button::State::new(),
+            error_message,
+            config,
+            setlist_path: None,
+            open_setlist_button: iced::button::State::new(),
+            save_setlist_button: iced::button::State::new(),
+            import_midi_button: iced::button::State::new(),
+            export_midi_button: iced::button::State::new(),
+            export_setlist_midi_button: iced::button::State::new(),
+            recent_picklist: iced::pick_list::State::<ui::RecentSetlist>::default(),
+            recent_setlists: Vec::new(),
+            window_resized: None,
+        };
+        example.update_recent_setlists();
+
+        // The window does not exist yet, so the error is shown in it instead of a dialog
+        if let Some(path) = setlist {
+            if let Err(e) = example.open_setlist(&path) {
+                error!("Could not open setlist ({:?})", e);
+                example.error_message = Some(format!("Could not open setlist: {:#}", e));
+            }
+        }
+
+        (example, Command::none())
     }
 
     fn title(&self) -> String {
-        format!("Metronome")
+        match self.setlist_path.as_ref().and_then(|p| p.file_stem()) {
+            Some(name) => format!("Metronome - {}", name.to_string_lossy()),
+            None => format!("Metronome"),
+        }
     }
 
     fn update(&mut self, message: Self::Message, _: &mut Clipboard) -> Command<Self::Message> {
@@ -123,15 +307,18 @@ impl Application for Example {
             Message::Ready(sender) => {
                 input::add_sender(sender);
             }
-            Message::KeyEvent(code) => {
-                self.handle_keystroke(code);
+            Message::KeyEvent(code, modifiers) => {
+                self.handle_keystroke(code, modifiers);
+            }
+            Message::Action(action) => {
+                self.perform(action);
             }
             Message::VolumeChanged(vol) => {
                 self.slider_value = vol;
                 self.audio_handle.send(AudioMessage::SetVolume(vol as u16))
             }
             Message::AudioMessage(msg) => {
-                self.audio_handle.send(msg)
+                self.transport.send(&mut self.audio_handle, msg)
             }
             Message::HostSelection(host) => {
                 self.selected_host = host;
@@ -144,10 +331,141 @@ impl Application for Example {
                 self.selected_device = Some(device);
             }
             Message::ApplySettings => {
-                if self.selected_device.is_some() {
-                    debug!("Applying settings... Host: {} Device: {}", self.selected_host, self.selected_device.as_ref().unwrap())
+                debug!("Applying settings... Host: {} Device: {:?}", self.selected_host, self.selected_device);
+                let settings = audio::OutputSettings {
+                    host: self.selected_host,
+                    device: self.selected_device.clone(),
+                    ..self.audio_handle.settings().clone()
+                };
+                match self.audio_handle.rebuild(settings) {
+                    Ok(()) => {
+                        self.error_message = self.audio_handle.routing_problem().map(String::from);
+                        // Only a device picked here replaces the configured one, not a fallback
+                        let settings = self.audio_handle.settings();
+                        self.config.config.audio.host = Some(settings.host.to_string());
+                        self.config.config.audio.device = settings.device.clone();
+                        self.save_config();
+                    }
+                    Err(e) => {
+                        error!("Could not apply audio settings ({:?})", e);
+                        self.error_message = Some(format!("{:#}", e));
+                    }
                 }
             }
+            Message::WindowResized(width, height) => {
+                self.config.config.ui.window = config::WindowGeometry {
+                    width: width.max(config::MIN_WINDOW_SIZE.0),
+                    height: height.max(config::MIN_WINDOW_SIZE.1),
+                };
+                if self.window_resized.replace(Instant::now()).is_none() {
+                    settle_window_after(WINDOW_SAVE_DELAY);
+                }
+            }
+            Message::WindowSettled => match self.window_resized {
+                Some(resized) if resized.elapsed() < WINDOW_SAVE_DELAY => {
+                    settle_window_after(WINDOW_SAVE_DELAY - resized.elapsed());
+                }
+                Some(_) => self.save_config(),
+                None => {}
+            },
+            Message::SaveConfig => {
+                self.save_config();
+            }
+            Message::OpenSetlist => {
+                let dialog = rfd::FileDialog::new().add_filter("Setlist", &["yaml", "yml"]);
+                let dialog = match self.setlist_path.as_ref().and_then(|p| p.parent()) {
+                    Some(dir) => dialog.set_directory(dir),
+                    None => dialog,
+                };
+                if let Some(path) = dialog.pick_file() {
+                    self.load_setlist(&path);
+                }
+            }
+            Message::SaveSetlist => {
+                let dialog = rfd::FileDialog::new().add_filter("Setlist", &["yaml", "yml"]);
+                let dialog = match &self.setlist_path {
+                    Some(path) => dialog
+                        .set_directory(path.parent().unwrap_or_else(|| Path::new(".")))
+                        .set_file_name(&path.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned())),
+                    None => dialog.set_file_name("setlist.yaml"),
+                };
+                if let Some(path) = dialog.save_file() {
+                    self.save_setlist(&path);
+                }
+            }
+            Message::ImportMidi => {
+                let dialog = rfd::FileDialog::new().add_filter("MIDI file", &["mid", "midi"]);
+                let dialog = match self.setlist_path.as_ref().and_then(|p| p.parent()) {
+                    Some(dir) => dialog.set_directory(dir),
+                    None => dialog,
+                };
+                if let Some(path) = dialog.pick_file() {
+                    self.import_midi(&path);
+                }
+            }
+            Message::ExportMidi => {
+                if let Some(song) = self.songs.get(self.transport.current()) {
+                    let dialog = rfd::FileDialog::new()
+                        .add_filter("MIDI file", &["mid"])
+                        .set_file_name(&format!("{}.mid", song.title()));
+                    let dialog = match self.setlist_path.as_ref().and_then(|p| p.parent()) {
+                        Some(dir) => dialog.set_directory(dir),
+                        None => dialog,
+                    };
+                    if let Some(path) = dialog.save_file() {
+                        self.export_midi(&path);
+                    }
+                }
+            }
+            Message::ExportSetlistMidi => {
+                let dialog = rfd::FileDialog::new().add_filter("MIDI file", &["mid"]);
+                let dialog = match &self.setlist_path {
+                    Some(path) => dialog
+                        .set_directory(path.parent().unwrap_or_else(|| Path::new(".")))
+                        .set_file_name(&format!("{}.mid", path.file_stem().map_or(String::from("setlist"), |n| n.to_string_lossy().into_owned()))),
+                    None => dialog.set_file_name("setlist.mid"),
+                };
+                if let Some(path) = dialog.save_file() {
+                    self.export_setlist_midi(&path);
+                }
+            }
+            Message::RecentSetlistSelected(recent) => {
+                self.load_setlist(&recent.0);
+            }
+            Message::ClockSync(event) => {
+                self.on_clock_sync(event);
+            }
+            Message::MidiTrigger(trigger) => {
+                self.handle_trigger(trigger);
+            }
+            Message::LearnActionSelected(action) => {
+                self.learn_action = action;
+                if self.learning.is_some() {
+                    self.learning = Some(action);
+                }
+            }
+            Message::BarChanged(bar) => {
+                self.bar = bar;
+                if let Some(problem) = self.midi_clock.as_ref().and_then(|c| c.problem()) {
+                    error!("{}", problem);
+                    self.error_message = Some(problem.to_string());
+                }
+            }
+            Message::Tap => {
+                self.perform(input::keymap::Action::TapTempo);
+            }
+            Message::ToggleLearn => {
+                self.learning = match self.learning {
+                    Some(_) => None,
+                    None => Some(self.learn_action),
+                };
+            }
+            Message::Quit => {
+                if self.window_resized.is_some() {
+                    self.save_config();
+                }
+                exit(0)
+            }
             Message::None => {}
         }
         Command::none()
@@ -156,23 +474,19 @@ impl Application for Example {
     fn subscription(&self) -> Subscription<Self::Message> {
         Subscription::batch([
             self.kb_worker.subscription(),
-            iced_native::subscription::events_with(|event, status| match status {
-                Status::Ignored => {
-                    if let iced_native::Event::Keyboard(
-                        iced_native::keyboard::Event::KeyPressed {
-                            key_code,
-                            modifiers: _,
-                        },
-                    ) = event
-                    {
-                        Some(key_code)
-                    } else {
-                        None
-                    }
+            iced_native::subscription::events_with(|event, status| match (event, status) {
+                (
+                    iced_native::Event::Keyboard(iced_native::keyboard::Event::KeyPressed {
+                        key_code,
+                        modifiers,
+                    }),
+                    Status::Ignored,
+                ) if !input::is_global() => Some(Message::KeyEvent(key_code.into(), modifiers.into())),
+                (iced_native::Event::Window(iced_native::window::Event::Resized { width, height }), _) => {
+                    Some(Message::WindowResized(width, height))
                 }
-                Status::Captured => None,
-            })
-            .map(|e| Message::KeyEvent(input::keycode::KeyCode::from(e))),
+                _ => None,
+            }),
         ])
     }
 
@@ -181,11 +495,15 @@ impl Application for Example {
             .height(Length::Units(170))
             .padding(20);
 
-        if let Some(song) = self.songs.get(self.current) {
+        if let Some(song) = self.songs.get(self.transport.current()) {
+            let bpm = match song.tempo_map() {
+                Some(map) if self.bar > 0 => map.at(self.bar).0.to_string(),
+                _ => song.bpm_str(""),
+            };
             tempo = tempo.push(Column::new()
                 .width(Length::FillPortion(70))
                 .push(
-                    Text::new(song.bpm_str(""))
+                    Text::new(bpm)
                         .size(100),
                 )
                 .push(
@@ -197,7 +515,7 @@ impl Application for Example {
             tempo = tempo.push(Column::new().width(Length::FillPortion(70)));
         }
 
-        if let Some(song) = self.songs.get(self.current + 1) {
+        if let Some(song) = self.songs.get(self.transport.current() + 1) {
             tempo = tempo.push(Column::new()
                .width(Length::FillPortion(30))
                 .push(
@@ -226,7 +544,7 @@ impl Application for Example {
             .width(Length::Fill)
             .spacing(10);
 
-        for song in self.songs.iter().skip(self.current + 1) {
+        for song in self.songs.iter().skip(self.transport.current() + 1) {
             scrollable = scrollable.push(song.element(Length::Units(30)));
         }
 
@@ -243,7 +561,12 @@ impl Application for Example {
                 0.0..=1000.0,
                 self.slider_value,
                 Message::VolumeChanged)
-                .width(Length::FillPortion(80))
+                .on_release(Message::SaveConfig)
+                .width(Length::FillPortion(70))
+            )
+            .push(Button::new(&mut self.tap_button, Text::new("Tap"))
+                .on_press(Message::Tap)
+                .width(Length::FillPortion(10))
             )
             .push(Button::new(&mut self.play_button, Text::new("Play"))
                 .on_press(Message::AudioMessage(audio::AudioMessage::Play))
@@ -270,12 +593,100 @@ impl Application for Example {
             .into();
 
 
-        let combined: Element<_> = Column::new()
+        let setlists: Element<_> = Row::new()
+            .padding(10)
+            .push(Button::new(&mut self.open_setlist_button, Text::new("Open"))
+                .on_press(Message::OpenSetlist)
+                .width(Length::FillPortion(10))
+            )
+            .push(Button::new(&mut self.save_setlist_button, Text::new("Save"))
+                .on_press(Message::SaveSetlist)
+                .width(Length::FillPortion(10))
+            )
+            .push(Button::new(&mut self.import_midi_button, Text::new("Import MIDI"))
+                .on_press(Message::ImportMidi)
+                .width(Length::FillPortion(15))
+            )
+            .push(Button::new(&mut self.export_midi_button, Text::new("Export MIDI"))
+                .on_press(Message::ExportMidi)
+                .width(Length::FillPortion(15))
+            )
+            .push(Button::new(&mut self.export_setlist_midi_button, Text::new("Export setlist"))
+                .on_press(Message::ExportSetlistMidi)
+                .width(Length::FillPortion(15))
+            )
+            .push(PickList::new(&mut self.recent_picklist, &self.recent_setlists, None, Message::RecentSetlistSelected)
+                .width(Length::FillPortion(35))
+            )
+            .spacing(10)
+            .height(Length::FillPortion(10))
+            .into();
+
+        let mut combined = Column::new()
             .push(tempo)
             .push(grid)
             .push(volume)
             .push(settings)
-            .into();
+            .push(setlists);
+
+        if let Some(map) = self.songs.get(self.transport.current()).and_then(|s| s.tempo_map()) {
+            let (_, meter) = map.at(self.bar);
+            let position = match (self.bar, map.section(self.bar)) {
+                (0, _) => format!("Ready, {}", meter),
+                (bar, Some(section)) => format!("{} - Bar {} - {}", section, bar, meter),
+                (bar, None) => format!("Bar {} - {}", bar, meter),
+            };
+            combined = combined.push(
+                Row::new()
+                    .padding(10)
+                    .push(Text::new(position).size(30))
+            );
+        }
+
+        if self.midi_sync.is_some() {
+            let status = match self.sync_tempo {
+                Some(tempo) => format!("MIDI sync: {:.1} BPM", tempo),
+                None => String::from("MIDI sync: waiting for clock"),
+            };
+            let mut row = Row::new()
+                .padding(10)
+                .spacing(20)
+                .push(Text::new(status));
+            if self.sync_lost {
+                row = row.push(Text::new("Sync lost").color(Color::from_rgb(0.8, 0.1, 0.1)));
+            }
+            combined = combined.push(row);
+        }
+
+        if self.midi_controller.is_some() {
+            let (label, status) = match self.learning {
+                Some(action) => ("Cancel", format!("Press a pedal or key on the controller for '{}'", action)),
+                None => ("Learn", String::from("MIDI control")),
+            };
+            combined = combined.push(
+                Row::new()
+                    .padding(10)
+                    .spacing(10)
+                    .push(PickList::new(&mut self.learn_picklist, &input::keymap::Action::ALL[..], Some(self.learn_action), Message::LearnActionSelected)
+                        .width(Length::FillPortion(30))
+                    )
+                    .push(Button::new(&mut self.learn_button, Text::new(label))
+                        .on_press(Message::ToggleLearn)
+                        .width(Length::FillPortion(10))
+                    )
+                    .push(Text::new(status).width(Length::FillPortion(60)))
+            );
+        }
+
+        if let Some(error) = &self.error_message {
+            combined = combined.push(
+                Row::new()
+                    .padding(10)
+                    .push(Text::new(error).color(Color::from_rgb(0.8, 0.1, 0.1)))
+            );
+        }
+
+        let combined: Element<_> = combined.into();
 
         Container::new(combined)
             .width(Length::Fill)
@@ -287,40 +698,235 @@ impl Application for Example {
 }
 
 impl Example {
-    fn handle_keystroke(&mut self, code: input::keycode::KeyCode) {
-        use input::keycode::KeyCode::*;
-        match code {
-            UpArrow => {
-                let song = ui::SongListing::new("Title", 128);
-                self.songs.push(song);
-            }
-            LeftArrow => {
-                self.current = self.current.saturating_sub(1);
-                self.apply_current();
-            }
-            RightArrow => {
-                if self.current < self.songs.len() - 1 {
-                    self.current += 1;
-                    self.apply_current();
+    fn handle_keystroke(&mut self, code: input::keycode::KeyCode, modifiers: input::keymap::Modifiers) {
+        let binding = input::keymap::KeyBinding::new(code, modifiers);
+        match self.config.config.keys.action(&binding) {
+            Some(action) => self.perform(action),
+            None => trace!("No action bound to {}", binding),
+        }
+    }
+
+    fn handle_trigger(&mut self, trigger: midi::controller::MidiTrigger) {
+        if let Some(action) = self.learning.take() {
+            debug!("Binding {} to {:?}", trigger, action);
+            self.config.config.midi.controls.bind(trigger, action);
+            self.save_config();
+            return;
+        }
+        match self.config.config.midi.controls.action(&trigger) {
+            Some(action) => self.perform(action),
+            None => trace!("No action bound to {}", trigger),
+        }
+    }
+
+    fn perform(&mut self, action: input::keymap::Action) {
+        use input::keymap::Action::*;
+        debug!("Performing {:?}", action);
+        match action {
+            VolumeUp => self.set_volume((self.slider_value + VOLUME_STEP).min(1000.)),
+            VolumeDown => self.set_volume((self.slider_value - VOLUME_STEP).max(0.)),
+            _ => {
+                let tapped = self.transport.perform(action, &self.songs, &mut self.audio_handle, self.midi_clock.as_ref());
+                if let Some(bpm) = tapped {
+                    self.on_tapped(bpm);
                 }
             }
-            SpaceBar => {
-                self.audio_handle.send(AudioMessage::Toggle)
+        }
+    }
+
+    // The engine follows the clock by itself, this only keeps track of what to show
+    fn on_clock_sync(&mut self, event: midi::sync::SyncEvent) {
+        use midi::sync::SyncEvent::*;
+        match event {
+            Start | Continue => self.sync_lost = false,
+            Tempo(tempo) => {
+                self.sync_tempo = Some(tempo);
+                self.sync_lost = false;
             }
-            _ => {}
+            Lost => self.sync_lost = true,
+            Stop | Sixteenth(_) => {}
         }
     }
 
-    fn apply_current(&mut self) {
-        self.audio_handle.send(AudioMessage::SetBpm(self.songs.get(self.current).unwrap().bpm().unwrap()))
+    fn on_tapped(&mut self, bpm: Bpm) {
+        if self.config.config.tap.update_song {
+            // Tapping must not throw away a tempo map
+            if let Some(song) = self.songs.get_mut(self.transport.current()).filter(|s| s.tempo_map().is_none()) {
+                song.set_bpm(bpm);
+            }
+        }
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.slider_value = volume;
+        self.audio_handle.send(AudioMessage::SetVolume(volume as u16));
+        self.save_config();
+    }
+
+    fn save_config(&mut self) {
+        self.window_resized = None;
+        self.config.config.audio.volume = self.slider_value as u16;
+
+        if let Err(e) = self.config.save() {
+            error!("Could not save config ({:?})", e);
+            self.error_message = Some(format!("{:#}", e));
+        }
     }
-}
 
-pub const fn bpm_to_ns(bpm: u128) -> u128 {
-    (60000 * 1000000) / bpm
+    fn show_error(&mut self, title: &str, error: anyhow::Error) {
+        error!("{} ({:?})", title, error);
+        let message = format!("{:#}", error);
+        self.remotes.publisher().publish(remote::RemoteEvent::Error { message: format!("{}: {}", title, message) });
+        ui::show_error_dialog(title, &message);
+        self.error_message = Some(message);
+    }
+
+    fn update_recent_setlists(&mut self) {
+        self.recent_setlists = self.config.config.ui.recent_setlists
+            .iter()
+            .cloned()
+            .map(ui::RecentSetlist)
+            .collect();
+    }
+
+    fn remember_setlist(&mut self, path: &Path) {
+        self.setlist_path = Some(path.to_path_buf());
+        self.config.config.remember_setlist(path);
+        self.update_recent_setlists();
+        self.save_config();
+    }
+
+    fn load_setlist(&mut self, path: &Path) {
+        if let Err(e) = self.open_setlist(path) {
+            self.show_error("Could not open setlist", e);
+        }
+    }
+
+    fn open_setlist(&mut self, path: &Path) -> Result<(), anyhow::Error> {
+        let songs = song_listing::load_setlist(path)?;
+        debug!("Loaded {} songs from {}", songs.len(), path.display());
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        self.songs = songs
+            .iter()
+            .map(|song| {
+                let mut listing = ui::SongListing::from(song);
+                listing.load_tempo_map(dir).map(|_| listing)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.transport.select(0, &self.songs, &mut self.audio_handle, self.midi_clock.as_ref());
+        self.remember_setlist(path);
+        Ok(())
+    }
+
+    // Adds the file as a song that follows its tempo map
+    fn import_midi(&mut self, path: &Path) {
+        let import = match midi::smf::load(path) {
+            Ok(import) => import,
+            Err(e) => return self.show_error("Could not import MIDI file", e),
+        };
+        // Tempo maps are stored relative to the setlist where possible
+        let file = self.setlist_path
+            .as_ref()
+            .and_then(|setlist| setlist.parent())
+            .and_then(|dir| path.strip_prefix(dir).ok())
+            .unwrap_or(path);
+        let mut song = ui::SongListing::from(&import.song_listing(&file.to_string_lossy()));
+        debug!("Imported '{}' with {} tempo changes from {}", song.title(), import.map.changes().len(), path.display());
+        if let Some(first) = import.moved.first() {
+            self.error_message = Some(match import.moved.len() {
+                1 => first.clone(),
+                n => format!("{} (and {} more changes inside bars)", first, n - 1),
+            });
+        }
+        song.set_tempo_map(Some(Arc::new(import.map)));
+
+        self.songs.push(song);
+        if self.songs.len() == 1 {
+            self.transport.select(0, &self.songs, &mut self.audio_handle, self.midi_clock.as_ref());
+        }
+    }
+
+    // Writes the click of the current song, for lining up a DAW session with the live rig
+    fn export_midi(&mut self, path: &Path) {
+        let song = match self.songs.get(self.transport.current()) {
+            Some(song) => song,
+            None => return,
+        };
+        let options = midi::smf::ClickExport {
+            meter: self.audio_handle.state().time_signature,
+            ..midi::smf::ClickExport::default()
+        };
+        let exported = FileSongListing::try_from(song)
+            .and_then(|file| midi::smf::save(path, &file, song.tempo_map().map(|m| m.as_ref()), &options));
+        match exported {
+            Ok(()) => debug!("Exported the click of '{}' to {}", song.title(), path.display()),
+            Err(e) => self.show_error("Could not export MIDI file", e),
+        }
+    }
+
+    // Writes the clicks of all songs into one file, each song starts with a marker of its title
+    fn export_setlist_midi(&mut self, path: &Path) {
+        let exported = self.songs
+            .iter()
+            .map(|song| FileSongListing::try_from(song).map(|file| (file, song.tempo_map().map(|m| m.as_ref()))))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|songs| midi::smf::save_setlist(path, &songs, &midi::smf::ClickExport::default()));
+        match exported {
+            Ok(()) => debug!("Exported the click of {} songs to {}", self.songs.len(), path.display()),
+            Err(e) => self.show_error("Could not export MIDI file", e),
+        }
+    }
+
+    fn save_setlist(&mut self, path: &Path) {
+        // Tempo maps are relative to the setlist, they have to resolve from its new directory
+        let from = self.setlist_path.as_ref().and_then(|p| p.parent()).unwrap_or_else(|| Path::new("")).to_path_buf();
+        let to = path.parent().unwrap_or_else(|| Path::new(""));
+        let saved = self.songs
+            .iter()
+            .map(|song| FileSongListing::try_from(song).map(|mut file| {
+                file.rebase_tempo_map(&from, to);
+                file
+            }))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|songs| song_listing::save_setlist(path, &songs));
+        match saved {
+            Ok(()) => {
+                for song in &mut self.songs {
+                    song.rebase_tempo_map(&from, to);
+                }
+                debug!("Saved setlist to {}", path.display());
+                self.remember_setlist(path);
+            }
+            Err(e) => self.show_error("Could not save setlist", e),
+        }
+    }
 }
 
 fn main() {
+    let cli = cli::Cli::parse();
+    let level = match cli.verbose {
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+
+    match cli.command {
+        // Commands keep stdout for their output
+        Some(command) => {
+            setup_logging(level, fern::Output::stderr("\n"));
+            if let Err(e) = cli::run(command, config::ConfigFile::load()) {
+                error!("{:#}", e);
+                exit(1);
+            }
+        }
+        None => {
+            setup_logging(log::LevelFilter::Trace, fern::Output::stdout("\n"));
+            run_window(cli.setlist);
+        }
+    }
+}
+
+fn setup_logging(level: log::LevelFilter, output: fern::Output) {
     fern::Dispatch::new()
         .filter(|metadata| {
             metadata.target().starts_with("metronome")
@@ -336,27 +942,28 @@ fn main() {
                 message
             ))
         })
-        .level(log::LevelFilter::Trace)
-        .chain(std::io::stdout())
+        .level(level)
+        .chain(output)
         .apply().unwrap();
+}
 
-    let mut songs = Vec::new();
-    for _ in 0..100 {
-        songs.push(song_listing::FileSongListing::random());
-    }
-    let yaml = serde_yaml::to_string(&songs).unwrap();
-    debug!("{}", yaml);
-    let songs2: Vec<song_listing::FileSongListing> = serde_yaml::from_str(&yaml).unwrap();
-    for song in songs2 {
-        debug!("{}", song.title());
-    }
-    // return;
+fn settle_window_after(wait: Duration) {
+    std::thread::spawn(move || {
+        std::thread::sleep(wait);
+        input::send(Message::WindowSettled);
+    });
+}
 
+fn run_window(setlist: Option<PathBuf>) {
     let input_handler = input::init();
 
-    let mut settings = Settings::default();
+    let config = config::ConfigFile::load();
+    let window = config.config.ui.window;
+
+    let mut settings = Settings::with_flags(Flags { config, setlist });
     settings.window = Default::default();
-    settings.window.min_size = Some((600, 400));
+    settings.window.size = (window.width, window.height);
+    settings.window.min_size = Some(config::MIN_WINDOW_SIZE);
 
     if let Err(e) = Example::run(settings) {
         error!("Application failed! ({:?})", e);
@@ -0,0 +1,125 @@
+use anyhow::anyhow;
+use log::info;
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+
+pub mod clock;
+pub mod controller;
+pub mod smf;
+pub mod sync;
+
+const CLIENT_NAME: &str = "Metronome";
+
+// System real time and common messages
+pub const TIMING_CLOCK: u8 = 0xF8;
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+pub const SONG_POSITION: u8 = 0xF2;
+pub const PROGRAM_CHANGE: u8 = 0xC0;
+
+// Connects to the first output port whose name contains `port`.
+// Without a port name a virtual port is created that other programs can connect to.
+pub fn open_output(port: Option<&str>, virtual_name: &str) -> Result<MidiOutputConnection, anyhow::Error> {
+    let output = MidiOutput::new(CLIENT_NAME)?;
+
+    let port = match port {
+        Some(port) => port,
+        None => return open_virtual_output(output, virtual_name),
+    };
+
+    let needle = port.to_lowercase();
+    let found = output.ports()
+        .into_iter()
+        .find(|p| output.port_name(p).map_or(false, |name| name.to_lowercase().contains(&needle)));
+    match found {
+        Some(found) => {
+            let name = output.port_name(&found)?;
+            let connection = output.connect(&found, virtual_name)
+                .map_err(|e| anyhow!("Could not connect to MIDI port '{}' ({})", name, e.kind()))?;
+            info!("Connected to MIDI port '{}'", name);
+            Ok(connection)
+        }
+        None => {
+            let available: Vec<String> = output.ports().iter().filter_map(|p| output.port_name(p).ok()).collect();
+            Err(anyhow!("Unknown MIDI output port '{}' (available: {})", port, available.join(", ")))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn open_virtual_output(output: MidiOutput, name: &str) -> Result<MidiOutputConnection, anyhow::Error> {
+    use midir::os::unix::VirtualOutput;
+
+    let connection = output.create_virtual(name)
+        .map_err(|e| anyhow!("Could not create virtual MIDI port '{}' ({})", name, e.kind()))?;
+    info!("Created virtual MIDI port '{}'", name);
+    Ok(connection)
+}
+
+#[cfg(not(unix))]
+fn open_virtual_output(_output: MidiOutput, name: &str) -> Result<MidiOutputConnection, anyhow::Error> {
+    Err(anyhow!("Virtual MIDI ports are not supported on this platform, configure a port for '{}'", name))
+}
+
+// Like `open_output`, `callback` gets a timestamp in microseconds and the message
+pub fn open_input<F>(port: Option<&str>, virtual_name: &str, callback: F) -> Result<MidiInputConnection<()>, anyhow::Error>
+    where F: FnMut(u64, &[u8]) + Send + 'static
+{
+    let mut input = MidiInput::new(CLIENT_NAME)?;
+    input.ignore(Ignore::SysexAndActiveSense);
+    let mut callback = callback;
+    let callback = move |stamp: u64, message: &[u8], _: &mut ()| callback(stamp, message);
+
+    let port = match port {
+        Some(port) => port,
+        None => return open_virtual_input(input, virtual_name, callback),
+    };
+
+    let needle = port.to_lowercase();
+    let found = input.ports()
+        .into_iter()
+        .find(|p| input.port_name(p).map_or(false, |name| name.to_lowercase().contains(&needle)));
+    match found {
+        Some(found) => {
+            let name = input.port_name(&found)?;
+            let connection = input.connect(&found, virtual_name, callback, ())
+                .map_err(|e| anyhow!("Could not connect to MIDI port '{}' ({})", name, e.kind()))?;
+            info!("Connected to MIDI port '{}'", name);
+            Ok(connection)
+        }
+        None => {
+            let available: Vec<String> = input.ports().iter().filter_map(|p| input.port_name(p).ok()).collect();
+            Err(anyhow!("Unknown MIDI input port '{}' (available: {})", port, available.join(", ")))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn open_virtual_input<F>(input: MidiInput, name: &str, callback: F) -> Result<MidiInputConnection<()>, anyhow::Error>
+    where F: FnMut(u64, &[u8], &mut ()) + Send + 'static
+{
+    use midir::os::unix::VirtualInput;
+
+    let connection = input.create_virtual(name, callback, ())
+        .map_err(|e| anyhow!("Could not create virtual MIDI port '{}' ({})", name, e.kind()))?;
+    info!("Created virtual MIDI port '{}'", name);
+    Ok(connection)
+}
+
+#[cfg(not(unix))]
+fn open_virtual_input<F>(_input: MidiInput, name: &str, _callback: F) -> Result<MidiInputConnection<()>, anyhow::Error>
+    where F: FnMut(u64, &[u8], &mut ()) + Send + 'static
+{
+    Err(anyhow!("Virtual MIDI ports are not supported on this platform, configure a port for '{}'", name))
+}
+
+// Song Position Pointer counts sixteenth notes in 14 bits
+pub fn song_position(sixteenths: u32) -> [u8; 3] {
+    let sixteenths = sixteenths.min(0x3FFF);
+    [SONG_POSITION, (sixteenths & 0x7F) as u8, (sixteenths >> 7) as u8]
+}
+
+// `channel` counts from 1 like on the devices
+pub fn program_change(channel: u8, program: u8) -> [u8; 2] {
+    [PROGRAM_CHANGE | (channel.saturating_sub(1) & 0x0F), program & 0x7F]
+}
@@ -0,0 +1,226 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::mpsc::TryRecvError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, warn};
+use midir::MidiOutputConnection;
+
+use crate::audio::clock::{ClockEvent, ClockEvents, ClockSink, QueueProblem, TimedClock};
+use crate::config::MidiConfig;
+
+use super::{program_change, song_position, CONTINUE, START, STOP, TIMING_CLOCK};
+
+const PORT_NAME: &str = "Clock";
+
+// How often the queue is checked. Events are queued ahead by the output latency,
+// so they are still sent on time.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// Clock pulses per sixteenth note, the unit of the song position
+const PULSES_PER_SIXTEENTH: u32 = 6;
+
+// Where the followers are in the song, in clock pulses since its start
+#[derive(Debug, Default)]
+struct SongPosition {
+    pulses: u32,
+    running: bool,
+}
+
+impl SongPosition {
+    fn on_event(&mut self, event: ClockEvent) -> Vec<u8> {
+        match event {
+            ClockEvent::Start { pulses_per_bar } => {
+                self.running = true;
+                if self.pulses == 0 {
+                    return vec![START];
+                }
+                // The engine always starts on a downbeat, so continue on the next bar
+                // that can be expressed as a song position
+                let mut pulses = (self.pulses + pulses_per_bar - 1) / pulses_per_bar * pulses_per_bar;
+                while pulses % PULSES_PER_SIXTEENTH != 0 {
+                    pulses += pulses_per_bar;
+                }
+                self.pulses = pulses;
+                let mut bytes = song_position(pulses / PULSES_PER_SIXTEENTH).to_vec();
+                bytes.push(CONTINUE);
+                bytes
+            }
+            ClockEvent::Stop => {
+                self.running = false;
+                vec![STOP]
+            }
+            ClockEvent::Pulse if self.running => {
+                self.pulses += 1;
+                vec![TIMING_CLOCK]
+            }
+            ClockEvent::Pulse => vec![],
+        }
+    }
+
+    fn on_song_change(&mut self) -> Vec<u8> {
+        self.pulses = 0;
+        if self.running {
+            // Song position must not be sent while running, the followers keep their position
+            vec![]
+        } else {
+            song_position(0).to_vec()
+        }
+    }
+}
+
+struct Shared {
+    connection: MidiOutputConnection,
+    position: SongPosition,
+}
+
+impl Shared {
+    fn send(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        if let Err(e) = self.connection.send(bytes) {
+            warn!("Could not send MIDI message {:02X?} ({})", bytes, e);
+        }
+    }
+}
+
+// Sends the clock of the audio engine as MIDI clock, start, stop and continue.
+// Events arrive ahead of time from the audio callback and are sent when their frame is heard.
+pub struct MidiClock {
+    sink: ClockSink,
+    shared: Arc<Mutex<Shared>>,
+    program_channel: u8,
+}
+
+impl MidiClock {
+    pub fn open(config: &MidiConfig) -> Result<MidiClock, anyhow::Error> {
+        let connection = super::open_output(config.output_port.as_deref(), PORT_NAME)?;
+        let shared = Arc::new(Mutex::new(Shared {
+            connection,
+            position: SongPosition::default(),
+        }));
+
+        let (sink, queues) = ClockSink::new();
+        let thread_shared = shared.clone();
+        std::thread::Builder::new()
+            .name(String::from("midi clock"))
+            .spawn(move || {
+                let mut events: Option<ClockEvents> = None;
+                loop {
+                    // Every stream that opens brings its own queue, the previous stream is closed
+                    match queues.try_recv() {
+                        Ok(queue) => {
+                            events = Some(queue);
+                            continue;
+                        }
+                        // Ends once the audio engine and this handle dropped their sinks
+                        Err(TryRecvError::Disconnected) if events.as_ref().map_or(true, |e| e.is_abandoned() && e.is_empty()) => break,
+                        Err(_) => {}
+                    }
+                    let TimedClock { at, event } = match events.as_mut().and_then(|e| e.pop().ok()) {
+                        Some(timed) => timed,
+                        None => {
+                            std::thread::sleep(POLL_INTERVAL);
+                            continue;
+                        }
+                    };
+                    let now = Instant::now();
+                    if at > now {
+                        std::thread::sleep(at - now);
+                    }
+                    match thread_shared.lock() {
+                        Ok(mut shared) => {
+                            let bytes = shared.position.on_event(event);
+                            shared.send(&bytes);
+                        }
+                        Err(e) => {
+                            error!("MIDI clock state is poisoned ({})", e);
+                            return;
+                        }
+                    }
+                }
+                debug!("MIDI clock stopped");
+            })?;
+
+        Ok(MidiClock {
+            sink,
+            shared,
+            program_channel: config.program_channel,
+        })
+    }
+
+    // Hand this to `AudioHandle::set_clock_sink`
+    pub fn sink(&self) -> ClockSink {
+        self.sink.clone()
+    }
+
+    // What went wrong in the audio thread since the last call
+    pub fn problem(&self) -> Option<&'static str> {
+        self.sink.status().take_problem().map(|problem| match problem {
+            QueueProblem::Stopped => "The MIDI clock stopped, no longer sending clock events",
+            QueueProblem::Overflowed => "The MIDI clock fell behind, clock events were dropped",
+        })
+    }
+
+    pub fn song_changed(&self, program: Option<u8>) {
+        if let Ok(mut shared) = self.shared.lock() {
+            let bytes = shared.position.on_song_change();
+            shared.send(&bytes);
+            if let Some(program) = program {
+                shared.send(&program_change(self.program_channel, program));
+            }
+        }
+    }
+}
+
+impl Debug for MidiClock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MidiClock(program channel {})", self.program_channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_start_starts_the_song() {
+        let mut position = SongPosition::default();
+        assert_eq!(position.on_event(ClockEvent::Start { pulses_per_bar: 96 }), vec![START]);
+        assert_eq!(position.on_event(ClockEvent::Pulse), vec![TIMING_CLOCK]);
+        assert_eq!(position.on_event(ClockEvent::Stop), vec![STOP]);
+        assert_eq!(position.on_event(ClockEvent::Pulse), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn restart_continues_on_the_next_bar() {
+        let mut position = SongPosition::default();
+        position.on_event(ClockEvent::Start { pulses_per_bar: 96 });
+        for _ in 0..100 {
+            position.on_event(ClockEvent::Pulse);
+        }
+        position.on_event(ClockEvent::Stop);
+
+        // 192 pulses are two bars, 32 sixteenths
+        assert_eq!(position.on_event(ClockEvent::Start { pulses_per_bar: 96 }), vec![0xF2, 32, 0, CONTINUE]);
+        assert_eq!(position.pulses, 192);
+    }
+
+    #[test]
+    fn song_change_rewinds_when_stopped() {
+        let mut position = SongPosition::default();
+        position.on_event(ClockEvent::Start { pulses_per_bar: 72 });
+        position.on_event(ClockEvent::Pulse);
+        assert!(position.on_song_change().is_empty());
+        position.on_event(ClockEvent::Stop);
+        assert_eq!(position.on_song_change(), vec![0xF2, 0, 0]);
+        assert_eq!(position.on_event(ClockEvent::Start { pulses_per_bar: 72 }), vec![START]);
+    }
+
+    #[test]
+    fn encodes_messages() {
+        assert_eq!(song_position(200), [0xF2, 200 & 0x7F, 1]);
+        assert_eq!(program_change(10, 5), [0xC9, 5]);
+    }
+}
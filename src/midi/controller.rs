@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use midir::MidiInputConnection;
+use serde::{Deserialize, Serialize};
+
+use crate::config::MidiConfig;
+use crate::input::keymap::Action;
+use crate::{input, Message};
+
+use super::PROGRAM_CHANGE;
+
+const PORT_NAME: &str = "Control";
+
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+
+// Foot switches send 127 when pressed and 0 when released
+const CONTROL_PRESSED: u8 = 64;
+
+// A MIDI message that triggers an action, written as "Note 1:60", "CC 1:64" or "PC 1:5".
+// Channels count from 1 like on the devices.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum MidiTrigger {
+    Note { channel: u8, note: u8 },
+    ControlChange { channel: u8, controller: u8 },
+    ProgramChange { channel: u8, program: u8 },
+}
+
+impl MidiTrigger {
+    // Only presses trigger, note offs and released switches are ignored
+    pub fn from_message(message: &[u8]) -> Option<MidiTrigger> {
+        let (status, channel) = match message.first() {
+            Some(byte) => (byte & 0xF0, (byte & 0x0F) + 1),
+            None => return None,
+        };
+        match (status, message.get(1), message.get(2)) {
+            (NOTE_ON, Some(&note), Some(&velocity)) if velocity > 0 => Some(MidiTrigger::Note { channel, note }),
+            (CONTROL_CHANGE, Some(&controller), Some(&value)) if value >= CONTROL_PRESSED => {
+                Some(MidiTrigger::ControlChange { channel, controller })
+            }
+            (PROGRAM_CHANGE, Some(&program), _) => Some(MidiTrigger::ProgramChange { channel, program }),
+            _ => None,
+        }
+    }
+}
+
+// Controllers only trigger when they cross from released to pressed, so expression pedals
+// and switches that repeat their value trigger once per press
+#[derive(Debug, Default)]
+pub struct TriggerDetector {
+    // One bit per controller and channel
+    pressed: [u128; 16],
+}
+
+impl TriggerDetector {
+    pub fn on_message(&mut self, message: &[u8]) -> Option<MidiTrigger> {
+        if let [status, controller, value, ..] = *message {
+            if status & 0xF0 == CONTROL_CHANGE {
+                let pressed = &mut self.pressed[(status & 0x0F) as usize];
+                let bit = 1u128 << (controller & 0x7F);
+                let was_pressed = *pressed & bit != 0;
+                if value >= CONTROL_PRESSED {
+                    *pressed |= bit;
+                } else {
+                    *pressed &= !bit;
+                }
+                if was_pressed {
+                    return None;
+                }
+            }
+        }
+        MidiTrigger::from_message(message)
+    }
+}
+
+impl Display for MidiTrigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MidiTrigger::Note { channel, note } => write!(f, "Note {}:{}", channel, note),
+            MidiTrigger::ControlChange { channel, controller } => write!(f, "CC {}:{}", channel, controller),
+            MidiTrigger::ProgramChange { channel, program } => write!(f, "PC {}:{}", channel, program),
+        }
+    }
+}
+
+impl FromStr for MidiTrigger {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Invalid MIDI trigger '{}', expected e.g. 'Note 1:60', 'CC 1:64' or 'PC 1:5'", s);
+
+        let (kind, address) = s.trim().split_once(' ').ok_or_else(invalid)?;
+        let (channel, number) = address.trim().split_once(':').ok_or_else(invalid)?;
+        let channel: u8 = channel.trim().parse().map_err(|_| invalid())?;
+        let number: u8 = number.trim().parse().map_err(|_| invalid())?;
+        if !(1..=16).contains(&channel) {
+            return Err(anyhow!("MIDI channel must be between 1 and 16 in '{}'", s));
+        }
+        if number > 127 {
+            return Err(anyhow!("MIDI value must be between 0 and 127 in '{}'", s));
+        }
+
+        match kind.to_ascii_lowercase().as_str() {
+            "note" => Ok(MidiTrigger::Note { channel, note: number }),
+            "cc" => Ok(MidiTrigger::ControlChange { channel, controller: number }),
+            "pc" => Ok(MidiTrigger::ProgramChange { channel, program: number }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for MidiTrigger {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<MidiTrigger> for String {
+    fn from(trigger: MidiTrigger) -> Self {
+        trigger.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ControlMap {
+    bindings: BTreeMap<MidiTrigger, Action>,
+}
+
+impl ControlMap {
+    pub fn action(&self, trigger: &MidiTrigger) -> Option<Action> {
+        self.bindings.get(trigger).copied()
+    }
+
+    pub fn bind(&mut self, trigger: MidiTrigger, action: Action) {
+        self.bindings.insert(trigger, action);
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        for (trigger, action) in &self.bindings {
+            if let Action::JumpToSong(0) = action {
+                return Err(anyhow!("'{}' jumps to song 0, songs are counted from 1", trigger));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sends every trigger from the controller port to the application as `Message::MidiTrigger`
+pub struct MidiController {
+    _connection: MidiInputConnection<()>,
+}
+
+impl MidiController {
+    pub fn open(config: &MidiConfig) -> Result<MidiController, anyhow::Error> {
+        let mut detector = TriggerDetector::default();
+        let connection = super::open_input(config.controller_port.as_deref(), PORT_NAME, move |_, message| {
+            if let Some(trigger) = detector.on_message(message) {
+                input::send(Message::MidiTrigger(trigger));
+            }
+        })?;
+        Ok(MidiController { _connection: connection })
+    }
+}
+
+impl Debug for MidiController {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MidiController")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triggers_from_presses_only() {
+        assert_eq!(MidiTrigger::from_message(&[0x90, 60, 100]), Some(MidiTrigger::Note { channel: 1, note: 60 }));
+        assert_eq!(MidiTrigger::from_message(&[0x90, 60, 0]), None);
+        assert_eq!(MidiTrigger::from_message(&[0x80, 60, 0]), None);
+        assert_eq!(
+            MidiTrigger::from_message(&[0xB3, 64, 127]),
+            Some(MidiTrigger::ControlChange { channel: 4, controller: 64 })
+        );
+        assert_eq!(MidiTrigger::from_message(&[0xB3, 64, 0]), None);
+        assert_eq!(MidiTrigger::from_message(&[0xCF, 5]), Some(MidiTrigger::ProgramChange { channel: 16, program: 5 }));
+        assert_eq!(MidiTrigger::from_message(&[0xF8]), None);
+    }
+
+    #[test]
+    fn controllers_trigger_on_the_rising_edge() {
+        let mut detector = TriggerDetector::default();
+        let pedal = Some(MidiTrigger::ControlChange { channel: 1, controller: 11 });
+        let values = [0, 70, 90, 127, 100, 63, 10, 64, 64];
+        let triggers: Vec<_> = values.iter().map(|value| detector.on_message(&[0xB0, 11, *value])).collect();
+        assert_eq!(triggers, vec![None, pedal, None, None, None, None, None, pedal, None]);
+
+        // Controllers and channels are separate
+        assert!(detector.on_message(&[0xB1, 11, 127]).is_some());
+        assert!(detector.on_message(&[0xB0, 12, 127]).is_some());
+        assert_eq!(detector.on_message(&[0x90, 60, 100]), Some(MidiTrigger::Note { channel: 1, note: 60 }));
+        assert_eq!(detector.on_message(&[0x90, 60, 100]), Some(MidiTrigger::Note { channel: 1, note: 60 }));
+    }
+
+    #[test]
+    fn triggers_round_trip_as_strings() {
+        for s in ["Note 1:60", "CC 16:64", "PC 2:0"] {
+            assert_eq!(s.parse::<MidiTrigger>().unwrap().to_string(), s);
+        }
+        assert_eq!("cc 1 : 64".parse::<MidiTrigger>().unwrap(), MidiTrigger::ControlChange { channel: 1, controller: 64 });
+        assert!("CC 0:64".parse::<MidiTrigger>().is_err());
+        assert!("CC 1:200".parse::<MidiTrigger>().is_err());
+        assert!("Pitch 1:2".parse::<MidiTrigger>().is_err());
+    }
+
+    #[test]
+    fn control_map_from_yaml() {
+        let map: ControlMap = serde_yaml::from_str("CC 1:64: NextSong\nPC 1:3:\n  JumpToSong: 4\n").unwrap();
+        assert_eq!(map.action(&"CC 1:64".parse().unwrap()), Some(Action::NextSong));
+        assert_eq!(map.action(&"PC 1:3".parse().unwrap()), Some(Action::JumpToSong(4)));
+        assert_eq!(map.action(&"CC 1:65".parse().unwrap()), None);
+    }
+}
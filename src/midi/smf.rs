@@ -0,0 +1,753 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use log::warn;
+
+use crate::audio::meter::{BeatClass, Subdivision, TimeSignature};
+use crate::audio::tempo::{Bpm, TempoChange, TempoMap};
+use crate::song_listing::{FileSongListing, BPM};
+
+const META: u8 = 0xFF;
+const SYSEX: u8 = 0xF0;
+const SYSEX_ESCAPE: u8 = 0xF7;
+
+const META_TRACK_NAME: u8 = 0x03;
+const META_MARKER: u8 = 0x06;
+const META_END_OF_TRACK: u8 = 0x2F;
+const META_TEMPO: u8 = 0x51;
+const META_TIME_SIGNATURE: u8 = 0x58;
+
+// 120 quarter notes per minute, until the first tempo event
+const DEFAULT_TEMPO: u32 = 500_000;
+// Tempo events have three bytes of microseconds per quarter note
+const MAX_TEMPO: u32 = 0xFF_FFFF;
+// Denominators go up to 64, so every bar is a whole number of 64ths of a tick
+const SUBTICKS: u64 = 64;
+
+// Divides evenly into every denominator up to 64 ticks per beat
+const EXPORT_TICKS_PER_QUARTER: u16 = 960;
+// General MIDI drums
+const PERCUSSION_CHANNEL: u8 = 9;
+const NOTE_ON: u8 = 0x90;
+const NOTE_OFF: u8 = 0x80;
+
+// Tempo, meter and sections of a Standard MIDI File
+#[derive(Debug, Clone, PartialEq)]
+pub struct MidiImport {
+    // Name of the first track, usually the song title
+    pub title: Option<String>,
+    pub map: TempoMap,
+    // Tempo and meter changes inside a bar, which had to move to a downbeat
+    pub moved: Vec<String>,
+}
+
+impl MidiImport {
+    // A setlist entry that plays `file` as its tempo map
+    pub fn song_listing(&self, file: &str) -> FileSongListing {
+        let title = self.title.clone().unwrap_or_else(|| {
+            Path::new(file).file_stem().map_or(String::from(file), |s| s.to_string_lossy().into_owned())
+        });
+        FileSongListing::new(&title, BPM::File(String::from(file)), Subdivision::default())
+    }
+}
+
+pub fn is_midi_file(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("mid") || ext.eq_ignore_ascii_case("midi"))
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<MidiImport, anyhow::Error> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    import(&bytes).with_context(|| format!("Malformed MIDI file {}", path.display()))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+    // Microseconds per quarter note
+    Tempo(u32),
+    Meter(TimeSignature),
+    Marker(String),
+}
+
+pub fn import(bytes: &[u8]) -> Result<MidiImport, anyhow::Error> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    let (id, length) = reader.chunk_header()?;
+    if &id != b"MThd" {
+        return Err(anyhow!("Not a Standard MIDI File, expected 'MThd' at byte 0"));
+    }
+    if length < 6 {
+        return Err(anyhow!("Header chunk is {} bytes long, expected at least 6", length));
+    }
+    let header_end = reader.pos + length;
+    let format = reader.u16()?;
+    let tracks = reader.u16()?;
+    let division = reader.u16()?;
+    reader.seek(header_end)?;
+
+    match format {
+        0 if tracks != 1 => return Err(anyhow!("Type 0 file must have exactly one track (has {})", tracks)),
+        0 | 1 => {}
+        _ => return Err(anyhow!("SMF type {} is not supported, only type 0 and 1", format)),
+    }
+    if division & 0x8000 != 0 {
+        return Err(anyhow!("SMPTE time division is not supported, only ticks per quarter note"));
+    }
+    if division == 0 {
+        return Err(anyhow!("Time division of 0 ticks per quarter note"));
+    }
+
+    let mut title = None;
+    let mut events = Vec::new();
+    let mut found = 0;
+    while found < tracks {
+        if reader.remaining() == 0 {
+            return Err(anyhow!("Expected {} tracks, found {}", tracks, found));
+        }
+        let start = reader.pos;
+        let (id, length) = reader.chunk_header()?;
+        if length > reader.remaining() {
+            return Err(anyhow!(
+                "Chunk at byte {} is {} bytes long, but only {} bytes are left",
+                start, length, reader.remaining()
+            ));
+        }
+        let body = &bytes[reader.pos..reader.pos + length];
+        // Unknown chunks have to be skipped
+        if &id == b"MTrk" {
+            let track = parse_track(body, reader.pos)
+                .with_context(|| format!("Track {}", found + 1))?;
+            if found == 0 {
+                title = track.name;
+            }
+            events.extend(track.events);
+            found += 1;
+        }
+        reader.seek(reader.pos + length)?;
+    }
+
+    // Stable, so events on the same tick keep the order of the tracks
+    events.sort_by_key(|(tick, _)| *tick);
+    let (map, moved) = build_map(&events, division as u64)?;
+    Ok(MidiImport { title, map, moved })
+}
+
+struct Track {
+    name: Option<String>,
+    events: Vec<(u64, Event)>,
+}
+
+// `offset` is where the track data starts in the file, for the error messages
+fn parse_track(bytes: &[u8], offset: usize) -> Result<Track, anyhow::Error> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let mut track = Track { name: None, events: Vec::new() };
+    let mut tick = 0u64;
+    let mut running_status = None;
+
+    loop {
+        if reader.remaining() == 0 {
+            return Err(anyhow!("Missing end of track event at byte {}", offset + reader.pos));
+        }
+        let delta = reader.vlq().map_err(|e| anyhow!("{} at byte {}", e, offset + reader.pos))?;
+        tick = tick.checked_add(delta as u64).ok_or_else(|| anyhow!("Event at byte {} is too far into the song", offset + reader.pos))?;
+
+        let at = offset + reader.pos;
+        let status = match reader.peek()? {
+            byte if byte & 0x80 != 0 => {
+                reader.pos += 1;
+                byte
+            }
+            _ => running_status.ok_or_else(|| anyhow!("Data byte without a status byte at byte {}", at))?,
+        };
+
+        match status {
+            META => {
+                running_status = None;
+                let kind = reader.u8()?;
+                let length = reader.vlq().map_err(|e| anyhow!("{} at byte {}", e, offset + reader.pos))? as usize;
+                let data = reader.take(length).map_err(|_| anyhow!("Meta event at byte {} is cut off", at))?;
+                match kind {
+                    META_END_OF_TRACK => {
+                        if reader.remaining() > 0 {
+                            warn!("Ignoring {} bytes after the end of track at byte {}", reader.remaining(), at);
+                        }
+                        return Ok(track);
+                    }
+                    META_TEMPO => {
+                        if length != 3 {
+                            return Err(anyhow!("Tempo event at byte {} has {} data bytes, expected 3", at, length));
+                        }
+                        let tempo = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+                        if tempo == 0 {
+                            return Err(anyhow!("Tempo event at byte {} has a tempo of 0", at));
+                        }
+                        track.events.push((tick, Event::Tempo(tempo)));
+                    }
+                    META_TIME_SIGNATURE => {
+                        if length != 4 {
+                            return Err(anyhow!("Time signature event at byte {} has {} data bytes, expected 4", at, length));
+                        }
+                        let denominator = 1u32.checked_shl(data[1] as u32).filter(|d| *d <= u8::MAX as u32);
+                        let meter = denominator
+                            .ok_or_else(|| anyhow!("Denominator 2^{} is too large", data[1]))
+                            .and_then(|d| TimeSignature::new(data[0], d as u8))
+                            .with_context(|| format!("Time signature event at byte {}", at))?;
+                        track.events.push((tick, Event::Meter(meter)));
+                    }
+                    META_MARKER => {
+                        let name = String::from_utf8_lossy(data).trim().to_string();
+                        track.events.push((tick, Event::Marker(name)));
+                    }
+                    META_TRACK_NAME if track.name.is_none() => {
+                        let name = String::from_utf8_lossy(data).trim().to_string();
+                        track.name = Some(name).filter(|n| !n.is_empty());
+                    }
+                    _ => {}
+                }
+            }
+            SYSEX | SYSEX_ESCAPE => {
+                running_status = None;
+                let length = reader.vlq().map_err(|e| anyhow!("{} at byte {}", e, offset + reader.pos))? as usize;
+                reader.take(length).map_err(|_| anyhow!("System exclusive event at byte {} is cut off", at))?;
+            }
+            0x80..=0xEF => {
+                running_status = Some(status);
+                let length = match status & 0xF0 {
+                    0xC0 | 0xD0 => 1,
+                    _ => 2,
+                };
+                let data = reader.take(length).map_err(|_| anyhow!("Channel event at byte {} is cut off", at))?;
+                if data.iter().any(|b| b & 0x80 != 0) {
+                    return Err(anyhow!("Channel event at byte {} has a data byte above 127", at));
+                }
+            }
+            _ => return Err(anyhow!("Invalid status byte 0x{:02X} at byte {}", status, at)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BarChange {
+    tempo: Option<u32>,
+    meter: Option<TimeSignature>,
+    section: Option<String>,
+}
+
+// Places the events on bars. Tempo and meter changes inside a bar move to the next bar,
+// since the engine only changes them on downbeats. Returns what was moved.
+fn build_map(events: &[(u64, Event)], ticks_per_quarter: u64) -> Result<(TempoMap, Vec<String>), anyhow::Error> {
+    // In subticks, bars of 7/8 with 1 tick per quarter are 3.5 ticks long
+    let bar_length = |meter: &TimeSignature| {
+        ticks_per_quarter * SUBTICKS * 4 * meter.numerator() as u64 / meter.denominator() as u64
+    };
+    let too_far = |tick: u64| anyhow!("Event at tick {} is too far into the song", tick);
+
+    let mut bars: BTreeMap<u32, BarChange> = BTreeMap::new();
+    bars.insert(1, BarChange::default());
+    let mut moved = Vec::new();
+    let mut meter = TimeSignature::default();
+    let mut bar = 1u32;
+    let mut bar_start = 0u64;
+    let mut length = bar_length(&meter);
+
+    for (tick, event) in events {
+        let at = tick.checked_mul(SUBTICKS).ok_or_else(|| too_far(*tick))?;
+        let elapsed = (at - bar_start) / length;
+        bar = u32::try_from(elapsed).ok().and_then(|e| bar.checked_add(e)).ok_or_else(|| too_far(*tick))?;
+        bar_start += elapsed * length;
+        let inside = at != bar_start;
+        let target = if inside { bar.checked_add(1).ok_or_else(|| too_far(*tick))? } else { bar };
+
+        match event {
+            Event::Tempo(tempo) => {
+                if inside {
+                    moved.push(format!("Tempo change at tick {} is inside bar {}, moved to bar {}", tick, bar, target));
+                }
+                bars.entry(target).or_default().tempo = Some(*tempo);
+            }
+            Event::Meter(new_meter) => {
+                if inside {
+                    moved.push(format!("Time signature change at tick {} is inside bar {}, bar {} starts there", tick, bar, target));
+                    bar = target;
+                    bar_start = at;
+                }
+                bars.entry(bar).or_default().meter = Some(*new_meter);
+                meter = *new_meter;
+                length = bar_length(&meter);
+            }
+            // Sections start on the bar the marker is in
+            Event::Marker(name) => bars.entry(bar).or_default().section = Some(name.clone()).filter(|n| !n.is_empty()),
+        }
+    }
+    for message in &moved {
+        warn!("{}", message);
+    }
+
+    let mut changes = Vec::new();
+    let mut tempo = DEFAULT_TEMPO;
+    let mut meter = TimeSignature::default();
+    let mut last_bpm = None;
+    for (bar, change) in bars {
+        tempo = change.tempo.unwrap_or(tempo);
+        let meter_changed = change.meter.map_or(false, |m| m != meter) || bar == 1;
+        meter = change.meter.unwrap_or(meter);
+
+        // The engine counts denominator notes, MIDI tempo counts quarters
+        let bpm = 60_000_000. / tempo as f64 * meter.denominator() as f64 / 4.;
+        let bpm = Bpm::from_f64(bpm).with_context(|| format!("Tempo on bar {}", bar))?;
+        let bpm = if last_bpm == Some(bpm) { None } else { Some(bpm) };
+        if bpm.is_some() {
+            last_bpm = bpm;
+        }
+
+        if bpm.is_some() || meter_changed || change.section.is_some() {
+            changes.push(TempoChange {
+                bar,
+                bpm,
+                meter: if meter_changed { Some(meter) } else { None },
+                section: change.section,
+            });
+        }
+    }
+    Ok((TempoMap::new(changes)?, moved))
+}
+
+// What to write besides the song itself
+#[derive(Debug, Clone)]
+pub struct ClickExport {
+    // Used for songs without a tempo map
+    pub meter: TimeSignature,
+    // Length after the count-in. Songs with a tempo map are at least as long as their last change.
+    pub bars: u32,
+    // Bars of plain beats before bar 1, at the starting tempo and meter
+    pub count_in: u32,
+}
+
+impl Default for ClickExport {
+    fn default() -> Self {
+        ClickExport {
+            meter: TimeSignature::default(),
+            bars: 64,
+            count_in: 1,
+        }
+    }
+}
+
+// General MIDI wood blocks and side stick, so the clicks sound right on any drum track
+fn click_note(class: BeatClass) -> (u8, u8) {
+    match class {
+        BeatClass::Accent => (76, 127),
+        BeatClass::SecondaryAccent => (76, 100),
+        BeatClass::Beat => (77, 100),
+        BeatClass::Subdivision => (37, 70),
+    }
+}
+
+// The click of a song as a type 0 file. `map` is the loaded tempo map of songs that have one.
+pub fn export(song: &FileSongListing, map: Option<&TempoMap>, options: &ClickExport) -> Result<Vec<u8>, anyhow::Error> {
+    let mut events = Vec::new();
+    if options.count_in > 0 {
+        events.push((0, meta_event(META_MARKER, b"Count-in")));
+    }
+    let (clicks, end) = song_events(song, map, options, 0)?;
+    events.extend(clicks);
+    Ok(file(song.title(), &events, end))
+}
+
+// The clicks of all songs one after the other, each starts with a marker of its title.
+// Every song comes with its loaded tempo map, if it has one.
+pub fn export_setlist(songs: &[(FileSongListing, Option<&TempoMap>)], options: &ClickExport) -> Result<Vec<u8>, anyhow::Error> {
+    let mut events = Vec::new();
+    let mut end = 0;
+    for (song, map) in songs {
+        events.push((end, meta_event(META_MARKER, song.title().as_bytes())));
+        let (clicks, song_end) = song_events(song, *map, options, end)?;
+        events.extend(clicks);
+        end = song_end;
+    }
+    Ok(file("Setlist", &events, end))
+}
+
+pub fn save<P: AsRef<Path>>(path: P, song: &FileSongListing, map: Option<&TempoMap>, options: &ClickExport) -> Result<(), anyhow::Error> {
+    let path = path.as_ref();
+    let bytes = export(song, map, options)?;
+    std::fs::write(path, bytes).with_context(|| format!("Could not write {}", path.display()))
+}
+
+pub fn save_setlist<P: AsRef<Path>>(path: P, songs: &[(FileSongListing, Option<&TempoMap>)], options: &ClickExport) -> Result<(), anyhow::Error> {
+    let path = path.as_ref();
+    let bytes = export_setlist(songs, options)?;
+    std::fs::write(path, bytes).with_context(|| format!("Could not write {}", path.display()))
+}
+
+fn song_events(song: &FileSongListing, map: Option<&TempoMap>, options: &ClickExport, start: u64) -> Result<(Vec<(u64, Vec<u8>)>, u64), anyhow::Error> {
+    let fixed;
+    let map = match (song.bpm(), map) {
+        (_, Some(map)) => map,
+        (BPM::Number(bpm), None) => {
+            fixed = TempoMap::new(vec![TempoChange { bar: 1, bpm: Some(*bpm), meter: Some(options.meter), section: None }])?;
+            &fixed
+        }
+        (BPM::File(file), None) => return Err(anyhow!("Song '{}' needs its tempo map {} loaded", song.title(), file)),
+    };
+    click_events(map, song.subdivision(), options, start).with_context(|| format!("Song '{}'", song.title()))
+}
+
+// A type 0 file with one track of `events`, which are sorted by tick
+fn file(name: &str, events: &[(u64, Vec<u8>)], end: u64) -> Vec<u8> {
+    let mut track = vec![0];
+    track.extend(meta_event(META_TRACK_NAME, name.as_bytes()));
+    let mut last = 0;
+    for (tick, event) in events {
+        write_vlq(&mut track, (tick - last) as u32);
+        track.extend_from_slice(event);
+        last = *tick;
+    }
+    write_vlq(&mut track, (end - last) as u32);
+    track.extend(meta_event(META_END_OF_TRACK, &[]));
+
+    let mut bytes = b"MThd\0\0\0\x06".to_vec();
+    for value in [0, 1, EXPORT_TICKS_PER_QUARTER] {
+        bytes.extend_from_slice(&value.to_be_bytes());
+    }
+    bytes.extend_from_slice(b"MTrk");
+    bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    bytes.extend(track);
+    bytes
+}
+
+// Timed events of the whole click from tick `start` on, and the tick it ends on
+fn click_events(map: &TempoMap, subdivision: Subdivision, options: &ClickExport, start: u64) -> Result<(Vec<(u64, Vec<u8>)>, u64), anyhow::Error> {
+    let last_change = map.changes().last().map_or(1, |c| c.bar);
+    let bars = options.bars.max(last_change);
+    let (start_bpm, start_meter) = map.start();
+
+    let mut events = Vec::new();
+    let mut tick = start;
+    let mut tempo = None;
+    let mut meter = None;
+
+    for bar in (1 - options.count_in as i64)..=bars as i64 {
+        let counting_in = bar < 1;
+        let (bpm, bar_meter) = if counting_in { (start_bpm, start_meter) } else { map.at(bar as u32) };
+
+        if meter != Some(bar_meter) {
+            let exponent = bar_meter.denominator().trailing_zeros() as u8;
+            events.push((tick, meta_event(META_TIME_SIGNATURE, &[bar_meter.numerator(), exponent, 24, 8])));
+            meter = Some(bar_meter);
+        }
+        let bar_tempo = midi_tempo(bpm, bar_meter).with_context(|| format!("Bar {}", bar.max(1)))?;
+        if tempo != Some(bar_tempo) {
+            let [_, a, b, c] = bar_tempo.to_be_bytes();
+            events.push((tick, meta_event(META_TEMPO, &[a, b, c])));
+            tempo = Some(bar_tempo);
+        }
+        if let Some(section) = map.changes().iter().find(|c| c.bar as i64 == bar).and_then(|c| c.section.as_ref()) {
+            events.push((tick, meta_event(META_MARKER, section.as_bytes())));
+        }
+
+        let ticks_per_beat = EXPORT_TICKS_PER_QUARTER as u64 * 4 / bar_meter.denominator() as u64;
+        // The count-in only clicks the beats
+        let divisions = if counting_in { 1 } else { subdivision.divisions() as u64 };
+        let length = (ticks_per_beat / divisions / 2).max(1);
+        for beat in 0..bar_meter.numerator() {
+            for division in 0..divisions {
+                let class = if division > 0 {
+                    if subdivision.is_muted(division as u8) {
+                        continue;
+                    }
+                    BeatClass::Subdivision
+                } else if counting_in && beat > 0 {
+                    BeatClass::Beat
+                } else {
+                    bar_meter.beat_class(beat)
+                };
+                let (note, velocity) = click_note(class);
+                let at = tick + beat as u64 * ticks_per_beat + division * ticks_per_beat / divisions;
+                events.push((at, vec![NOTE_ON | PERCUSSION_CHANNEL, note, velocity]));
+                events.push((at + length, vec![NOTE_OFF | PERCUSSION_CHANNEL, note, 0]));
+            }
+        }
+        tick += ticks_per_beat * bar_meter.numerator() as u64;
+    }
+
+    // Stable, so meta events stay in front of the notes they belong to
+    events.sort_by_key(|(tick, _)| *tick);
+    Ok((events, tick))
+}
+
+// Microseconds per quarter note, the engine's BPM counts denominator notes
+fn midi_tempo(bpm: Bpm, meter: TimeSignature) -> Result<u32, anyhow::Error> {
+    let tempo = (60_000_000_000u64 * meter.denominator() as u64 / (4 * bpm.millis() as u64)) as u32;
+    if tempo > MAX_TEMPO {
+        return Err(anyhow!("{} BPM in {} is too slow for a MIDI file", bpm, meter));
+    }
+    Ok(tempo)
+}
+
+fn meta_event(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut event = vec![META, kind];
+    write_vlq(&mut event, data.len() as u32);
+    event.extend_from_slice(data);
+    event
+}
+
+fn write_vlq(bytes: &mut Vec<u8>, mut value: u32) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        groups.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.extend(groups.iter().rev());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    fn seek(&mut self, pos: usize) -> Result<(), anyhow::Error> {
+        if pos > self.bytes.len() {
+            return Err(anyhow!("Unexpected end of file at byte {}, expected {} more bytes", self.bytes.len(), pos - self.bytes.len()));
+        }
+        self.pos = pos;
+        Ok(())
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], anyhow::Error> {
+        if length > self.remaining() {
+            return Err(anyhow!("Unexpected end of data at byte {}", self.bytes.len()));
+        }
+        let data = &self.bytes[self.pos..self.pos + length];
+        self.pos += length;
+        Ok(data)
+    }
+
+    fn peek(&self) -> Result<u8, anyhow::Error> {
+        self.bytes.get(self.pos).copied().ok_or_else(|| anyhow!("Unexpected end of data at byte {}", self.pos))
+    }
+
+    fn u8(&mut self) -> Result<u8, anyhow::Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, anyhow::Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn chunk_header(&mut self) -> Result<([u8; 4], usize), anyhow::Error> {
+        let start = self.pos;
+        let bytes = self.take(8).map_err(|_| anyhow!("Chunk header at byte {} is cut off", start))?;
+        let id = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        let length = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        Ok((id, length as usize))
+    }
+
+    // Variable length quantities have at most four bytes
+    fn vlq(&mut self) -> Result<u32, anyhow::Error> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = value << 7 | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("Variable length quantity longer than four bytes"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(format: u16, tracks: u16, division: u16) -> Vec<u8> {
+        let mut bytes = b"MThd\0\0\0\x06".to_vec();
+        for value in [format, tracks, division] {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn track(events: &[u8]) -> Vec<u8> {
+        let mut bytes = b"MTrk".to_vec();
+        bytes.extend_from_slice(&(events.len() as u32 + 4).to_be_bytes());
+        bytes.extend_from_slice(events);
+        bytes.extend_from_slice(&[0, 0xFF, 0x2F, 0]);
+        bytes
+    }
+
+    // 96 ticks per quarter, a 4/4 bar is 384 ticks (0x83 0x00 as a delta)
+    fn song() -> Vec<u8> {
+        let mut bytes = header(1, 2, 96);
+        bytes.extend(track(&[
+            0, 0xFF, 0x03, 4, b'S', b'o', b'n', b'g',
+            0, 0xFF, 0x51, 3, 0x07, 0xA1, 0x20,
+            0, 0xFF, 0x06, 5, b'I', b'n', b't', b'r', b'o',
+            // Bar 3: 7/8 and a marker
+            0x86, 0x00, 0xFF, 0x58, 4, 7, 3, 24, 8,
+            0, 0xFF, 0x06, 5, b'V', b'e', b'r', b's', b'e',
+        ]));
+        bytes.extend(track(&[
+            // Notes with running status
+            0, 0x90, 60, 100, 0x60, 60, 0,
+            // 92.5 quarter BPM on bar 4, a 7/8 bar after bar 3 at tick 1104
+            0x87, 0x70, 0xFF, 0x51, 3, 0x09, 0xE5, 0xC9,
+        ]));
+        bytes
+    }
+
+    #[test]
+    fn imports_tempo_meter_and_markers() {
+        let import = import(&song()).unwrap();
+        assert_eq!(import.title.as_deref(), Some("Song"));
+        assert!(import.moved.is_empty());
+
+        let map = &import.map;
+        assert_eq!(map.changes().len(), 3);
+        assert_eq!(map.start(), (Bpm::whole(120).unwrap(), TimeSignature::default()));
+        assert_eq!(map.section(2), Some("Intro"));
+        assert_eq!(map.section(3), Some("Verse"));
+
+        let (bpm, meter) = map.at(3);
+        assert_eq!(meter, TimeSignature::new(7, 8).unwrap());
+        assert_eq!(bpm, Bpm::whole(240).unwrap());
+        // Counted in eighths
+        assert_eq!(map.at(4).0.to_string(), "185");
+        assert!(map.at(2).1 == TimeSignature::default());
+    }
+
+    #[test]
+    fn bars_need_not_be_whole_ticks() {
+        // 1 tick per quarter, a 7/8 bar is 3.5 ticks long
+        let mut bytes = header(0, 1, 1);
+        bytes.extend(track(&[
+            0, 0xFF, 0x58, 4, 7, 3, 24, 8,
+            7, 0xFF, 0x06, 5, b'V', b'e', b'r', b's', b'e',
+            // Half a bar later
+            2, 0xFF, 0x51, 3, 0x07, 0xA1, 0x20,
+        ]));
+        let import = import(&bytes).unwrap();
+        assert_eq!(import.map.section(3), Some("Verse"));
+        assert_eq!(import.moved, vec![String::from("Tempo change at tick 9 is inside bar 3, moved to bar 4")]);
+    }
+
+    #[test]
+    fn becomes_a_setlist_entry() {
+        let entry = import(&song()).unwrap().song_listing("maps/song.mid");
+        assert_eq!(entry.title(), "Song");
+        assert!(matches!(entry.bpm(), BPM::File(file) if file == "maps/song.mid"));
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        format!("{:#}", import(bytes).unwrap_err())
+    }
+
+    #[test]
+    fn reports_malformed_files() {
+        assert!(error(b"RIFF").contains("cut off"));
+        assert!(error(b"RIFF\0\0\0\x06\0\0\0\x01\0\x60").contains("Not a Standard MIDI File"));
+        assert!(error(&header(2, 1, 96)).contains("SMF type 2"));
+        assert!(error(&header(1, 1, 0xE728)).contains("SMPTE"));
+        assert!(error(&header(1, 2, 96)).contains("Expected 2 tracks, found 0"));
+
+        let mut bytes = header(0, 1, 96);
+        bytes.extend(track(&[0, 60, 100]));
+        let message = error(&bytes);
+        assert!(message.contains("Track 1") && message.contains("without a status byte at byte 23"), "{}", message);
+
+        let mut bytes = header(0, 1, 96);
+        bytes.extend(track(&[0, 0xFF, 0x51, 2, 0x07, 0xA1]));
+        assert!(error(&bytes).contains("Tempo event at byte 23 has 2 data bytes"));
+
+        let mut bytes = header(0, 1, 96);
+        bytes.extend(track(&[0, 0xFF, 0x58, 4, 4, 7, 24, 8]));
+        assert!(error(&bytes).contains("Denominator"));
+
+        let mut bytes = header(0, 1, 96);
+        bytes.extend(b"MTrk\0\0\0\x04\0\x90\x3C\x40");
+        assert!(error(&bytes).contains("Missing end of track event at byte 26"));
+
+        let mut bytes = header(0, 1, 96);
+        bytes.extend(b"MTrk\0\0\0\x40\0\xFF\x2F\0");
+        assert!(error(&bytes).contains("only 4 bytes are left"));
+
+        // More bars than can be counted, at 4 ticks a bar
+        let mut bytes = header(0, 1, 1);
+        bytes.extend(track(&[0xFF, 0xFF, 0xFF, 0x7F, 0xFF, 0x06, 0].repeat(70)));
+        assert!(error(&bytes).contains("too far into the song"));
+    }
+
+    fn note_ons(events: &[(u64, Vec<u8>)]) -> Vec<(u64, u8, u8)> {
+        events.iter()
+            .filter(|(_, e)| e[0] == NOTE_ON | PERCUSSION_CHANNEL)
+            .map(|(tick, e)| (*tick, e[1], e[2]))
+            .collect()
+    }
+
+    #[test]
+    fn exports_count_in_accents_and_subdivisions() {
+        let options = ClickExport { meter: TimeSignature::new(3, 4).unwrap(), bars: 2, count_in: 1 };
+        let song = FileSongListing::new("Waltz", BPM::Number(Bpm::whole(90).unwrap()), Subdivision::from_pattern("x-x").unwrap());
+        let map = TempoMap::new(vec![TempoChange { bar: 1, bpm: Some(Bpm::whole(90).unwrap()), meter: Some(options.meter), section: None }]).unwrap();
+
+        let (events, end) = click_events(&map, song.subdivision(), &options, 0).unwrap();
+        let notes = note_ons(&events);
+        // Three count-in beats, then a beat and its last third in both bars
+        assert_eq!(notes.len(), 3 + 2 * 6);
+        assert_eq!(&notes[..4], &[(0, 76, 127), (960, 77, 100), (1920, 77, 100), (2880, 76, 127)]);
+        assert_eq!(notes[4], (2880 + 640, 37, 70));
+        assert_eq!(end, 3 * 2880);
+
+        let import = import(&export(&song, None, &options).unwrap()).unwrap();
+        assert_eq!(import.title.as_deref(), Some("Waltz"));
+        assert_eq!(import.map.section(1), Some("Count-in"));
+        assert_eq!(import.map.at(2), (Bpm::whole(90).unwrap(), options.meter));
+    }
+
+    #[test]
+    fn exported_tempo_maps_import_again() {
+        let map = import(&song()).unwrap().map;
+        let song = FileSongListing::new("Song", BPM::File(String::from("song.mid")), Subdivision::default());
+        let options = ClickExport { bars: 4, count_in: 0, ..ClickExport::default() };
+
+        assert!(export(&song, None, &options).is_err());
+        let exported = import(&export(&song, Some(&map), &options).unwrap()).unwrap();
+        assert_eq!(exported.map, map);
+    }
+
+    #[test]
+    fn exports_setlists_song_after_song() {
+        let options = ClickExport { bars: 2, count_in: 1, ..ClickExport::default() };
+        let first = FileSongListing::new("First", BPM::Number(Bpm::whole(120).unwrap()), Subdivision::default());
+        let second = FileSongListing::new("Second", BPM::File(String::from("second.mid")), Subdivision::default());
+        let waltz = TimeSignature::new(3, 4).unwrap();
+        let map = TempoMap::new(vec![TempoChange { bar: 1, bpm: Some(Bpm::whole(90).unwrap()), meter: Some(waltz), section: None }]).unwrap();
+
+        assert!(export_setlist(&[(second.clone(), None)], &options).is_err());
+        let import = import(&export_setlist(&[(first, None), (second, Some(&map))], &options).unwrap()).unwrap();
+        assert_eq!(import.title.as_deref(), Some("Setlist"));
+        // Count-in and two bars each
+        assert_eq!(import.map.section(1), Some("First"));
+        assert_eq!(import.map.at(3), (Bpm::whole(120).unwrap(), TimeSignature::default()));
+        assert_eq!(import.map.section(4), Some("Second"));
+        assert_eq!(import.map.at(4), (Bpm::whole(90).unwrap(), waltz));
+        assert_eq!(import.map.changes().last().map(|c| c.bar), Some(4));
+    }
+
+    #[test]
+    fn tempos_beyond_midi_are_errors() {
+        let song = FileSongListing::new("Slow", BPM::Number(Bpm::whole(2).unwrap()), Subdivision::default());
+        let error = export(&song, None, &ClickExport::default()).unwrap_err();
+        assert!(format!("{:#}", error).contains("2 BPM in 4/4 is too slow"), "{:#}", error);
+    }
+}
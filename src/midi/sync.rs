@@ -0,0 +1,298 @@
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use midir::MidiInputConnection;
+use rtrb::Producer;
+
+pub use crate::audio::clock::SyncEvent;
+use crate::audio::clock::{SyncSource, TimedSync, PULSES_PER_QUARTER};
+use crate::config::MidiConfig;
+use crate::{input, Message};
+
+use super::{CONTINUE, SONG_POSITION, START, STOP, TIMING_CLOCK};
+
+const PORT_NAME: &str = "Sync";
+
+// Pulse intervals averaged for the tempo, one quarter note
+const TEMPO_WINDOW: usize = PULSES_PER_QUARTER as usize;
+// A gap this many times longer than the average interval restarts the estimate
+const MAX_GAP: f64 = 4.;
+const PULSES_PER_SIXTEENTH: u32 = 6;
+
+// No clock for this long means the master is gone
+pub const LOST_AFTER: Duration = Duration::from_millis(500);
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(100);
+
+// Turns incoming clock messages into transport, tempo and position events
+#[derive(Debug, Default)]
+pub struct ClockFollower {
+    last_stamp: Option<u64>,
+    intervals: VecDeque<u64>,
+    received: u32,
+    // Position in the song, in pulses
+    pulses: u32,
+    running: bool,
+}
+
+impl ClockFollower {
+    pub fn on_message(&mut self, stamp: u64, message: &[u8]) -> Vec<SyncEvent> {
+        let mut events = Vec::new();
+        match message {
+            [TIMING_CLOCK, ..] => self.on_pulse(stamp, &mut events),
+            [START, ..] => {
+                self.pulses = 0;
+                self.running = true;
+                events.push(SyncEvent::Start);
+            }
+            [CONTINUE, ..] => {
+                self.running = true;
+                events.push(SyncEvent::Continue);
+            }
+            [STOP, ..] => {
+                self.running = false;
+                events.push(SyncEvent::Stop);
+            }
+            [SONG_POSITION, lsb, msb, ..] => {
+                let sixteenths = (*lsb as u32 & 0x7F) | (*msb as u32 & 0x7F) << 7;
+                self.pulses = sixteenths * PULSES_PER_SIXTEENTH;
+            }
+            _ => {}
+        }
+        events
+    }
+
+    // Quarter notes per minute, once a quarter note of clock has arrived
+    pub fn tempo(&self) -> Option<f64> {
+        if self.intervals.len() < TEMPO_WINDOW / 2 {
+            return None;
+        }
+        let average = self.average_interval()?;
+        Some(60_000_000. / (average * PULSES_PER_QUARTER as f64))
+    }
+
+    fn average_interval(&self) -> Option<f64> {
+        if self.intervals.is_empty() {
+            None
+        } else {
+            Some(self.intervals.iter().sum::<u64>() as f64 / self.intervals.len() as f64)
+        }
+    }
+
+    fn on_pulse(&mut self, stamp: u64, events: &mut Vec<SyncEvent>) {
+        if let Some(last) = self.last_stamp {
+            let interval = stamp.saturating_sub(last);
+            let gap = self.average_interval().map_or(false, |average| interval as f64 > average * MAX_GAP);
+            if gap {
+                self.intervals.clear();
+            } else {
+                self.intervals.push_back(interval);
+                if self.intervals.len() > TEMPO_WINDOW {
+                    self.intervals.pop_front();
+                }
+            }
+        }
+        self.last_stamp = Some(stamp);
+
+        if self.running {
+            if self.pulses % PULSES_PER_SIXTEENTH == 0 {
+                events.push(SyncEvent::Sixteenth(self.pulses / PULSES_PER_SIXTEENTH));
+            }
+            self.pulses += 1;
+        }
+
+        // Once per quarter note is enough to follow tempo changes
+        self.received = self.received.wrapping_add(1);
+        if self.received % PULSES_PER_QUARTER == 0 {
+            if let Some(tempo) = self.tempo() {
+                events.push(SyncEvent::Tempo(tempo));
+            }
+        }
+    }
+}
+
+// Turns the microsecond stamps of the MIDI input into instants, counted from the first message
+#[derive(Debug, Default)]
+struct StampClock {
+    origin: Option<(u64, Instant)>,
+}
+
+impl StampClock {
+    fn instant(&mut self, stamp: u64, now: Instant) -> Instant {
+        let (first_stamp, first) = *self.origin.get_or_insert((stamp, now));
+        let at = first + Duration::from_micros(stamp.saturating_sub(first_stamp));
+        // The stamps run on another clock, which must not get ahead
+        if at > now {
+            self.origin = Some((stamp, now));
+            return now;
+        }
+        at
+    }
+}
+
+struct SyncState {
+    follower: ClockFollower,
+    stamps: StampClock,
+    queues: Receiver<Producer<TimedSync>>,
+    queue: Option<Producer<TimedSync>>,
+    last_pulse: Option<Instant>,
+    lost: bool,
+}
+
+// Listens for MIDI clock. The engine follows the resulting `SyncEvent`s in the audio thread,
+// the application gets all but the sixteenths to show the state of the sync.
+pub struct MidiSync {
+    _connection: MidiInputConnection<()>,
+    source: SyncSource,
+    alive: Arc<AtomicBool>,
+}
+
+impl MidiSync {
+    pub fn open(config: &MidiConfig) -> Result<MidiSync, anyhow::Error> {
+        let (source, queues) = SyncSource::new();
+        let state = Arc::new(Mutex::new(SyncState {
+            follower: ClockFollower::default(),
+            stamps: StampClock::default(),
+            queues,
+            queue: None,
+            last_pulse: None,
+            lost: false,
+        }));
+
+        let callback_state = state.clone();
+        let connection = super::open_input(config.input_port.as_deref(), PORT_NAME, move |stamp, message| {
+            let now = Instant::now();
+            if let Ok(mut state) = callback_state.lock() {
+                let state = &mut *state;
+                if message.first() == Some(&TIMING_CLOCK) {
+                    state.last_pulse = Some(now);
+                    state.lost = false;
+                }
+                // Every stream that opens brings its own queue
+                while let Ok(queue) = state.queues.try_recv() {
+                    state.queue = Some(queue);
+                }
+                let at = state.stamps.instant(stamp, now);
+                for event in state.follower.on_message(stamp, message) {
+                    // A full queue belongs to a stream that stopped
+                    if let Some(queue) = &mut state.queue {
+                        let _ = queue.push(TimedSync { at, event });
+                    }
+                    if !matches!(event, SyncEvent::Sixteenth(_)) {
+                        input::send(Message::ClockSync(event));
+                    }
+                }
+            }
+        })?;
+
+        let alive = Arc::new(AtomicBool::new(true));
+        let watchdog_alive = alive.clone();
+        std::thread::Builder::new()
+            .name(String::from("midi sync watchdog"))
+            .spawn(move || {
+                while watchdog_alive.load(Ordering::SeqCst) {
+                    std::thread::sleep(WATCHDOG_INTERVAL);
+                    let mut state = match state.lock() {
+                        Ok(state) => state,
+                        Err(_) => return,
+                    };
+                    let silent = state.last_pulse.map_or(false, |last| last.elapsed() > LOST_AFTER);
+                    if silent && !state.lost {
+                        warn!("MIDI clock stopped arriving");
+                        state.lost = true;
+                        input::send(Message::ClockSync(SyncEvent::Lost));
+                    }
+                }
+                debug!("MIDI sync watchdog stopped");
+            })?;
+
+        Ok(MidiSync {
+            _connection: connection,
+            source,
+            alive,
+        })
+    }
+
+    // Hand this to `AudioHandle::set_sync_source`
+    pub fn source(&self) -> SyncSource {
+        self.source.clone()
+    }
+}
+
+impl Debug for MidiSync {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MidiSync")
+    }
+}
+
+impl Drop for MidiSync {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds `count` pulses at the given tempo and returns all events
+    fn pulses(follower: &mut ClockFollower, start: u64, bpm: f64, count: u32) -> Vec<SyncEvent> {
+        let interval = 60_000_000. / (bpm * PULSES_PER_QUARTER as f64);
+        (0..count)
+            .flat_map(|n| follower.on_message(start + (n as f64 * interval) as u64, &[TIMING_CLOCK]))
+            .collect()
+    }
+
+    #[test]
+    fn stamps_never_get_ahead() {
+        let mut stamps = StampClock::default();
+        let now = Instant::now();
+        assert_eq!(stamps.instant(1_000, now), now);
+        assert_eq!(stamps.instant(3_000, now + Duration::from_millis(5)), now + Duration::from_millis(2));
+        // Eight milliseconds of stamps in six
+        assert_eq!(stamps.instant(9_000, now + Duration::from_millis(6)), now + Duration::from_millis(6));
+        assert_eq!(stamps.instant(9_500, now + Duration::from_millis(7)), now + Duration::from_micros(6_500));
+    }
+
+    #[test]
+    fn estimates_tempo() {
+        let mut follower = ClockFollower::default();
+        let events = pulses(&mut follower, 0, 120., 48);
+        let tempos: Vec<f64> = events.iter().filter_map(|e| match e {
+            SyncEvent::Tempo(t) => Some(*t),
+            _ => None,
+        }).collect();
+        assert_eq!(tempos.len(), 2);
+        assert!((tempos[1] - 120.).abs() < 0.01);
+    }
+
+    #[test]
+    fn gaps_restart_the_estimate() {
+        let mut follower = ClockFollower::default();
+        pulses(&mut follower, 0, 60., 48);
+        pulses(&mut follower, 10_000_000, 140., 48);
+        assert!((follower.tempo().unwrap() - 140.).abs() < 0.01);
+    }
+
+    #[test]
+    fn counts_sixteenths_from_start_and_song_position() {
+        let mut follower = ClockFollower::default();
+        assert!(pulses(&mut follower, 0, 120., 12).is_empty());
+
+        assert_eq!(follower.on_message(0, &[START]), vec![SyncEvent::Start]);
+        let sixteenths: Vec<SyncEvent> = pulses(&mut follower, 0, 120., 13)
+            .into_iter()
+            .filter(|e| matches!(e, SyncEvent::Sixteenth(_)))
+            .collect();
+        assert_eq!(sixteenths, vec![SyncEvent::Sixteenth(0), SyncEvent::Sixteenth(1), SyncEvent::Sixteenth(2)]);
+
+        follower.on_message(0, &[STOP]);
+        follower.on_message(0, &[SONG_POSITION, 16, 0]);
+        assert_eq!(follower.on_message(0, &[CONTINUE]), vec![SyncEvent::Continue]);
+        assert_eq!(pulses(&mut follower, 0, 120., 1), vec![SyncEvent::Sixteenth(16)]);
+    }
+}
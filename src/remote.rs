@@ -0,0 +1,411 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::audio::clock::{BeatEvents, BeatSink, QueueProblem, TimedBeat};
+use super::audio::tempo::Bpm;
+use super::audio::AudioMessage;
+use super::config::RemoteConfig;
+use super::input::{self, keymap::Action};
+use super::Message;
+
+pub mod osc;
+#[cfg(unix)]
+pub mod socket;
+pub mod web;
+
+// How often the beat listener looks for new clicks of the engine
+const BEAT_POLL: Duration = Duration::from_millis(1);
+
+// What scripts and remotes can do, as JSON like {"command": "set_bpm", "bpm": 120}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum RemoteCommand {
+    Play,
+    Pause,
+    Toggle,
+    SetBpm { bpm: Bpm },
+    SetVolume { volume: u16 },
+    Tap,
+    NextSong,
+    PreviousSong,
+    // Counted from 1
+    JumpToSong { number: usize },
+}
+
+impl RemoteCommand {
+    // Takes JSON, or the command and its value separated by a space, like "set_bpm 92.5"
+    pub fn parse(line: &str) -> Result<RemoteCommand, anyhow::Error> {
+        let line = line.trim();
+        if line.starts_with('{') {
+            return serde_json::from_str(line).map_err(|e| anyhow!("Invalid command '{}' ({})", line, e));
+        }
+
+        let (name, value) = line.split_once(' ').map_or((line, None), |(n, v)| (n, Some(v.trim())));
+        let field = match name {
+            "set_bpm" => Some("bpm"),
+            "set_volume" => Some("volume"),
+            "jump_to_song" => Some("number"),
+            _ => None,
+        };
+        let mut json = serde_json::Map::new();
+        json.insert(String::from("command"), serde_json::Value::from(name));
+        match (field, value) {
+            (Some(field), Some(value)) => {
+                let value = serde_json::from_str(value).map_err(|_| anyhow!("Invalid value '{}' for {}", value, name))?;
+                json.insert(String::from(field), value);
+            }
+            (Some(field), None) => return Err(anyhow!("{} needs a {}", name, field)),
+            (None, Some(_)) => return Err(anyhow!("{} takes no value", name)),
+            (None, None) => {}
+        }
+        serde_json::from_value(serde_json::Value::Object(json)).map_err(|e| anyhow!("Invalid command '{}' ({})", line, e))
+    }
+
+    // The message doing the same in the window or the headless player
+    pub fn message(&self) -> Result<Message, anyhow::Error> {
+        Ok(match *self {
+            RemoteCommand::Play => Message::Action(Action::Play),
+            RemoteCommand::Pause => Message::Action(Action::Pause),
+            RemoteCommand::Toggle => Message::Action(Action::Toggle),
+            RemoteCommand::SetBpm { bpm } => Message::AudioMessage(AudioMessage::SetBpm(bpm)),
+            RemoteCommand::SetVolume { volume } if volume <= 1000 => Message::VolumeChanged(volume as f32),
+            RemoteCommand::SetVolume { volume } => return Err(anyhow!("Volume must be between 0 and 1000 (got {})", volume)),
+            RemoteCommand::Tap => Message::Action(Action::TapTempo),
+            RemoteCommand::NextSong => Message::Action(Action::NextSong),
+            RemoteCommand::PreviousSong => Message::Action(Action::PreviousSong),
+            RemoteCommand::JumpToSong { number } if number > 0 => Message::Action(Action::JumpToSong(number)),
+            RemoteCommand::JumpToSong { .. } => return Err(anyhow!("Songs are counted from 1")),
+        })
+    }
+}
+
+// What remotes are told, as JSON like {"event": "beat", "bar": 3, "beat": 1, "tick": 0}
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RemoteEvent {
+    // Every click, `tick` is 0 on the beat and counts its subdivisions from 1
+    Beat { bar: u32, beat: u32, tick: u8 },
+    Song {
+        // Counted from 1
+        number: usize,
+        count: usize,
+        title: String,
+        // The starting tempo for songs with a tempo map
+        bpm: Option<Bpm>,
+        next: Option<String>,
+    },
+    // Whenever the tempo changes, also on tempo maps and clock masters
+    Tempo { bpm: Bpm },
+    Error { message: String },
+}
+
+#[derive(Debug, Default)]
+struct Subscribers {
+    senders: Vec<Sender<RemoteEvent>>,
+    // Told to everyone who subscribes later
+    song: Option<RemoteEvent>,
+    tempo: Option<RemoteEvent>,
+}
+
+// Hands events to the clients of the remotes. Clones share their subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct Publisher {
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl Publisher {
+    // Events are delivered until the receiver is dropped
+    pub fn subscribe(&self) -> Receiver<RemoteEvent> {
+        let (sender, receiver) = channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            for event in subscribers.song.iter().chain(&subscribers.tempo) {
+                let _ = sender.send(event.clone());
+            }
+            subscribers.senders.push(sender);
+        }
+        receiver
+    }
+
+    // Tells every subscriber, from any thread. Tempos are only told when they change.
+    pub fn publish(&self, event: RemoteEvent) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            match event {
+                RemoteEvent::Song { .. } => subscribers.song = Some(event.clone()),
+                RemoteEvent::Tempo { .. } if subscribers.tempo.as_ref() == Some(&event) => return,
+                RemoteEvent::Tempo { .. } => subscribers.tempo = Some(event.clone()),
+                _ => {}
+            }
+            subscribers.senders.retain(|sender| sender.send(event.clone()).is_ok());
+        }
+    }
+}
+
+// Sends a command of a client to the window or the headless player.
+// Errors only go back to the client that sent it.
+pub(crate) fn execute(line: &str) -> Result<(), anyhow::Error> {
+    RemoteCommand::parse(line).and_then(|command| command.message()).map(input::send)
+}
+
+// Hands the connections of a listener to `serve` on its own thread, until it is dropped
+pub(crate) struct Acceptor {
+    running: Arc<AtomicBool>,
+    // Connects to the listener, which wakes it up so it sees that it has to stop
+    wake: Box<dyn Fn() + Send>,
+}
+
+impl Acceptor {
+    pub(crate) fn spawn<S, A, W, F>(name: &str, mut accept: A, wake: W, mut serve: F) -> Result<Acceptor, anyhow::Error>
+        where A: FnMut() -> std::io::Result<S> + Send + 'static,
+              W: Fn() + Send + 'static,
+              F: FnMut(S) -> Result<(), anyhow::Error> + Send + 'static
+    {
+        let running = Arc::new(AtomicBool::new(true));
+        let accepting = running.clone();
+        std::thread::Builder::new()
+            .name(String::from(name))
+            .spawn(move || loop {
+                let stream = accept();
+                if !accepting.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Err(e) = stream.map_err(anyhow::Error::from).and_then(&mut serve) {
+                    warn!("Could not accept a remote connection ({:?})", e);
+                }
+            })?;
+        Ok(Acceptor { running, wake: Box::new(wake) })
+    }
+}
+
+impl Debug for Acceptor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Acceptor(running: {})", self.running.load(Ordering::SeqCst))
+    }
+}
+
+impl Drop for Acceptor {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        (self.wake)();
+    }
+}
+
+// The servers enabled in the config, they stop when this is dropped
+#[derive(Debug, Default)]
+pub struct Remotes {
+    publisher: Publisher,
+    beat_sink: Option<BeatSink>,
+    #[cfg(unix)]
+    socket: Option<socket::SocketServer>,
+    osc: Option<osc::OscServer>,
+    beat_sender: Option<osc::BeatSender>,
+    web: Option<web::WebServer>,
+}
+
+impl Remotes {
+    // Servers that cannot be started are left out and reported to `on_error`
+    pub fn open<F>(config: &RemoteConfig, mut on_error: F) -> Remotes
+        where F: FnMut(anyhow::Error)
+    {
+        let mut remotes = Remotes::default();
+
+        #[cfg(unix)]
+        if config.socket {
+            match socket::SocketServer::open(&config.socket_path(), remotes.publisher.clone()) {
+                Ok(server) => remotes.socket = Some(server),
+                Err(e) => on_error(e),
+            }
+        }
+        #[cfg(not(unix))]
+        if config.socket {
+            on_error(anyhow!("The control socket is only available on Unix"));
+        }
+
+        if config.osc {
+            match osc::OscServer::open(config.osc_listen) {
+                Ok(server) => remotes.osc = Some(server),
+                Err(e) => on_error(e),
+            }
+        }
+        if let Some(target) = config.osc_beat_target {
+            match osc::BeatSender::open(target, remotes.publisher.clone()) {
+                Ok(sender) => remotes.beat_sender = Some(sender),
+                Err(e) => on_error(e),
+            }
+        }
+        if config.web {
+            match web::WebServer::open(config.web_listen, config.web_token.clone(), config.web_hosts.clone(), remotes.publisher.clone()) {
+                Ok(server) => remotes.web = Some(server),
+                Err(e) => on_error(e),
+            }
+        }
+
+        if remotes.is_running() {
+            match publish_beats(remotes.publisher.clone()) {
+                Ok(sink) => remotes.beat_sink = Some(sink),
+                Err(e) => on_error(e),
+            }
+            info!("Remote control is running");
+        }
+        remotes
+    }
+
+    // Events for the clients, even if no server is running yet
+    pub fn publisher(&self) -> Publisher {
+        self.publisher.clone()
+    }
+
+    // Hand this to `AudioHandle::set_beat_sink`, None if no server is running
+    pub fn beat_sink(&self) -> Option<BeatSink> {
+        self.beat_sink.clone()
+    }
+
+    fn is_running(&self) -> bool {
+        #[cfg(unix)]
+        if self.socket.is_some() {
+            return true;
+        }
+        self.osc.is_some() || self.beat_sender.is_some() || self.web.is_some()
+    }
+}
+
+// Publishes the clicks of the engine when they are heard
+fn publish_beats(publisher: Publisher) -> Result<BeatSink, anyhow::Error> {
+    let (sink, queues) = BeatSink::new();
+    let status = sink.status().clone();
+    std::thread::Builder::new()
+        .name(String::from("remote beats"))
+        .spawn(move || {
+            let mut events: Option<BeatEvents> = None;
+            loop {
+                // Every stream that opens brings its own queue, the previous stream is closed
+                match queues.try_recv() {
+                    Ok(queue) => {
+                        events = Some(queue);
+                        continue;
+                    }
+                    // Ends once the audio engine and the remotes dropped their sinks
+                    Err(TryRecvError::Disconnected) if events.as_ref().map_or(true, |e| e.is_abandoned() && e.is_empty()) => break,
+                    Err(_) => {}
+                }
+                let TimedBeat { at, event } = match events.as_mut().and_then(|e| e.pop().ok()) {
+                    Some(timed) => timed,
+                    None => {
+                        if let Some(QueueProblem::Overflowed) = status.take_problem() {
+                            warn!("The remotes fell behind, beats were dropped");
+                        }
+                        std::thread::sleep(BEAT_POLL);
+                        continue;
+                    }
+                };
+                let now = Instant::now();
+                if at > now {
+                    std::thread::sleep(at - now);
+                }
+                publisher.publish(RemoteEvent::Tempo { bpm: event.bpm });
+                publisher.publish(RemoteEvent::Beat { bar: event.bar, beat: event.beat, tick: event.tick });
+            }
+            debug!("Stopped publishing beats");
+        })?;
+    Ok(sink)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_json_and_plain_commands() {
+        assert_eq!(RemoteCommand::parse(r#"{"command": "play"}"#).unwrap(), RemoteCommand::Play);
+        assert_eq!(
+            RemoteCommand::parse(r#"{"command": "set_bpm", "bpm": 92.5}"#).unwrap(),
+            RemoteCommand::SetBpm { bpm: "92.5".parse().unwrap() }
+        );
+        assert_eq!(RemoteCommand::parse("next_song").unwrap(), RemoteCommand::NextSong);
+        assert_eq!(RemoteCommand::parse(" set_volume 500 ").unwrap(), RemoteCommand::SetVolume { volume: 500 });
+        assert_eq!(RemoteCommand::parse("jump_to_song 3").unwrap(), RemoteCommand::JumpToSong { number: 3 });
+
+        assert!(RemoteCommand::parse("set_bpm").unwrap_err().to_string().contains("needs a bpm"));
+        assert!(RemoteCommand::parse("play now").is_err());
+        assert!(RemoteCommand::parse("set_bpm 0").is_err());
+        assert!(RemoteCommand::parse("rewind").is_err());
+        assert!(RemoteCommand::parse(r#"{"command": "set_bpm"}"#).is_err());
+        assert!(RemoteCommand::parse("{").is_err());
+    }
+
+    #[test]
+    fn becomes_the_same_messages_as_keys() {
+        assert!(matches!(RemoteCommand::Toggle.message().unwrap(), Message::Action(Action::Toggle)));
+        assert!(matches!(RemoteCommand::JumpToSong { number: 2 }.message().unwrap(), Message::Action(Action::JumpToSong(2))));
+        assert!(RemoteCommand::JumpToSong { number: 0 }.message().is_err());
+        assert!(RemoteCommand::SetVolume { volume: 1001 }.message().is_err());
+    }
+
+    #[test]
+    fn subscribers_get_the_current_song_first() {
+        let publisher = Publisher::default();
+        let early = publisher.subscribe();
+        publisher.publish(RemoteEvent::Beat { bar: 1, beat: 1, tick: 0 });
+        let song = RemoteEvent::Song { number: 1, count: 1, title: String::from("Song"), bpm: None, next: None };
+        publisher.publish(song.clone());
+        assert_eq!(early.try_iter().collect::<Vec<_>>(), vec![RemoteEvent::Beat { bar: 1, beat: 1, tick: 0 }, song.clone()]);
+
+        let tempo = RemoteEvent::Tempo { bpm: Bpm::whole(90).unwrap() };
+        publisher.publish(tempo.clone());
+        publisher.publish(tempo.clone());
+        assert_eq!(early.try_iter().collect::<Vec<_>>(), vec![tempo.clone()]);
+
+        let late = publisher.clone().subscribe();
+        publisher.publish(RemoteEvent::Beat { bar: 1, beat: 2, tick: 0 });
+        assert_eq!(late.try_iter().collect::<Vec<_>>(), vec![song, tempo, RemoteEvent::Beat { bar: 1, beat: 2, tick: 0 }]);
+        // Other publishers have their own subscribers
+        assert!(Publisher::default().subscribe().try_recv().is_err());
+    }
+
+    #[test]
+    fn publishes_beats_when_they_are_heard() {
+        use crate::audio::clock::{BeatEvent, BeatOutput};
+        use crate::audio::meter::BeatClass;
+
+        let publisher = Publisher::default();
+        let events = publisher.subscribe();
+        let sink = publish_beats(publisher).unwrap();
+        let mut output = BeatOutput::new(sink.queue());
+        let start = Instant::now();
+        let bpm = Bpm::whole(120).unwrap();
+        let beats = output.events().unwrap();
+        beats.push((0, BeatEvent { bar: 2, beat: 1, tick: 0, class: BeatClass::Accent, bpm }));
+        beats.push((50, BeatEvent { bar: 2, beat: 1, tick: 1, class: BeatClass::Subdivision, bpm }));
+        output.flush(start, 1000.);
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(events.recv_timeout(timeout).unwrap(), RemoteEvent::Tempo { bpm });
+        assert_eq!(events.recv_timeout(timeout).unwrap(), RemoteEvent::Beat { bar: 2, beat: 1, tick: 0 });
+        assert_eq!(events.recv_timeout(timeout).unwrap(), RemoteEvent::Beat { bar: 2, beat: 1, tick: 1 });
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn events_are_json() {
+        let beat = serde_json::to_string(&RemoteEvent::Beat { bar: 3, beat: 1, tick: 2 }).unwrap();
+        assert_eq!(beat, r#"{"event":"beat","bar":3,"beat":1,"tick":2}"#);
+
+        let song = RemoteEvent::Song {
+            number: 2,
+            count: 5,
+            title: String::from("Song"),
+            bpm: Some("92.5".parse().unwrap()),
+            next: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&song).unwrap(),
+            r#"{"event":"song","number":2,"count":5,"title":"Song","bpm":92.5,"next":null}"#
+        );
+    }
+}
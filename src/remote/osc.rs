@@ -0,0 +1,379 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use log::{debug, info, trace, warn};
+
+use super::{Publisher, RemoteCommand, RemoteEvent};
+use crate::audio::tempo::Bpm;
+use crate::input;
+
+const PREFIX: &str = "/metronome";
+const BUNDLE: &[u8] = b"#bundle\0";
+// Larger packets do not fit into a UDP datagram anyway
+const MAX_PACKET: usize = 65507;
+// How often the threads check whether they have to stop
+const STOP_CHECK: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    String(String),
+    Bool(bool),
+    Nil,
+}
+
+impl OscArg {
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            OscArg::Int(value) => Some(*value as f64),
+            OscArg::Long(value) => Some(*value as f64),
+            OscArg::Float(value) => Some(*value as f64),
+            OscArg::Double(value) => Some(*value),
+            OscArg::String(value) => value.trim().parse().ok(),
+            OscArg::Bool(value) => Some(if *value { 1. } else { 0. }),
+            OscArg::Nil => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(address: &str, args: Vec<OscArg>) -> OscMessage {
+        OscMessage {
+            address: String::from(address),
+            args,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_string(&mut bytes, &self.address);
+        let mut tags = String::from(",");
+        for arg in &self.args {
+            tags.push(match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Long(_) => 'h',
+                OscArg::Float(_) => 'f',
+                OscArg::Double(_) => 'd',
+                OscArg::String(_) => 's',
+                OscArg::Bool(true) => 'T',
+                OscArg::Bool(false) => 'F',
+                OscArg::Nil => 'N',
+            });
+        }
+        write_string(&mut bytes, &tags);
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+                OscArg::Long(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+                OscArg::Float(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+                OscArg::Double(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+                OscArg::String(value) => write_string(&mut bytes, value),
+                OscArg::Bool(_) | OscArg::Nil => {}
+            }
+        }
+        bytes
+    }
+
+    // The command of a message to /metronome/..., None for button releases.
+    // Buttons of most OSC controllers send 1 when pressed and 0 when released.
+    pub fn command(&self) -> Result<Option<RemoteCommand>, anyhow::Error> {
+        let action = self.address
+            .strip_prefix(PREFIX)
+            .ok_or_else(|| anyhow!("Address {} does not start with {}", self.address, PREFIX))?;
+        let value = self.args.first().and_then(OscArg::as_f64);
+        let number = || value.ok_or_else(|| anyhow!("{} needs a number", self.address));
+
+        let command = match action {
+            "/bpm" => {
+                let bpm = Bpm::from_f64(number()?)?;
+                return Ok(Some(RemoteCommand::SetBpm { bpm }));
+            }
+            "/volume" => {
+                let volume = number()?;
+                if !(0. ..=1000.).contains(&volume) {
+                    return Err(anyhow!("Volume must be between 0 and 1000 (got {})", volume));
+                }
+                return Ok(Some(RemoteCommand::SetVolume { volume: volume.round() as u16 }));
+            }
+            "/song" => {
+                let number = number()?;
+                if number < 1. || number.fract() != 0. {
+                    return Err(anyhow!("Invalid song number {}", number));
+                }
+                return Ok(Some(RemoteCommand::JumpToSong { number: number as usize }));
+            }
+            "/play" => RemoteCommand::Play,
+            "/pause" => RemoteCommand::Pause,
+            "/toggle" => RemoteCommand::Toggle,
+            "/tap" => RemoteCommand::Tap,
+            "/song/next" => RemoteCommand::NextSong,
+            "/song/previous" => RemoteCommand::PreviousSong,
+            _ => return Err(anyhow!("Unknown address {}", self.address)),
+        };
+        if value == Some(0.) {
+            return Ok(None);
+        }
+        Ok(Some(command))
+    }
+}
+
+// The messages of a packet, with bundles unpacked. Time tags are ignored, everything happens right away.
+pub fn decode(packet: &[u8]) -> Result<Vec<OscMessage>, anyhow::Error> {
+    let mut messages = Vec::new();
+    decode_into(packet, &mut messages)?;
+    Ok(messages)
+}
+
+fn decode_into(packet: &[u8], messages: &mut Vec<OscMessage>) -> Result<(), anyhow::Error> {
+    if packet.starts_with(BUNDLE) {
+        // Skips the time tag
+        let mut pos = BUNDLE.len() + 8;
+        while pos < packet.len() {
+            let size = read_i32(packet, &mut pos)?;
+            let end = usize::try_from(size).ok()
+                .and_then(|size| pos.checked_add(size))
+                .filter(|end| *end <= packet.len())
+                .ok_or_else(|| anyhow!("Bundle element at byte {} is cut off", pos))?;
+            decode_into(&packet[pos..end], messages)?;
+            pos = end;
+        }
+        return Ok(());
+    }
+
+    let mut pos = 0;
+    let address = read_string(packet, &mut pos)?;
+    if !address.starts_with('/') {
+        return Err(anyhow!("Invalid address '{}'", address));
+    }
+    // Type tags are optional in old implementations
+    let tags = if pos < packet.len() { read_string(packet, &mut pos)? } else { String::from(",") };
+    let tags = tags.strip_prefix(',').ok_or_else(|| anyhow!("Type tags of {} do not start with ','", address))?;
+
+    let mut args = Vec::new();
+    for tag in tags.chars() {
+        args.push(match tag {
+            'i' => OscArg::Int(read_i32(packet, &mut pos)?),
+            'h' => OscArg::Long(i64::from_be_bytes(take(packet, &mut pos)?)),
+            'f' => OscArg::Float(f32::from_be_bytes(take(packet, &mut pos)?)),
+            'd' => OscArg::Double(f64::from_be_bytes(take(packet, &mut pos)?)),
+            's' | 'S' => OscArg::String(read_string(packet, &mut pos)?),
+            'T' => OscArg::Bool(true),
+            'F' => OscArg::Bool(false),
+            'N' | 'I' => OscArg::Nil,
+            tag => return Err(anyhow!("Unsupported type tag '{}' in {}", tag, address)),
+        });
+    }
+    messages.push(OscMessage { address, args });
+    Ok(())
+}
+
+fn take<const N: usize>(packet: &[u8], pos: &mut usize) -> Result<[u8; N], anyhow::Error> {
+    let bytes = packet
+        .get(*pos..*pos + N)
+        .ok_or_else(|| anyhow!("Packet ends at byte {}, expected {} more bytes", packet.len(), N))?;
+    *pos += N;
+    Ok(bytes.try_into().unwrap())
+}
+
+fn read_i32(packet: &[u8], pos: &mut usize) -> Result<i32, anyhow::Error> {
+    Ok(i32::from_be_bytes(take(packet, pos)?))
+}
+
+// Strings end with a zero and are padded to four bytes
+fn read_string(packet: &[u8], pos: &mut usize) -> Result<String, anyhow::Error> {
+    let rest = packet.get(*pos..).unwrap_or_default();
+    let length = rest
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| anyhow!("String at byte {} does not end", pos))?;
+    let string = String::from_utf8_lossy(&rest[..length]).into_owned();
+    *pos += (length / 4 + 1) * 4;
+    Ok(string)
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &str) {
+    bytes.extend_from_slice(string.as_bytes());
+    let padding = 4 - string.len() % 4;
+    bytes.resize(bytes.len() + padding, 0);
+}
+
+// Takes commands from lighting and video desks
+#[derive(Debug)]
+pub struct OscServer {
+    running: Arc<AtomicBool>,
+}
+
+impl OscServer {
+    pub fn open(address: SocketAddr) -> Result<OscServer, anyhow::Error> {
+        let socket = UdpSocket::bind(address).with_context(|| format!("Could not listen for OSC on {}", address))?;
+        socket.set_read_timeout(Some(STOP_CHECK))?;
+        let running = Arc::new(AtomicBool::new(true));
+        let receiving = running.clone();
+        std::thread::Builder::new()
+            .name(String::from("osc server"))
+            .spawn(move || {
+                let mut packet = vec![0u8; MAX_PACKET];
+                while receiving.load(Ordering::SeqCst) {
+                    let (size, from) = match socket.recv_from(&mut packet) {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    match decode(&packet[..size]) {
+                        Ok(messages) => messages.iter().for_each(|message| handle(message, from)),
+                        Err(e) => warn!("Ignoring malformed OSC packet from {} ({})", from, e),
+                    }
+                }
+            })?;
+        info!("Listening for OSC on {}", address);
+        Ok(OscServer { running })
+    }
+}
+
+impl Drop for OscServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+fn handle(message: &OscMessage, from: SocketAddr) {
+    trace!("OSC {} {:?} from {}", message.address, message.args, from);
+    match message.command().and_then(|command| command.map(|c| c.message()).transpose()) {
+        Ok(Some(message)) => input::send(message),
+        Ok(None) => {}
+        Err(e) => warn!("Ignoring OSC message {} from {} ({:#})", message.address, from, e),
+    }
+}
+
+// Sends /metronome/beat <bar> <beat> on every beat, /metronome/tick <bar> <beat> <tick> on every
+// subdivision and /metronome/song <number> <title> on song changes
+#[derive(Debug)]
+pub struct BeatSender {
+    running: Arc<AtomicBool>,
+}
+
+impl BeatSender {
+    pub fn open(target: SocketAddr, publisher: Publisher) -> Result<BeatSender, anyhow::Error> {
+        let any: SocketAddr = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+        let socket = UdpSocket::bind(any)?;
+        // So the target can be a broadcast address
+        socket.set_broadcast(true)?;
+        socket.connect(target).with_context(|| format!("Could not send OSC to {}", target))?;
+
+        let events = publisher.subscribe();
+        let running = Arc::new(AtomicBool::new(true));
+        let sending = running.clone();
+        std::thread::Builder::new()
+            .name(String::from("osc beats"))
+            .spawn(move || {
+                while sending.load(Ordering::SeqCst) {
+                    let message = match events.recv_timeout(STOP_CHECK) {
+                        Ok(RemoteEvent::Beat { bar, beat, tick: 0 }) => {
+                            OscMessage::new("/metronome/beat", vec![OscArg::Int(bar as i32), OscArg::Int(beat as i32)])
+                        }
+                        Ok(RemoteEvent::Beat { bar, beat, tick }) => {
+                            let args = vec![OscArg::Int(bar as i32), OscArg::Int(beat as i32), OscArg::Int(tick as i32)];
+                            OscMessage::new("/metronome/tick", args)
+                        }
+                        Ok(RemoteEvent::Song { number, title, .. }) => {
+                            OscMessage::new("/metronome/song", vec![OscArg::Int(number as i32), OscArg::String(title)])
+                        }
+                        Ok(RemoteEvent::Tempo { .. }) | Ok(RemoteEvent::Error { .. }) | Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    if let Err(e) = socket.send(&message.encode()) {
+                        debug!("Could not send {} ({:?})", message.address, e);
+                    }
+                }
+            })?;
+        info!("Sending OSC beats to {}", target);
+        Ok(BeatSender { running })
+    }
+}
+
+impl Drop for BeatSender {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(address: &str, args: Vec<OscArg>) -> OscMessage {
+        OscMessage::new(address, args)
+    }
+
+    #[test]
+    fn round_trips_messages_and_bundles() {
+        let bpm = message("/metronome/bpm", vec![OscArg::Float(92.5), OscArg::String(String::from("abcd")), OscArg::Bool(true)]);
+        let bytes = bpm.encode();
+        assert_eq!(&bytes[..16], b"/metronome/bpm\0\0");
+        assert_eq!(&bytes[16..24], b",fsT\0\0\0\0");
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(decode(&bytes).unwrap(), vec![bpm.clone()]);
+
+        let play = message("/metronome/play", vec![]).encode();
+        let mut bundle = BUNDLE.to_vec();
+        bundle.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        for element in [&bytes, &play] {
+            bundle.extend_from_slice(&(element.len() as i32).to_be_bytes());
+            bundle.extend_from_slice(element);
+        }
+        let messages = decode(&bundle).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].address, "/metronome/play");
+
+        assert!(decode(b"/metronome/bpm\0\0,f\0\0\0\0").unwrap_err().to_string().contains("expected 4 more bytes"));
+        assert!(decode(b"metronome\0\0\0").is_err());
+        assert!(decode(&bundle[..bundle.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn maps_addresses_to_commands() {
+        let command = |address: &str, args: Vec<OscArg>| message(address, args).command();
+        assert_eq!(command("/metronome/bpm", vec![OscArg::Int(120)]).unwrap(), Some(RemoteCommand::SetBpm { bpm: Bpm::whole(120).unwrap() }));
+        assert_eq!(command("/metronome/bpm", vec![OscArg::String(String::from("92.5"))]).unwrap(), Some(RemoteCommand::SetBpm { bpm: "92.5".parse().unwrap() }));
+        assert_eq!(command("/metronome/song/next", vec![]).unwrap(), Some(RemoteCommand::NextSong));
+        assert_eq!(command("/metronome/song", vec![OscArg::Float(3.)]).unwrap(), Some(RemoteCommand::JumpToSong { number: 3 }));
+        assert_eq!(command("/metronome/volume", vec![OscArg::Double(499.6)]).unwrap(), Some(RemoteCommand::SetVolume { volume: 500 }));
+        // Button press and release
+        assert_eq!(command("/metronome/play", vec![OscArg::Float(1.)]).unwrap(), Some(RemoteCommand::Play));
+        assert_eq!(command("/metronome/play", vec![OscArg::Float(0.)]).unwrap(), None);
+
+        assert!(command("/metronome/bpm", vec![]).is_err());
+        assert!(command("/metronome/song", vec![OscArg::Float(1.5)]).is_err());
+        assert!(command("/metronome/rewind", vec![]).is_err());
+        assert!(command("/lights/play", vec![]).is_err());
+    }
+
+    #[test]
+    fn sends_beats_on_loopback() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let publisher = Publisher::default();
+        let sender = BeatSender::open(receiver.local_addr().unwrap(), publisher.clone()).unwrap();
+
+        let mut packet = [0u8; 256];
+        publisher.publish(RemoteEvent::Beat { bar: 12, beat: 2, tick: 0 });
+        let size = receiver.recv(&mut packet).unwrap();
+        assert_eq!(decode(&packet[..size]).unwrap(), vec![message("/metronome/beat", vec![OscArg::Int(12), OscArg::Int(2)])]);
+        publisher.publish(RemoteEvent::Beat { bar: 12, beat: 2, tick: 1 });
+        let size = receiver.recv(&mut packet).unwrap();
+        assert_eq!(decode(&packet[..size]).unwrap(), vec![message("/metronome/tick", vec![OscArg::Int(12), OscArg::Int(2), OscArg::Int(1)])]);
+        drop(sender);
+    }
+}
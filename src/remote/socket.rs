@@ -0,0 +1,138 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use log::{debug, info};
+
+use super::{execute, Acceptor, Publisher, RemoteEvent};
+
+// How often a client without events checks whether it disconnected
+const IDLE_CHECK: Duration = Duration::from_secs(1);
+
+// Newline separated commands in, newline separated events out, both JSON
+#[derive(Debug)]
+pub struct SocketServer {
+    // Removes the socket file once it stopped
+    _acceptor: Acceptor,
+}
+
+impl SocketServer {
+    pub fn open(path: &Path, publisher: Publisher) -> Result<SocketServer, anyhow::Error> {
+        if path.exists() {
+            // A socket left behind by a crash can be replaced, one that is in use cannot
+            if UnixStream::connect(path).is_ok() {
+                return Err(anyhow!("Control socket {} is already in use", path.display()));
+            }
+            std::fs::remove_file(path).with_context(|| format!("Could not remove the old control socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Could not create the control socket {}", path.display()))?;
+
+        let socket = path.to_path_buf();
+        let wake = move || {
+            let _ = UnixStream::connect(&socket);
+            let _ = std::fs::remove_file(&socket);
+        };
+        let acceptor = Acceptor::spawn("control socket", move || listener.accept().map(|(stream, _)| stream), wake, move |stream| {
+            serve(stream, &publisher)
+        })?;
+
+        info!("Listening for control commands on {}", path.display());
+        Ok(SocketServer { _acceptor: acceptor })
+    }
+}
+
+fn serve(stream: UnixStream, publisher: &Publisher) -> Result<(), anyhow::Error> {
+    debug!("Control client connected");
+    let reader = BufReader::new(stream.try_clone()?);
+    let writer = Arc::new(Mutex::new(stream));
+    let connected = Arc::new(AtomicBool::new(true));
+
+    let events = publisher.subscribe();
+    let event_writer = writer.clone();
+    let still_connected = connected.clone();
+    std::thread::Builder::new()
+        .name(String::from("control events"))
+        .spawn(move || loop {
+            match events.recv_timeout(IDLE_CHECK) {
+                Ok(event) => {
+                    if write_event(&event_writer, &event).is_err() {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) if still_connected.load(Ordering::SeqCst) => {}
+                Err(_) => break,
+            }
+        })?;
+
+    std::thread::Builder::new()
+        .name(String::from("control client"))
+        .spawn(move || {
+            for line in reader.lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if let Err(e) = execute(&line) {
+                    let error = RemoteEvent::Error { message: format!("{:#}", e) };
+                    if write_event(&writer, &error).is_err() {
+                        break;
+                    }
+                }
+            }
+            connected.store(false, Ordering::SeqCst);
+            debug!("Control client disconnected");
+        })?;
+    Ok(())
+}
+
+fn write_event(writer: &Mutex<UnixStream>, event: &RemoteEvent) -> Result<(), anyhow::Error> {
+    let mut line = serde_json::to_string(event)?;
+    line.push('\n');
+    let mut stream = writer.lock().map_err(|_| anyhow!("Control client writer is poisoned"))?;
+    stream.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn read_line(stream: &mut UnixStream) -> String {
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while stream.read_exact(&mut byte).is_ok() && byte[0] != b'\n' {
+            line.push(byte[0]);
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    #[test]
+    fn streams_events_and_reports_bad_commands() {
+        let path = std::env::temp_dir().join(format!("metronome-{}.sock", std::process::id()));
+        let publisher = Publisher::default();
+        let server = SocketServer::open(&path, publisher.clone()).unwrap();
+        assert!(SocketServer::open(&path, publisher.clone()).is_err());
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"rewind\n").unwrap();
+        let error = read_line(&mut client);
+        assert!(error.starts_with(r#"{"event":"error","message":"Invalid command 'rewind'"#), "{}", error);
+
+        publisher.publish(RemoteEvent::Beat { bar: 7, beat: 3, tick: 0 });
+        assert_eq!(read_line(&mut client), r#"{"event":"beat","bar":7,"beat":3,"tick":0}"#);
+
+        drop(server);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,412 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::TryRecvError;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use log::{debug, info};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message as WsMessage, WebSocket};
+
+use super::{execute, Acceptor, Publisher, RemoteEvent};
+
+const PAGE: &str = include_str!("page.html");
+// Longer requests are not sent by the page
+const MAX_REQUEST: usize = 16 * 1024;
+// How long a WebSocket client waits for commands before it sends the events that arrived meanwhile
+const POLL: Duration = Duration::from_millis(20);
+// Clients that do not finish their request in time are dropped, however slowly they keep sending
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Every client has a thread of its own, more are turned away
+const MAX_CLIENTS: usize = 16;
+
+// Serves the control page on / and events and commands on the WebSocket /ws.
+// Commands can also be posted to /command, in the same format as on the control socket.
+// Both only take requests from the page itself, not from other sites open in the browser,
+// only by IP address, localhost or a configured host name, and with a token only if it is
+// passed as ?token=... or as a bearer token.
+#[derive(Debug)]
+pub struct WebServer {
+    // With the port picked by the system if the configured one was 0
+    #[cfg(test)]
+    address: SocketAddr,
+    _acceptor: Acceptor,
+}
+
+impl WebServer {
+    pub fn open(address: SocketAddr, token: Option<String>, hosts: Vec<String>, publisher: Publisher) -> Result<WebServer, anyhow::Error> {
+        let listener = TcpListener::bind(address).with_context(|| format!("Could not start the web remote on {}", address))?;
+        let address = listener.local_addr()?;
+
+        // Unspecified addresses cannot be connected to, the listener is woken up on loopback
+        let mut local = address;
+        if local.ip().is_unspecified() {
+            local.set_ip(match local.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        let wake = move || {
+            let _ = TcpStream::connect(local);
+        };
+        let access = Arc::new(Access { token, hosts });
+        let clients = Arc::new(AtomicUsize::new(0));
+        let acceptor = Acceptor::spawn("web remote", move || listener.accept().map(|(stream, _)| stream), wake, move |stream| {
+            let slot = match ClientSlot::take(&clients) {
+                Some(slot) => slot,
+                None => {
+                    debug!("Turning away a web client, {} are connected already", MAX_CLIENTS);
+                    return Ok(());
+                }
+            };
+            let (access, publisher) = (access.clone(), publisher.clone());
+            std::thread::Builder::new()
+                .name(String::from("web client"))
+                .spawn(move || {
+                    let _slot = slot;
+                    if let Err(e) = serve(stream, &access, &publisher) {
+                        debug!("Web client failed ({:#})", e);
+                    }
+                })?;
+            Ok(())
+        })?;
+
+        info!("Web remote is running on http://{}", address);
+        Ok(WebServer {
+            #[cfg(test)]
+            address,
+            _acceptor: acceptor,
+        })
+    }
+}
+
+// Counts a connected client until it is dropped
+struct ClientSlot(Arc<AtomicUsize>);
+
+impl ClientSlot {
+    fn take(clients: &Arc<AtomicUsize>) -> Option<ClientSlot> {
+        clients
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < MAX_CLIENTS).then(|| n + 1))
+            .ok()
+            .map(|_| ClientSlot(clients.clone()))
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+struct Access {
+    token: Option<String>,
+    hosts: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    fn query(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.path.split_once('?')?;
+        query.split('&').filter_map(|pair| pair.split_once('=')).find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+
+    // Browsers send the origin of the page that makes the request, other clients usually send none
+    fn is_same_origin(&self) -> bool {
+        let origin = match self.header("Origin") {
+            Some(origin) => origin,
+            None => return true,
+        };
+        let host = origin.strip_prefix("http://").or_else(|| origin.strip_prefix("https://"));
+        matches!((host, self.header("Host")), (Some(origin), Some(host)) if origin.eq_ignore_ascii_case(host))
+    }
+
+    // A site that points its own domain at this machine sends a matching origin, but its own
+    // domain as the host
+    fn is_known_host(&self, hosts: &[String]) -> bool {
+        let host = match self.header("Host") {
+            Some(host) => host,
+            None => return true,
+        };
+        let name = match host.strip_prefix('[') {
+            Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+            None => host.split(':').next().unwrap_or_default(),
+        };
+        name.parse::<IpAddr>().is_ok()
+            || name.eq_ignore_ascii_case("localhost")
+            || hosts.iter().any(|known| known.eq_ignore_ascii_case(name))
+    }
+
+    fn has_token(&self, token: Option<&str>) -> bool {
+        let token = match token {
+            Some(token) => token,
+            None => return true,
+        };
+        let bearer = self.header("Authorization").and_then(|value| value.strip_prefix("Bearer "));
+        self.query("token").or(bearer) == Some(token)
+    }
+}
+
+// Fills `buf` unless the deadline passes first
+fn read_before(stream: &mut TcpStream, buf: &mut [u8], deadline: Instant) -> Result<(), anyhow::Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(anyhow!("Request took longer than {:?}", REQUEST_TIMEOUT));
+        }
+        stream.set_read_timeout(Some(left))?;
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => return Err(anyhow!("Connection closed during the request")),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+// Reads byte by byte, so nothing the client sends after the headers is lost before a WebSocket takes over
+fn read_request(stream: &mut TcpStream) -> Result<Request, anyhow::Error> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST {
+            return Err(anyhow!("Request headers are too long"));
+        }
+        read_before(stream, &mut byte, deadline)?;
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(anyhow!("Invalid request line")),
+    };
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut request = Request { method, path, headers, body: Vec::new() };
+    let length: usize = request.header("Content-Length").and_then(|l| l.parse().ok()).unwrap_or(0);
+    if length > MAX_REQUEST {
+        return Err(anyhow!("Request body is too long"));
+    }
+    request.body.resize(length, 0);
+    read_before(stream, &mut request.body, deadline)?;
+    Ok(request)
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> Result<(), anyhow::Error> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    Ok(())
+}
+
+fn serve(mut stream: TcpStream, access: &Access, publisher: &Publisher) -> Result<(), anyhow::Error> {
+    let request = read_request(&mut stream)?;
+    debug!("{} {} from {}", request.method, request.path.split('?').next().unwrap_or_default(), stream.peer_addr()?);
+    let path = request.path.split('?').next().unwrap_or_default();
+    match (request.method.as_str(), path) {
+        ("GET", "/") | ("GET", "/index.html") => respond(&mut stream, "200 OK", "text/html; charset=utf-8", PAGE.as_bytes()),
+        (_, "/ws") | (_, "/command") if !request.is_known_host(&access.hosts) => {
+            respond(&mut stream, "403 Forbidden", "text/plain", b"Unknown host name, add it to web_hosts\n")
+        }
+        (_, "/ws") | (_, "/command") if !request.is_same_origin() => {
+            respond(&mut stream, "403 Forbidden", "text/plain", b"Only the control page may send commands\n")
+        }
+        (_, "/ws") | (_, "/command") if !request.has_token(access.token.as_deref()) => {
+            respond(&mut stream, "401 Unauthorized", "text/plain", b"Missing or wrong token\n")
+        }
+        ("GET", "/ws") => match request.header("Sec-WebSocket-Key") {
+            Some(key) => {
+                let accept = derive_accept_key(key.as_bytes());
+                let head = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    accept
+                );
+                stream.write_all(head.as_bytes())?;
+                serve_websocket(WebSocket::from_raw_socket(stream, Role::Server, None), publisher)
+            }
+            None => respond(&mut stream, "400 Bad Request", "text/plain", b"Expected a WebSocket upgrade\n"),
+        },
+        ("POST", "/command") => {
+            match execute(&String::from_utf8_lossy(&request.body)) {
+                Ok(()) => respond(&mut stream, "204 No Content", "text/plain", b""),
+                Err(e) => respond(&mut stream, "400 Bad Request", "text/plain", format!("{:#}\n", e).as_bytes()),
+            }
+        }
+        ("GET", _) | ("POST", _) => respond(&mut stream, "404 Not Found", "text/plain", b"Not found\n"),
+        _ => respond(&mut stream, "405 Method Not Allowed", "text/plain", b"Method not allowed\n"),
+    }
+}
+
+fn serve_websocket(mut socket: WebSocket<TcpStream>, publisher: &Publisher) -> Result<(), anyhow::Error> {
+    debug!("Web remote client connected");
+    socket.get_mut().set_read_timeout(Some(POLL))?;
+    let events = publisher.subscribe();
+    loop {
+        loop {
+            match events.try_recv() {
+                Ok(event) => socket.write_message(WsMessage::Text(serde_json::to_string(&event)?))?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+
+        let text = match socket.read_message() {
+            Ok(WsMessage::Text(text)) => text,
+            Ok(WsMessage::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => break,
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if let Err(e) = execute(&text) {
+            let error = RemoteEvent::Error { message: format!("{:#}", e) };
+            socket.write_message(WsMessage::Text(serde_json::to_string(&error)?))?;
+        }
+    }
+    debug!("Web remote client disconnected");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(publisher: Publisher) -> WebServer {
+        WebServer::open("127.0.0.1:0".parse().unwrap(), None, vec![String::from("stage.local")], publisher).unwrap()
+    }
+
+    fn request(server: &WebServer, request: &str) -> String {
+        let mut stream = TcpStream::connect(server.address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_the_page() {
+        let server = server(Publisher::default());
+        let page = request(&server, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\n"), "{}", page);
+        assert!(page.contains("new WebSocket"));
+
+        assert!(request(&server, "GET /setlist.yaml HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        assert!(request(&server, "GET /ws HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 400"));
+        let bad = request(&server, "POST /command HTTP/1.1\r\nContent-Length: 6\r\n\r\nrewind");
+        assert!(bad.starts_with("HTTP/1.1 400") && bad.contains("Invalid command 'rewind'"), "{}", bad);
+    }
+
+    #[test]
+    fn refuses_other_sites_and_missing_tokens() {
+        let server = server(Publisher::default());
+        let post = |headers: &str| format!("POST /command HTTP/1.1\r\nHost: localhost:8080\r\n{}Content-Length: 6\r\n\r\nrewind", headers);
+        assert!(request(&server, &post("Origin: http://evil.example\r\n")).starts_with("HTTP/1.1 403"));
+        assert!(request(&server, &post("Origin: null\r\n")).starts_with("HTTP/1.1 403"));
+        // Got past the check, the command itself is wrong
+        assert!(request(&server, &post("Origin: http://localhost:8080\r\n")).starts_with("HTTP/1.1 400"));
+        // A foreign domain resolving to this machine
+        let rebound = "POST /command HTTP/1.1\r\nHost: evil.example:8080\r\nOrigin: http://evil.example:8080\r\nContent-Length: 6\r\n\r\nrewind";
+        assert!(request(&server, rebound).starts_with("HTTP/1.1 403"));
+        for host in ["stage.local:8080", "192.168.1.20:8080", "[::1]:8080"] {
+            let post = format!("POST /command HTTP/1.1\r\nHost: {0}\r\nOrigin: http://{0}\r\nContent-Length: 6\r\n\r\nrewind", host);
+            assert!(request(&server, &post).starts_with("HTTP/1.1 400"), "{}", host);
+        }
+
+        let server = WebServer::open("127.0.0.1:0".parse().unwrap(), Some(String::from("secret")), Vec::new(), Publisher::default()).unwrap();
+        let post = |path: &str, headers: &str| format!("POST {} HTTP/1.1\r\n{}Content-Length: 6\r\n\r\nrewind", path, headers);
+        assert!(request(&server, &post("/command", "")).starts_with("HTTP/1.1 401"));
+        assert!(request(&server, &post("/command?token=wrong", "")).starts_with("HTTP/1.1 401"));
+        assert!(request(&server, &post("/command?token=secret", "")).starts_with("HTTP/1.1 400"));
+        assert!(request(&server, &post("/command", "Authorization: Bearer secret\r\n")).starts_with("HTTP/1.1 400"));
+        assert!(request(&server, "GET /ws HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 401"));
+        assert!(request(&server, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn drops_clients_that_send_nothing() {
+        let server = server(Publisher::default());
+        let mut stream = TcpStream::connect(server.address).unwrap();
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT * 3)).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let mut response = Vec::new();
+        // Closed by the server without an answer
+        assert_eq!(stream.read_to_end(&mut response).unwrap(), 0);
+    }
+
+    #[test]
+    fn drops_clients_that_send_slowly() {
+        let server = server(Publisher::default());
+        let mut stream = TcpStream::connect(server.address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let start = Instant::now();
+        let mut buf = [0u8; 64];
+        // A header byte every second, each within the timeout of a single read
+        loop {
+            assert!(start.elapsed() < REQUEST_TIMEOUT * 2, "still connected");
+            if stream.write_all(b"X").is_err() {
+                break;
+            }
+            match stream.read(&mut buf) {
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                _ => break,
+            }
+        }
+        assert!(start.elapsed() >= REQUEST_TIMEOUT - Duration::from_secs(1));
+    }
+
+    #[test]
+    fn turns_away_too_many_clients() {
+        let server = server(Publisher::default());
+        let idle: Vec<TcpStream> = (0..MAX_CLIENTS).map(|_| TcpStream::connect(server.address).unwrap()).collect();
+        let start = Instant::now();
+        let mut extra = TcpStream::connect(server.address).unwrap();
+        let mut response = Vec::new();
+        assert_eq!(extra.read_to_end(&mut response).unwrap(), 0);
+        assert!(start.elapsed() < REQUEST_TIMEOUT);
+        drop(idle);
+    }
+
+    #[test]
+    fn streams_events_over_a_websocket() {
+        let publisher = Publisher::default();
+        let server = server(publisher.clone());
+        let stream = TcpStream::connect(server.address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let url = format!("ws://{}/ws", server.address);
+        let (mut client, _) = tungstenite::client(url.as_str(), stream).unwrap();
+
+        client.write_message(WsMessage::Text(String::from("set_bpm 0"))).unwrap();
+        let error = client.read_message().unwrap().into_text().unwrap();
+        assert!(error.contains("Invalid command 'set_bpm 0'"), "{}", error);
+
+        publisher.publish(RemoteEvent::Beat { bar: 21, beat: 4, tick: 0 });
+        let text = client.read_message().unwrap().into_text().unwrap();
+        assert_eq!(text, r#"{"event":"beat","bar":21,"beat":4,"tick":0}"#);
+    }
+}
@@ -1,4 +1,5 @@
-use std::num::NonZeroU16;
+use std::path::{Path, PathBuf};
+use anyhow::Context;
 use rand;
 use rand::Rng;
 
@@ -7,29 +8,56 @@ use serde::{
     Deserialize
 };
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+use crate::audio::meter::Subdivision;
+use crate::audio::tempo::{Bpm, TempoMap};
+use crate::midi::smf;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum BPM {
-    Number(NonZeroU16)
+    // Whole numbers like `120` and decimals like `92.5`
+    Number(Bpm),
+    // Tempo map of the song, relative to the setlist
+    File(String)
 }
 
 impl BPM {
     pub fn random() -> BPM {
-        let mut num = NonZeroU16::new(rand::thread_rng().gen_range(1..=300)).unwrap();
-        BPM::Number(num)
+        BPM::Number(Bpm::whole(rand::thread_rng().gen_range(1..=300)).unwrap())
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileSongListing {
     title: String,
-    bpm: BPM
+    bpm: BPM,
+    #[serde(default)]
+    subdivision: Subdivision,
+    // MIDI program sent when the song is selected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    program: Option<u8>
 }
 
 impl FileSongListing {
+    pub fn new(title: &str, bpm: BPM, subdivision: Subdivision) -> FileSongListing {
+        FileSongListing {
+            title: String::from(title),
+            bpm,
+            subdivision,
+            program: None
+        }
+    }
+
+    pub fn with_program(mut self, program: Option<u8>) -> FileSongListing {
+        self.program = program;
+        self
+    }
+
     pub fn random() -> FileSongListing {
         FileSongListing {
             title: format!("Song {}", rand::thread_rng().gen_range(1..=300)),
-            bpm: BPM::random()
+            bpm: BPM::random(),
+            subdivision: Subdivision::default(),
+            program: None
         }
     }
 
@@ -37,7 +65,153 @@ impl FileSongListing {
         &self.title
     }
 
-    pub fn bpm(&self) -> BPM {
-        self.bpm
+    pub fn bpm(&self) -> &BPM {
+        &self.bpm
+    }
+
+    pub fn subdivision(&self) -> Subdivision {
+        self.subdivision
+    }
+
+    pub fn program(&self) -> Option<u8> {
+        self.program
+    }
+
+    // Tempo map files are relative to the directory of the setlist
+    pub fn load_tempo_map(&self, dir: &Path) -> Result<Option<TempoMap>, anyhow::Error> {
+        match &self.bpm {
+            BPM::Number(_) => Ok(None),
+            BPM::File(file) => load_tempo_map(dir.join(file))
+                .map(Some)
+                .with_context(|| format!("Song '{}'", self.title)),
+        }
+    }
+
+    // For saving the setlist into another directory
+    pub fn rebase_tempo_map(&mut self, from: &Path, to: &Path) {
+        if let BPM::File(file) = &self.bpm {
+            self.bpm = BPM::File(rebase_tempo_map(file, from, to));
+        }
+    }
+}
+
+// Makes a tempo map file relative to the setlist directory `from` relative to `to` instead,
+// or absolute if it is not inside `to`
+pub fn rebase_tempo_map(file: &str, from: &Path, to: &Path) -> String {
+    // The directory of a setlist given by its bare file name is empty
+    let dir = |d: &Path| if d.as_os_str().is_empty() { PathBuf::from(".") } else { d.to_path_buf() };
+    let path = match dir(from).canonicalize() {
+        Ok(from) => from.join(file),
+        Err(_) => return file.to_string(),
+    };
+    let to = dir(to).canonicalize().unwrap_or_else(|_| to.to_path_buf());
+    path.strip_prefix(&to).unwrap_or(&path).to_string_lossy().into_owned()
+}
+
+// Either a YAML tempo map or a MIDI file
+pub fn load_tempo_map<P: AsRef<Path>>(path: P) -> Result<TempoMap, anyhow::Error> {
+    let path = path.as_ref();
+    if smf::is_midi_file(path) {
+        smf::load(path).map(|import| import.map)
+    } else {
+        TempoMap::load(path)
+    }
+}
+
+pub fn parse_setlist(yaml: &str) -> Result<Vec<FileSongListing>, anyhow::Error> {
+    let songs: Vec<FileSongListing> = serde_yaml::from_str(yaml)?;
+    for song in &songs {
+        if let Some(program) = song.program.filter(|p| *p > 127) {
+            return Err(anyhow::anyhow!("Song '{}': program must be between 0 and 127 (got {})", song.title, program));
+        }
+    }
+    Ok(songs)
+}
+
+pub fn load_setlist<P: AsRef<Path>>(path: P) -> Result<Vec<FileSongListing>, anyhow::Error> {
+    let path = path.as_ref();
+    let yaml = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    parse_setlist(&yaml).with_context(|| format!("Malformed setlist {}", path.display()))
+}
+
+pub fn save_setlist<P: AsRef<Path>>(path: P, songs: &[FileSongListing]) -> Result<(), anyhow::Error> {
+    let path = path.as_ref();
+    let yaml = serde_yaml::to_string(songs)?;
+    std::fs::write(path, yaml).with_context(|| format!("Could not write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn setlist_round_trips() {
+        let songs: Vec<FileSongListing> = (0..5).map(|_| FileSongListing::random()).collect();
+        let yaml = serde_yaml::to_string(&songs).unwrap();
+        let parsed = parse_setlist(&yaml).unwrap();
+        assert_eq!(parsed.len(), 5);
+        for (a, b) in songs.iter().zip(parsed.iter()) {
+            assert_eq!(a.title(), b.title());
+            assert_eq!(a.subdivision(), b.subdivision());
+        }
+    }
+
+    #[test]
+    fn malformed_setlists_are_errors() {
+        assert!(parse_setlist("- title: Song\n").is_err());
+        assert!(parse_setlist("- title: Song\n  bpm:\n    Number: 0\n").is_err());
+        assert!(parse_setlist("title: [").is_err());
+        assert!(parse_setlist("- title: Song\n  bpm:\n    Number: 120\n  program: 128\n").is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn integer_and_fractional_bpm_load() {
+        let songs = parse_setlist("- title: Old\n  bpm:\n    Number: 120\n- title: New\n  bpm:\n    Number: 92.5\n").unwrap();
+        let tempos: Vec<u32> = songs.iter().filter_map(|s| match s.bpm() {
+            BPM::Number(bpm) => Some(bpm.millis()),
+            BPM::File(_) => None,
+        }).collect();
+        assert_eq!(tempos, vec![120_000, 92_500]);
+
+        let yaml = serde_yaml::to_string(&songs).unwrap();
+        assert!(yaml.contains("Number: 120\n"));
+        assert!(yaml.contains("Number: 92.5\n"));
+        assert!(parse_setlist("- title: Song\n  bpm:\n    Number: -4\n").is_err());
+    }
+
+    #[test]
+    fn tempo_map_files() {
+        let songs = parse_setlist("- title: Song\n  bpm:\n    File: maps/song.yaml\n").unwrap();
+        assert!(matches!(songs[0].bpm(), BPM::File(path) if path == "maps/song.yaml"));
+    }
+
+    #[test]
+    fn tempo_maps_follow_the_setlist() {
+        let dir = std::env::temp_dir().join(format!("metronome-rebase-{}", std::process::id()));
+        let (old, new) = (dir.join("old"), dir.join("new"));
+        std::fs::create_dir_all(old.join("maps")).unwrap();
+        std::fs::create_dir_all(&new).unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        let mut song = FileSongListing::new("Song", BPM::File(String::from("maps/song.mid")), Subdivision::default());
+        song.rebase_tempo_map(&old, &old);
+        assert!(matches!(song.bpm(), BPM::File(path) if path == "maps/song.mid"));
+        song.rebase_tempo_map(&old, &new);
+        let moved = dir.join("old").join("maps").join("song.mid");
+        assert!(matches!(song.bpm(), BPM::File(path) if Path::new(path) == moved));
+        // Back inside the new setlist directory
+        song.rebase_tempo_map(&new, &dir);
+        assert!(matches!(song.bpm(), BPM::File(path) if Path::new(path) == Path::new("old/maps/song.mid")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn program_is_optional() {
+        let songs = parse_setlist("- title: Song\n  bpm:\n    Number: 120\n  program: 12\n").unwrap();
+        assert_eq!(songs[0].program(), Some(12));
+
+        let yaml = serde_yaml::to_string(&[songs[0].clone().with_program(None)]).unwrap();
+        assert!(!yaml.contains("program"));
+    }
+}
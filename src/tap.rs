@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+// Intervals further than this from the median are mis-taps
+const OUTLIER_TOLERANCE: f64 = 0.25;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TapRounding {
+    Off,
+    // Nearest whole BPM
+    Whole,
+    // Nearest multiple of 5 BPM
+    Five,
+}
+
+impl Default for TapRounding {
+    fn default() -> Self {
+        TapRounding::Off
+    }
+}
+
+impl TapRounding {
+    fn apply(&self, bpm: f64) -> f64 {
+        match self {
+            TapRounding::Off => bpm,
+            TapRounding::Whole => bpm.round(),
+            TapRounding::Five => ((bpm / 5.).round() * 5.).max(5.),
+        }
+    }
+}
+
+// Estimates the tempo from the last `window` taps
+#[derive(Debug)]
+pub struct TapTempo {
+    taps: VecDeque<Instant>,
+    window: usize,
+    timeout: Duration,
+    rounding: TapRounding,
+}
+
+impl TapTempo {
+    pub fn new(window: usize, timeout: Duration, rounding: TapRounding) -> TapTempo {
+        let window = window.max(2);
+        TapTempo {
+            taps: VecDeque::with_capacity(window),
+            window,
+            timeout,
+            rounding,
+        }
+    }
+
+    // Returns the tempo once there are at least two taps
+    pub fn tap(&mut self, at: Instant) -> Option<f64> {
+        // A long pause starts a new measurement
+        if self.taps.back().map_or(false, |last| at.saturating_duration_since(*last) > self.timeout) {
+            self.taps.clear();
+        }
+        self.taps.push_back(at);
+        if self.taps.len() > self.window {
+            self.taps.pop_front();
+        }
+        self.bpm()
+    }
+
+    pub fn reset(&mut self) {
+        self.taps.clear();
+    }
+
+    pub fn bpm(&self) -> Option<f64> {
+        let mut intervals: Vec<f64> = self.taps
+            .iter()
+            .zip(self.taps.iter().skip(1))
+            .map(|(a, b)| b.saturating_duration_since(*a).as_secs_f64())
+            .filter(|interval| *interval > 0.)
+            .collect();
+        if intervals.is_empty() {
+            return None;
+        }
+
+        intervals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let middle = intervals.len() / 2;
+        let median = if intervals.len() % 2 == 0 {
+            (intervals[middle - 1] + intervals[middle]) / 2.
+        } else {
+            intervals[middle]
+        };
+
+        // With an even count the median lies between two intervals, which may both be outliers
+        let kept: Vec<f64> = intervals
+            .into_iter()
+            .filter(|interval| (interval - median).abs() <= median * OUTLIER_TOLERANCE)
+            .collect();
+        let average = match kept.len() {
+            0 => median,
+            n => kept.iter().sum::<f64>() / n as f64,
+        };
+        Some(self.rounding.apply(60. / average))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn taps(tempo: &mut TapTempo, start: Instant, intervals_ms: &[u64]) -> Option<f64> {
+        let mut at = start;
+        let mut bpm = tempo.tap(at);
+        for interval in intervals_ms {
+            at += Duration::from_millis(*interval);
+            bpm = tempo.tap(at);
+        }
+        bpm
+    }
+
+    #[test]
+    fn averages_steady_taps() {
+        let mut tempo = TapTempo::new(8, Duration::from_secs(2), TapRounding::Off);
+        assert_eq!(tempo.tap(Instant::now()), None);
+        let bpm = taps(&mut tempo, Instant::now(), &[500, 490, 510, 500]).unwrap();
+        assert!((bpm - 120.).abs() < 0.01);
+    }
+
+    #[test]
+    fn ignores_outliers() {
+        let mut tempo = TapTempo::new(8, Duration::from_secs(2), TapRounding::Whole);
+        // A doubled tap and a missed one
+        assert_eq!(taps(&mut tempo, Instant::now(), &[600, 600, 300, 300, 600, 1200, 600]), Some(100.));
+        tempo.reset();
+        // Both intervals are too far from their median of 700 ms
+        assert_eq!(taps(&mut tempo, Instant::now(), &[500, 900]), Some(86.));
+    }
+
+    #[test]
+    fn resets_after_timeout_and_keeps_window() {
+        let start = Instant::now();
+        let mut tempo = TapTempo::new(4, Duration::from_secs(2), TapRounding::Whole);
+        taps(&mut tempo, start, &[1000, 1000, 1000]);
+        // Only the last three intervals count
+        assert_eq!(taps(&mut tempo, start + Duration::from_millis(3500), &[500, 500, 500]), Some(120.));
+
+        assert_eq!(tempo.tap(start + Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn rounds_to_fives() {
+        let mut tempo = TapTempo::new(8, Duration::from_secs(2), TapRounding::Five);
+        // 127.7 BPM
+        assert_eq!(taps(&mut tempo, Instant::now(), &[470, 470]), Some(130.));
+        tempo.reset();
+        // 60 / 0.48 = 125
+        assert_eq!(taps(&mut tempo, Instant::now(), &[480, 480]), Some(125.));
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::{debug, info};
+
+use super::audio::{AudioHandle, AudioMessage};
+use super::audio::meter::{Subdivision, TimeSignature};
+use super::audio::tempo::{Bpm, TempoMap};
+use super::input::keymap::Action;
+use super::midi::clock::MidiClock;
+use super::remote::{Publisher, RemoteEvent};
+use super::tap::TapTempo;
+
+const BPM_STEP: f64 = 1.;
+
+// What the transport needs to know of a song, with the window and without it
+pub trait SetlistSong {
+    fn title(&self) -> &str;
+    // The fixed tempo, or the starting tempo of a tempo map
+    fn bpm(&self) -> Option<Bpm>;
+    fn tempo_map(&self) -> Option<&Arc<TempoMap>>;
+    fn subdivision(&self) -> Subdivision;
+    fn program(&self) -> Option<u8>;
+}
+
+// Moves through the setlist and starts and stops playback. The songs stay with the caller,
+// which passes them in on every call.
+#[derive(Debug)]
+pub struct Transport {
+    current: usize,
+    tap_tempo: TapTempo,
+    // Playback follows a MIDI clock, which starts and stops it and sets the tempo
+    following: bool,
+    publisher: Publisher,
+}
+
+impl Transport {
+    pub fn new(tap_tempo: TapTempo, following: bool, publisher: Publisher) -> Transport {
+        Transport {
+            current: 0,
+            tap_tempo,
+            following,
+            publisher,
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    // Everything but the volume, which is up to the caller. Returns the tempo a tap set.
+    pub fn perform<S: SetlistSong>(&mut self, action: Action, songs: &[S], audio: &mut AudioHandle, clock: Option<&MidiClock>) -> Option<Bpm> {
+        use Action::*;
+        match action {
+            NextSong => {
+                if self.current + 1 < songs.len() {
+                    self.select(self.current + 1, songs, audio, clock);
+                }
+            }
+            PreviousSong => self.select(self.current.saturating_sub(1), songs, audio, clock),
+            JumpToSong(number) => {
+                if (1..=songs.len()).contains(&number) {
+                    self.select(number - 1, songs, audio, clock);
+                }
+            }
+            Play => self.send(audio, AudioMessage::Play),
+            Pause => self.send(audio, AudioMessage::Pause),
+            Toggle => self.send(audio, AudioMessage::Toggle),
+            TapTempo => return self.tap(audio),
+            BpmUp => self.set_bpm(audio, Bpm::saturating(audio.state().bpm.as_f64() + BPM_STEP)),
+            BpmDown => self.set_bpm(audio, Bpm::saturating(audio.state().bpm.as_f64() - BPM_STEP)),
+            VolumeUp | VolumeDown => debug!("{:?} is left to the caller", action),
+        }
+        None
+    }
+
+    // While following a MIDI clock, only the clock starts and stops playback
+    pub fn send(&mut self, audio: &mut AudioHandle, msg: AudioMessage) {
+        match msg {
+            AudioMessage::Play | AudioMessage::Pause | AudioMessage::Toggle if self.following => {
+                debug!("Ignoring {:?}, playback follows the MIDI clock", msg);
+            }
+            AudioMessage::SetBpm(bpm) => self.set_bpm(audio, bpm),
+            _ => audio.send(msg),
+        }
+    }
+
+    // Sets the tapped tempo once there are enough taps
+    pub fn tap(&mut self, audio: &mut AudioHandle) -> Option<Bpm> {
+        if self.following {
+            debug!("Ignoring tap, the tempo follows the MIDI clock");
+            return None;
+        }
+        let bpm = Bpm::saturating(self.tap_tempo.tap(Instant::now())?);
+        self.set_bpm(audio, bpm);
+        Some(bpm)
+    }
+
+    // Makes `index` the current song and sets up its tempo, meter and subdivision
+    pub fn select<S: SetlistSong>(&mut self, index: usize, songs: &[S], audio: &mut AudioHandle, clock: Option<&MidiClock>) {
+        self.current = index.min(songs.len().saturating_sub(1));
+        // Taps for the previous song must not count for this one
+        self.tap_tempo.reset();
+        let song = match songs.get(self.current) {
+            Some(song) => song,
+            None => return,
+        };
+        info!("Song {} of {}: {}", self.current + 1, songs.len(), song.title());
+
+        // The tempo of a MIDI clock wins over the one of the song
+        match (song.tempo_map(), self.following) {
+            (_, true) => {}
+            (Some(map), false) => audio.set_tempo_map(Some(map.clone())),
+            (None, false) => {
+                // Songs without a map are played in 4/4 again
+                if audio.tempo_map().is_some() {
+                    audio.set_tempo_map(None);
+                    audio.send(AudioMessage::SetTimeSignature(TimeSignature::default()));
+                }
+                if let Some(bpm) = song.bpm() {
+                    audio.send(AudioMessage::SetBpm(bpm));
+                }
+            }
+        }
+        audio.send(AudioMessage::SetSubdivision(song.subdivision()));
+        if let (Some(bpm), false) = (song.bpm(), self.following) {
+            self.publisher.publish(RemoteEvent::Tempo { bpm });
+        }
+        if let Some(clock) = clock {
+            clock.song_changed(song.program());
+        }
+        self.publisher.publish(RemoteEvent::Song {
+            number: self.current + 1,
+            count: songs.len(),
+            title: song.title().to_string(),
+            bpm: song.bpm(),
+            next: songs.get(self.current + 1).map(|s| s.title().to_string()),
+        });
+    }
+
+    fn set_bpm(&self, audio: &mut AudioHandle, bpm: Bpm) {
+        info!("{} BPM", bpm);
+        audio.send(AudioMessage::SetBpm(bpm));
+        self.publisher.publish(RemoteEvent::Tempo { bpm });
+    }
+}
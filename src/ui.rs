@@ -2,11 +2,21 @@ use iced::{
     Element, Row, TextInput, Length, Button, Text
 };
 use iced_native::Widget;
+use anyhow::Context;
 use log::error;
 use rand::RngCore;
 
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
 use super::id;
 use super::Message;
+use super::audio::meter::Subdivision;
+use super::audio::tempo::{Bpm, TempoMap};
+use super::song_listing::{self, BPM, FileSongListing};
+use super::transport::SetlistSong;
 
 #[derive(Debug, Clone)]
 pub enum SongListingEvent {
@@ -14,27 +24,34 @@ pub enum SongListingEvent {
     BpmChange(String)
 }
 
-// Somehow have this only work, if Option is not none
-// Also only serialize the inner values, so first number than file
-enum BpmSetting {
-    Value(Option<u16>),
+#[derive(Debug, Clone)]
+pub enum BpmSetting {
+    Value(Option<Bpm>),
+    // Tempo map, as written in the setlist
     File(String)
 }
 
 #[derive(Debug)]
 pub struct SongListing {
     title: String,
-    bpm: Option<u16>,
+    bpm: BpmSetting,
+    // Loaded from the file of `BpmSetting::File`
+    tempo_map: Option<Arc<TempoMap>>,
+    subdivision: Subdivision,
+    program: Option<u8>,
     title_input: iced::text_input::State,
     bpm_input: iced::text_input::State,
     button: iced::button::State,
 }
 
 impl SongListing {
-    pub fn new(title: &str, bpm: u16) -> SongListing {
+    pub fn new(title: &str, bpm: Bpm) -> SongListing {
         SongListing {
             title: String::from(title),
-            bpm: Some(bpm),
+            bpm: BpmSetting::Value(Some(bpm)),
+            tempo_map: None,
+            subdivision: Subdivision::default(),
+            program: None,
             title_input: iced::text_input::State::new(),
             bpm_input: iced::text_input::State::new(),
             button: iced::button::State::new()
@@ -49,16 +66,62 @@ impl SongListing {
         self.title = String::from(val);
     }
 
-    pub fn bpm(&self) -> Option<u16> {
-        self.bpm
+    // The starting tempo for songs with a tempo map
+    pub fn bpm(&self) -> Option<Bpm> {
+        match &self.bpm {
+            BpmSetting::Value(bpm) => *bpm,
+            BpmSetting::File(_) => self.tempo_map.as_ref().map(|map| map.start().0),
+        }
     }
 
     pub fn bpm_str(&self, default: &str) -> String {
-        self.bpm.map_or(String::from(default), |opt| { format!("{}", opt) })
+        self.bpm().map_or(String::from(default), |opt| { format!("{}", opt) })
+    }
+
+    // Replaces a tempo map
+    pub fn set_bpm(&mut self, val: Bpm) {
+        self.bpm = BpmSetting::Value(Some(val));
+        self.tempo_map = None;
+    }
+
+    pub fn tempo_map(&self) -> Option<&Arc<TempoMap>> {
+        self.tempo_map.as_ref()
     }
 
-    pub fn set_bpm(&mut self, val: u16) {
-        self.bpm = Some(val);
+    pub fn set_tempo_map(&mut self, map: Option<Arc<TempoMap>>) {
+        self.tempo_map = map;
+    }
+
+    // Tempo map files are relative to the directory of the setlist, either YAML or MIDI files
+    pub fn load_tempo_map(&mut self, dir: &Path) -> Result<(), anyhow::Error> {
+        if let BpmSetting::File(file) = &self.bpm {
+            let map = song_listing::load_tempo_map(dir.join(file)).with_context(|| format!("Song '{}'", self.title))?;
+            self.tempo_map = Some(Arc::new(map));
+        }
+        Ok(())
+    }
+
+    // For saving the setlist into another directory
+    pub fn rebase_tempo_map(&mut self, from: &Path, to: &Path) {
+        if let BpmSetting::File(file) = &self.bpm {
+            self.bpm = BpmSetting::File(song_listing::rebase_tempo_map(file, from, to));
+        }
+    }
+
+    pub fn subdivision(&self) -> Subdivision {
+        self.subdivision
+    }
+
+    pub fn set_subdivision(&mut self, val: Subdivision) {
+        self.subdivision = val;
+    }
+
+    pub fn program(&self) -> Option<u8> {
+        self.program
+    }
+
+    pub fn set_program(&mut self, val: Option<u8>) {
+        self.program = val;
     }
 
     pub fn apply_event(&mut self, event: SongListingEvent) {
@@ -66,9 +129,9 @@ impl SongListing {
             SongListingEvent::TitleChange(title) => self.title = title,
             SongListingEvent::BpmChange(bpm) => {
                 if bpm.is_empty() {
-                    self.bpm = None
+                    self.bpm = BpmSetting::Value(None)
                 } else if let Ok(parsed_bpm) = bpm.parse() {
-                    self.bpm = Some(parsed_bpm)
+                    self.set_bpm(parsed_bpm)
                 }
             }
         }
@@ -88,7 +151,7 @@ impl SongListing {
                 TextInput::new(
                     &mut self.bpm_input,
                     "BPM",
-                    &*self.bpm.map_or(String::new(), |opt| { format!("{}", opt) }),
+                    &*self.bpm_text(),
                     move |v| { Message::None }
                 ).width(Length::FillPortion(45)),
             )
@@ -101,13 +164,93 @@ impl SongListing {
             .height(height)
     }
 
+    fn bpm_text(&self) -> String {
+        match &self.bpm {
+            BpmSetting::Value(bpm) => bpm.map_or(String::new(), |opt| { format!("{}", opt) }),
+            BpmSetting::File(file) => format!("{} ({})", self.bpm_str("?"), file),
+        }
+    }
+
     pub fn element(&self, height: Length) -> Row<Message> {
         Row::new()
             .push(Text::new(&self.title).width(Length::FillPortion(50)),
             )
-            .push(Text::new(format!("{} BPM", self.bpm.unwrap())).width(Length::FillPortion(50)),
+            .push(Text::new(self.bpm_text() + " BPM").width(Length::FillPortion(50)),
             )
             .spacing(10)
             .height(height)
     }
 }
+
+impl From<&FileSongListing> for SongListing {
+    fn from(song: &FileSongListing) -> Self {
+        let mut listing = match song.bpm() {
+            BPM::Number(bpm) => SongListing::new(song.title(), *bpm),
+            BPM::File(file) => {
+                let mut listing = SongListing::new(song.title(), Bpm::default());
+                listing.bpm = BpmSetting::File(file.clone());
+                listing
+            }
+        };
+        listing.set_subdivision(song.subdivision());
+        listing.set_program(song.program());
+        listing
+    }
+}
+
+impl SetlistSong for SongListing {
+    fn title(&self) -> &str {
+        SongListing::title(self)
+    }
+
+    fn bpm(&self) -> Option<Bpm> {
+        SongListing::bpm(self)
+    }
+
+    fn tempo_map(&self) -> Option<&Arc<TempoMap>> {
+        SongListing::tempo_map(self)
+    }
+
+    fn subdivision(&self) -> Subdivision {
+        SongListing::subdivision(self)
+    }
+
+    fn program(&self) -> Option<u8> {
+        SongListing::program(self)
+    }
+}
+
+impl TryFrom<&SongListing> for FileSongListing {
+    type Error = anyhow::Error;
+
+    fn try_from(song: &SongListing) -> Result<Self, Self::Error> {
+        let bpm = match &song.bpm {
+            BpmSetting::Value(bpm) => bpm
+                .map(BPM::Number)
+                .ok_or_else(|| anyhow::anyhow!("Song '{}' has no BPM", song.title))?,
+            BpmSetting::File(file) => BPM::File(file.clone()),
+        };
+        Ok(FileSongListing::new(&song.title, bpm, song.subdivision).with_program(song.program))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentSetlist(pub PathBuf);
+
+impl Display for RecentSetlist {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.0.file_name() {
+            Some(name) => write!(f, "{}", name.to_string_lossy()),
+            None => write!(f, "{}", self.0.display()),
+        }
+    }
+}
+
+pub fn show_error_dialog(title: &str, message: &str) {
+    rfd::MessageDialog::new()
+        .set_level(rfd::MessageLevel::Error)
+        .set_title(title)
+        .set_description(message)
+        .set_buttons(rfd::MessageButtons::Ok)
+        .show();
+}
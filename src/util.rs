@@ -1,3 +1,5 @@
+use crate::audio::tempo::Bpm;
+
 #[derive(Debug)]
 pub struct SyncWrapper<T> {
     inner: T,
@@ -28,6 +30,7 @@ pub fn busy_sleep_from(start: std::time::Instant, ns: u128) {
     while std::time::Instant::now().duration_since(start).as_nanos() < ns {}
 }
 
-pub const fn bpm_to_ns(bpm: u128) -> u128 {
-    (60000 * 1000000) / bpm
+// Length of one beat in nanoseconds
+pub fn bpm_to_ns(bpm: Bpm) -> u128 {
+    (60_000 * 1_000_000 * 1000) / bpm.millis() as u128
 }